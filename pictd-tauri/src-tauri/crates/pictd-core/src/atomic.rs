@@ -0,0 +1,141 @@
+//! Crash-safe file writes.
+//!
+//! Bytes go to a hidden temporary file in the target's directory, are
+//! flushed to disk, and only then renamed into place. Readers such as
+//! live-reload servers and sync tools see either no file or all of it,
+//! and a crash leaves at most a stray temporary file.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Fully written, synced bytes waiting to be moved into place; removed if
+/// dropped before that
+pub struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Write `bytes` to a new temporary file in `dir` and sync it to disk
+    pub fn write(dir: &Path, bytes: &[u8]) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        loop {
+            let n = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = dir.join(format!(".pictd-{}-{}.tmp", std::process::id(), n));
+            let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => file,
+                // Left behind by an earlier process with the same id
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            };
+
+            let temp = Self {
+                path,
+                persisted: false,
+            };
+            file.write_all(bytes)?;
+            file.sync_all()?;
+            return Ok(temp);
+        }
+    }
+
+    /// Move into place at `path`, replacing any file there
+    pub fn persist(mut self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path)?;
+        self.persisted = true;
+        sync_parent(path);
+        Ok(())
+    }
+
+    /// Move into place at `path` unless something is already there, which
+    /// fails with [`ErrorKind::AlreadyExists`] and keeps the temporary file
+    /// for another try
+    pub fn persist_new(&mut self, path: &Path) -> io::Result<()> {
+        // A hard link is an atomic create-new; a plain rename would replace
+        match fs::hard_link(&self.path, path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => return Err(e),
+            // Filesystems without hard links; the check races, rarely
+            Err(_) if path.symlink_metadata().is_ok() => {
+                return Err(ErrorKind::AlreadyExists.into())
+            }
+            Err(_) => {
+                fs::rename(&self.path, path)?;
+                self.persisted = true;
+                sync_parent(path);
+                return Ok(());
+            }
+        }
+
+        let _ = fs::remove_file(&self.path);
+        self.persisted = true;
+        sync_parent(path);
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Replace `path` with `bytes` in one step
+pub fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    TempFile::write(dir, bytes)
+        .and_then(|temp| temp.persist(path))
+        .map_err(Error::at(path))
+}
+
+/// Make a rename into the directory of `path` survive a crash; best effort,
+/// since not every platform can open a directory
+fn sync_parent(path: &Path) {
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = fs::File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writes_replace_or_claim_in_one_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+
+        write_atomic(&path, b"first").unwrap();
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+
+        let mut temp = TempFile::write(dir.path(), b"third").unwrap();
+        let err = temp.persist_new(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+
+        let other = dir.path().join("other.png");
+        temp.persist_new(&other).unwrap();
+        assert_eq!(fs::read(&other).unwrap(), b"third");
+
+        // Nothing but the two images is left behind
+        drop(TempFile::write(dir.path(), b"abandoned").unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
@@ -1,84 +1,348 @@
-use arboard::Clipboard;
-use image::{ImageBuffer, RgbaImage};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use chrono::{DateTime, Local};
+use image::RgbaImage;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::storage;
+use crate::copy::ClipboardCopy;
+use crate::digest::ImageDigest;
+use crate::encode::SaveOptions;
+use crate::error::Result;
+use crate::files;
+use crate::filter::CaptureFilter;
+use crate::format::ImageFormat;
+use crate::hook::{HookConfig, HookFailure, HookInput, HookRunner};
+use crate::perceptual::{NearDuplicateFilter, PerceptualHash};
+use crate::retention::{self, RetentionPolicy, RetentionReport};
+use crate::sink::{Sink, SinkConfig};
+use crate::source::{ClipboardImage, ClipboardSource, ReadOptions, ReadOutcome};
+use crate::state::MonitorState;
+use crate::storage::{self, ImageInfo};
+use crate::upload::{UploadOutcome, UploadQueue};
+use crate::window::{self, SourceWindow};
 
+#[derive(Clone, Debug)]
+pub struct MonitorConfig {
+    /// How often the clipboard is read when polling, and how often the
+    /// worker wakes up to check for `stop` while waiting for a change
+    pub poll_interval: Duration,
+    /// Only read the clipboard when the source reports a change.
+    /// Sources without change notifications are polled regardless.
+    pub change_events: bool,
+    /// Skip captures that look almost the same as a recent save
+    pub near_duplicates: Option<NearDuplicateFilter>,
+    /// Also skip images identical to any of this many recent saves,
+    /// not just the last image read
+    pub recent_digests: usize,
+    /// File the dedup state is kept in between runs, see
+    /// [`crate::state::default_monitor_state_path`]. `None` starts fresh every time.
+    pub state_path: Option<PathBuf>,
+    /// Treat whatever is on the clipboard when monitoring starts as already seen
+    pub ignore_initial: bool,
+    /// Formats stored with their original bytes when the clipboard offers
+    /// them; anything else is saved as PNG
+    pub formats: Vec<ImageFormat>,
+    /// Size and source rules a capture must pass to be saved
+    pub filter: CaptureFilter,
+    /// Record the focused application and window title with each save
+    pub record_window: bool,
+    /// Output format and quality of saved captures
+    pub save: SaveOptions,
+    /// Which saved captures the worker moves to the trash
+    pub retention: RetentionPolicy,
+    /// How often the worker applies `retention`, starting when it starts
+    pub retention_interval: Duration,
+    /// Remote sinks every save is copied to
+    pub sinks: Vec<SinkConfig>,
+    /// File uploads not done yet are kept in between runs, see
+    /// [`crate::upload::default_upload_queue_path`]. `None` keeps them in memory.
+    pub upload_queue_path: Option<PathBuf>,
+    /// Commands and webhooks run after each save
+    pub hooks: HookConfig,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_millis(500),
+            change_events: true,
+            near_duplicates: None,
+            recent_digests: 0,
+            state_path: None,
+            ignore_initial: false,
+            formats: ImageFormat::ALL.to_vec(),
+            filter: CaptureFilter::default(),
+            record_window: true,
+            save: SaveOptions::default(),
+            retention: RetentionPolicy::default(),
+            retention_interval: Duration::from_secs(60 * 60),
+            sinks: Vec::new(),
+            upload_queue_path: None,
+            hooks: HookConfig::default(),
+        }
+    }
+}
+
+/// Why a new clipboard image was not saved
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SkipReason {
+    /// It was already on the clipboard when monitoring started
+    Initial,
+    /// Identical to one of the recent saves
+    RecentDuplicate,
+    /// Looks almost the same as one of the recent saves
+    NearDuplicate { distance: u32 },
+    /// Below the filter's minimum width or height
+    TooSmall { width: u32, height: u32 },
+    /// Above the filter's maximum width, height or pixel count
+    TooLarge { width: u32, height: u32 },
+    /// Original encoding below the filter's minimum size
+    TooFewBytes { bytes: usize },
+    /// Copied while a window the filter excludes was active
+    WindowClass { class: Option<String> },
+    /// Put on the clipboard by pictd itself
+    OwnCopy,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Initial => write!(f, "already on the clipboard at start"),
+            SkipReason::RecentDuplicate => write!(f, "identical to a recent capture"),
+            SkipReason::NearDuplicate { distance } => {
+                write!(
+                    f,
+                    "near-duplicate of a recent capture (distance {})",
+                    distance
+                )
+            }
+            SkipReason::TooSmall { width, height } => {
+                write!(f, "too small ({}x{})", width, height)
+            }
+            SkipReason::TooLarge { width, height } => {
+                write!(f, "too large ({}x{})", width, height)
+            }
+            SkipReason::TooFewBytes { bytes } => write!(f, "too few bytes ({})", bytes),
+            SkipReason::WindowClass { class } => match class {
+                Some(class) => write!(f, "copied from {}", class),
+                None => write!(f, "copied from an unknown window"),
+            },
+            SkipReason::OwnCopy => write!(f, "copied back from pictd's history"),
+        }
+    }
+}
+
+/// What the monitor worker reports to subscribers
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MonitorEvent {
+    Started,
+    Saved {
+        info: Box<ImageInfo>,
+    },
+    Skipped {
+        reason: SkipReason,
+    },
+    Error {
+        message: String,
+    },
+    /// Captures expired by the retention policy were trashed
+    Pruned {
+        report: RetentionReport,
+    },
+    /// A capture was copied to a sink, where `url` serves it
+    Uploaded {
+        path: String,
+        sink: String,
+        url: String,
+    },
+    /// Copying a capture to a sink failed; it is retried at `retry_at`,
+    /// or given up if that is `None`
+    UploadFailed {
+        path: String,
+        sink: String,
+        message: String,
+        retry_at: Option<DateTime<Local>>,
+    },
+    /// A post-save hook failed or timed out
+    HookFailed {
+        #[serde(flatten)]
+        failure: HookFailure,
+    },
+    Stopped,
+}
+
+impl From<UploadOutcome> for MonitorEvent {
+    fn from(outcome: UploadOutcome) -> Self {
+        match outcome {
+            UploadOutcome::Uploaded { path, sink, url } => {
+                MonitorEvent::Uploaded { path, sink, url }
+            }
+            UploadOutcome::Failed {
+                path,
+                sink,
+                message,
+                retry_at,
+            } => MonitorEvent::UploadFailed {
+                path,
+                sink,
+                message,
+                retry_at,
+            },
+            UploadOutcome::UrlNotRecorded { path, message } => MonitorEvent::Error {
+                message: format!("Failed to record the URL of {}: {}", path, message),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Subscribers(Arc<Mutex<Vec<Sender<MonitorEvent>>>>);
+
+impl Subscribers {
+    fn publish(&self, event: MonitorEvent) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+/// Images pictd is putting on the clipboard itself, oldest first
+#[derive(Clone, Default)]
+struct OwnCopies(Arc<Mutex<VecDeque<ImageDigest>>>);
+
+impl OwnCopies {
+    /// Copies that are never read back are forgotten after this many more
+    const LIMIT: usize = 8;
+
+    fn push(&self, digest: ImageDigest) {
+        let mut copies = self.0.lock().unwrap();
+        copies.push_back(digest);
+        if copies.len() > Self::LIMIT {
+            copies.pop_front();
+        }
+    }
+
+    /// Whether `digest` was pictd's own copy; it is only matched once
+    fn take(&self, digest: ImageDigest) -> bool {
+        let mut copies = self.0.lock().unwrap();
+        match copies.iter().position(|d| *d == digest) {
+            Some(index) => copies.remove(index).is_some(),
+            None => false,
+        }
+    }
+}
+
+struct Worker {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+/// Watches a clipboard source on a background worker and saves new images.
+///
+/// There is at most one worker at a time: `start` is a no-op while one is
+/// running, and `stop` waits for it to exit.
 pub struct ClipboardMonitor {
-    running: Arc<AtomicBool>,
+    config: Mutex<MonitorConfig>,
+    worker: Mutex<Option<Worker>>,
+    subscribers: Subscribers,
+    own_copies: OwnCopies,
 }
 
 impl ClipboardMonitor {
     pub fn new() -> Self {
+        Self::with_config(MonitorConfig::default())
+    }
+
+    pub fn with_config(config: MonitorConfig) -> Self {
         Self {
-            running: Arc::new(AtomicBool::new(false)),
+            config: Mutex::new(config),
+            worker: Mutex::new(None),
+            subscribers: Subscribers::default(),
+            own_copies: OwnCopies::default(),
         }
     }
 
-    /// Start monitoring clipboard with a callback for when images are saved
-    pub fn start<F>(&self, save_dir: String, on_image: F)
+    /// Change the configuration; a running worker keeps the old one until restarted
+    pub fn configure(&self, update: impl FnOnce(&mut MonitorConfig)) {
+        update(&mut self.config.lock().unwrap());
+    }
+
+    /// The configuration the next worker starts with
+    pub fn config(&self) -> MonitorConfig {
+        self.config.lock().unwrap().clone()
+    }
+
+    /// Receive the events of every worker started from now on
+    pub fn subscribe(&self) -> Receiver<MonitorEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Start monitoring a clipboard source, saving new images to `save_dir`
+    pub fn start<S>(&self, source: S, save_dir: String)
     where
-        F: Fn(storage::ImageInfo) + Send + 'static,
+        S: ClipboardSource + 'static,
     {
-        if self.running.load(Ordering::SeqCst) {
+        let mut worker = self.worker.lock().unwrap();
+        if worker.as_ref().is_some_and(|w| !w.handle.is_finished()) {
             return;
         }
 
-        self.running.store(true, Ordering::SeqCst);
-        let running = self.running.clone();
-
-        thread::spawn(move || {
-            let mut clipboard = match Clipboard::new() {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Failed to access clipboard: {}", e);
-                    return;
-                }
-            };
-
-            let mut last_image_hash: Option<u64> = None;
-
-            while running.load(Ordering::SeqCst) {
-                if let Ok(img_data) = clipboard.get_image() {
-                    let rgba_image: RgbaImage = ImageBuffer::from_raw(
-                        img_data.width as u32,
-                        img_data.height as u32,
-                        img_data.bytes.into_owned(),
-                    )
-                    .unwrap_or_else(|| ImageBuffer::new(1, 1));
-
-                    let current_hash = simple_hash(&rgba_image);
-
-                    if last_image_hash != Some(current_hash) {
-                        last_image_hash = Some(current_hash);
-
-                        match storage::save_image(&rgba_image, &save_dir) {
-                            Ok(saved_info) => {
-                                on_image(saved_info);
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to save image: {}", e);
-                            }
-                        }
-                    }
-                }
+        // Reap a worker that exited on its own
+        if let Some(finished) = worker.take() {
+            let _ = finished.handle.join();
+        }
 
-                thread::sleep(Duration::from_millis(500));
-            }
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = thread::spawn({
+            let stop = stop.clone();
+            let config = self.config.lock().unwrap().clone();
+            let events = self.subscribers.clone();
+            let own_copies = self.own_copies.clone();
+            move || run_worker(source, save_dir, config, stop, events, own_copies)
         });
+
+        *worker = Some(Worker { stop, handle });
+    }
+
+    /// Don't save `digest` the next time it is read; it is pictd's own write
+    pub fn mark_own_copy(&self, digest: ImageDigest) {
+        self.own_copies.push(digest);
+    }
+
+    /// Put a saved image back on the clipboard without capturing it again
+    pub fn copy_image(&self, path: &Path) -> Result<()> {
+        let copy = ClipboardCopy::of_file(path)?;
+        self.mark_own_copy(copy.digest());
+        copy.write()
     }
 
+    /// Stop monitoring and wait for the worker, and an upload in progress,
+    /// to finish
     pub fn stop(&self) {
-        self.running.store(false, Ordering::SeqCst);
+        let mut worker = self.worker.lock().unwrap();
+        if let Some(worker) = worker.take() {
+            worker.stop.store(true, Ordering::SeqCst);
+            let _ = worker.handle.join();
+        }
     }
 
     pub fn is_running(&self) -> bool {
-        self.running.load(Ordering::SeqCst)
+        self.worker
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|w| !w.handle.is_finished())
     }
 }
 
@@ -88,31 +352,873 @@ impl Default for ClipboardMonitor {
     }
 }
 
-/// Get the current clipboard image if available
-pub fn get_clipboard_image() -> Option<RgbaImage> {
-    let mut clipboard = Clipboard::new().ok()?;
-    let img_data = clipboard.get_image().ok()?;
+impl Drop for ClipboardMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_worker<S: ClipboardSource>(
+    mut source: S,
+    save_dir: String,
+    config: MonitorConfig,
+    stop: Arc<AtomicBool>,
+    events: Subscribers,
+    own_copies: OwnCopies,
+) {
+    events.publish(MonitorEvent::Started);
+
+    let mut notifier = if config.change_events {
+        source.change_notifier().unwrap_or_else(|e| {
+            events.publish(MonitorEvent::Error {
+                message: format!(
+                    "Clipboard notifications unavailable, polling instead: {}",
+                    e
+                ),
+            });
+            None
+        })
+    } else {
+        None
+    };
+
+    let read_options = ReadOptions {
+        formats: config.formats.clone(),
+        max_pixels: config.filter.max_pixels,
+    };
+
+    let mut session = Session {
+        dedup: Dedup::new(&config, &events),
+        uploader: Uploader::start(&config, &save_dir, stop.clone(), events.clone()),
+        hooks: HookRunner::start(&config.hooks, {
+            let events = events.clone();
+            move |failure| events.publish(MonitorEvent::HookFailed { failure })
+        }),
+        save_dir,
+        config,
+        events,
+        own_copies,
+        last_retention: None,
+    };
+
+    if session.config.ignore_initial {
+        session.ignore_current(&mut source);
+    }
+
+    let mut changed = true;
+    let mut last_error: Option<String> = None;
+
+    while !stop.load(Ordering::SeqCst) {
+        session.enforce_retention();
+
+        // Only read when something may have changed since the last read
+        if changed {
+            let read = source
+                .read(&read_options)
+                .and_then(|outcome| match outcome {
+                    Some(ReadOutcome::Image(image)) => {
+                        session.capture(&image);
+                        Ok(())
+                    }
+                    Some(ReadOutcome::TooLarge { width, height }) => {
+                        session.reject_too_large(width, height);
+                        Ok(())
+                    }
+                    // No pixels on offer, but maybe files copied in a file manager
+                    None => source.get_files().map(|copied| {
+                        session.import_files(&copied, &read_options);
+                    }),
+                });
+
+            match read {
+                Ok(()) => last_error = None,
+                // Report a persistent failure once, not on every poll
+                Err(e) if last_error.as_ref() != Some(&e.to_string()) => {
+                    let message = e.to_string();
+                    session.publish(MonitorEvent::Error {
+                        message: message.clone(),
+                    });
+                    last_error = Some(message);
+                }
+                Err(_) => {}
+            }
+        }
+
+        changed = match notifier.as_mut() {
+            Some(n) => match n.wait(session.config.poll_interval) {
+                Ok(changed) => changed,
+                Err(e) => {
+                    session.publish(MonitorEvent::Error {
+                        message: format!("Clipboard notifications stopped, polling instead: {}", e),
+                    });
+                    notifier = None;
+                    true
+                }
+            },
+            None => {
+                thread::sleep(session.config.poll_interval);
+                true
+            }
+        };
+    }
+
+    if let Some(uploader) = session.uploader.take() {
+        uploader.finish();
+    }
+    session.publish(MonitorEvent::Stopped);
+}
+
+/// Copies saves to the configured sinks on its own thread, so a slow
+/// upload never holds up captures
+struct Uploader {
+    jobs: Sender<PathBuf>,
+    handle: JoinHandle<()>,
+}
+
+impl Uploader {
+    /// `None` without sinks
+    fn start(
+        config: &MonitorConfig,
+        save_dir: &str,
+        stop: Arc<AtomicBool>,
+        events: Subscribers,
+    ) -> Option<Self> {
+        if config.sinks.is_empty() {
+            return None;
+        }
+
+        let (jobs, queued) = mpsc::channel();
+        let handle = thread::spawn({
+            let sinks = config.sinks.clone();
+            let queue_path = config.upload_queue_path.clone();
+            let save_dir = PathBuf::from(save_dir);
+            let interval = config.poll_interval;
+            move || run_uploader(sinks, queue_path, save_dir, queued, interval, stop, events)
+        });
+        Some(Self { jobs, handle })
+    }
+
+    /// Queue a saved capture for every sink
+    fn push(&self, path: PathBuf) {
+        let _ = self.jobs.send(path);
+    }
+
+    /// Wait for the upload in progress, if any; the rest stay queued
+    fn finish(self) {
+        drop(self.jobs);
+        let _ = self.handle.join();
+    }
+}
+
+fn run_uploader(
+    sinks: Vec<SinkConfig>,
+    queue_path: Option<PathBuf>,
+    save_dir: PathBuf,
+    queued: Receiver<PathBuf>,
+    interval: Duration,
+    stop: Arc<AtomicBool>,
+    events: Subscribers,
+) {
+    let opened: Vec<(String, Box<dyn Sink>)> = sinks
+        .iter()
+        .map(|sink| (sink.name.clone(), sink.open()))
+        .collect();
+    let mut queue = match queue_path.as_deref().map(UploadQueue::load) {
+        Some(Ok(queue)) => queue,
+        Some(Err(e)) => {
+            events.publish(MonitorEvent::Error {
+                message: format!("Starting with an empty upload queue: {}", e),
+            });
+            UploadQueue::default()
+        }
+        None => UploadQueue::default(),
+    };
+    let persist = |queue: &UploadQueue| {
+        let Some(path) = &queue_path else {
+            return;
+        };
+        if let Err(e) = queue.save(path) {
+            events.publish(MonitorEvent::Error {
+                message: format!("Failed to save upload queue: {}", e),
+            });
+        }
+    };
+
+    loop {
+        // Uploads left from earlier runs are due on the first pass
+        let outcomes = queue.process(&opened, Local::now());
+        if !outcomes.is_empty() {
+            persist(&queue);
+            for outcome in outcomes {
+                events.publish(outcome.into());
+            }
+        }
+
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match queued.recv_timeout(interval) {
+            Ok(path) => {
+                for path in std::iter::once(path).chain(queued.try_iter()) {
+                    queue.push(&save_dir, &path, &sinks, Local::now());
+                }
+                // On disk before the upload starts, in case pictd quits
+                persist(&queue);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+}
+
+/// What a running worker decides with
+struct Session {
+    dedup: Dedup,
+    uploader: Option<Uploader>,
+    /// Not waited for when the worker stops
+    hooks: Option<HookRunner>,
+    save_dir: String,
+    config: MonitorConfig,
+    events: Subscribers,
+    own_copies: OwnCopies,
+    last_retention: Option<Instant>,
+}
+
+impl Session {
+    fn publish(&self, event: MonitorEvent) {
+        self.events.publish(event);
+    }
+
+    /// Treat the current clipboard content as already seen
+    fn ignore_current<S: ClipboardSource>(&mut self, source: &mut S) {
+        let seen = match source.get_image() {
+            Ok(Some(rgba_image)) => {
+                self.dedup.mark_seen(&rgba_image);
+                true
+            }
+            _ => {
+                let copied = files::image_files(&source.get_files().unwrap_or_default());
+                let any = !copied.is_empty();
+                self.dedup.mark_files_seen(copied);
+                any
+            }
+        };
+
+        if seen {
+            self.persist();
+            self.publish(MonitorEvent::Skipped {
+                reason: SkipReason::Initial,
+            });
+        }
+    }
+
+    fn capture(&mut self, image: &ClipboardImage) {
+        if !self.dedup.is_new(&image.image) {
+            return;
+        }
+
+        if self.own_copies.take(ImageDigest::of(&image.image)) {
+            self.persist();
+            self.publish(MonitorEvent::Skipped {
+                reason: SkipReason::OwnCopy,
+            });
+            return;
+        }
+
+        let source = if self.config.record_window || self.config.filter.window_class.is_some() {
+            window::active_window()
+        } else {
+            None
+        };
+
+        let verdict = self
+            .config
+            .filter
+            .check(image)
+            .and_then(|()| self.check_window(source.as_ref()))
+            .and_then(|()| self.dedup.check_recent(&image.image));
+        self.persist();
+
+        let source = source.filter(|_| self.config.record_window);
+        match verdict {
+            Err(reason) => self.publish(MonitorEvent::Skipped { reason }),
+            Ok(()) => match storage::save_capture(
+                image,
+                source.as_ref(),
+                &self.save_dir,
+                &self.config.save,
+            ) {
+                Ok(info) => {
+                    self.dedup.record_saved(&image.image);
+                    self.persist();
+                    if let Some(uploader) = &self.uploader {
+                        uploader.push(PathBuf::from(&info.path));
+                    }
+                    if let Some(hooks) = &self.hooks {
+                        hooks.run(HookInput::new(&info));
+                    }
+                    self.publish(MonitorEvent::Saved {
+                        info: Box::new(info),
+                    })
+                }
+                Err(e) => self.publish(MonitorEvent::Error {
+                    message: format!("Failed to save image: {}", e),
+                }),
+            },
+        }
+    }
+
+    fn check_window(&self, source: Option<&SourceWindow>) -> Result<(), SkipReason> {
+        if self.config.filter.window_class.is_none() {
+            return Ok(());
+        }
+
+        self.config
+            .filter
+            .check_window(source.and_then(|s| s.app.as_deref()))
+    }
+
+    /// Skip an image left undecoded because of its size, once per image
+    fn reject_too_large(&mut self, width: u32, height: u32) {
+        if self.dedup.is_new_too_large(width, height) {
+            self.publish(MonitorEvent::Skipped {
+                reason: SkipReason::TooLarge { width, height },
+            });
+        }
+    }
+
+    /// Import the image files of a copied file list, once per list
+    fn import_files(&mut self, copied: &[PathBuf], read_options: &ReadOptions) {
+        let copied = files::image_files(copied);
+        if copied.is_empty() || copied == self.dedup.state.last_files {
+            return;
+        }
+
+        for path in &copied {
+            match files::read_image_file(path, read_options) {
+                Ok(ReadOutcome::Image(image)) => self.capture(&image),
+                Ok(ReadOutcome::TooLarge { width, height }) => {
+                    self.publish(MonitorEvent::Skipped {
+                        reason: SkipReason::TooLarge { width, height },
+                    })
+                }
+                Err(e) => self.publish(MonitorEvent::Error {
+                    message: format!("Failed to import {}: {}", path.display(), e),
+                }),
+            }
+        }
+
+        self.dedup.mark_files_seen(copied);
+        self.persist();
+    }
+
+    /// Trash expired captures, at most once per `retention_interval`
+    fn enforce_retention(&mut self) {
+        if self.config.retention.is_unlimited()
+            || self
+                .last_retention
+                .is_some_and(|last| last.elapsed() < self.config.retention_interval)
+        {
+            return;
+        }
+        self.last_retention = Some(Instant::now());
+
+        match retention::apply_retention(
+            &self.save_dir,
+            &self.config.save.filename,
+            &self.config.retention,
+        ) {
+            Ok(report) if !report.expired.is_empty() => {
+                self.publish(MonitorEvent::Pruned { report })
+            }
+            Ok(_) => {}
+            Err(e) => self.publish(MonitorEvent::Error {
+                message: format!("Failed to apply retention policy: {}", e),
+            }),
+        }
+    }
+
+    fn persist(&self) {
+        if let Err(e) = self.dedup.persist() {
+            self.publish(MonitorEvent::Error {
+                message: format!("Failed to save monitor state: {}", e),
+            });
+        }
+    }
+}
+
+/// Decides which clipboard images are new enough to save
+struct Dedup {
+    state: MonitorState,
+    state_path: Option<PathBuf>,
+    recent_digests: usize,
+    near_duplicates: Option<NearDuplicateFilter>,
+    recent_saves: VecDeque<PerceptualHash>,
+    last_too_large: Option<(u32, u32)>,
+}
+
+impl Dedup {
+    /// Problems loading the saved state are published to `events`
+    fn new(config: &MonitorConfig, events: &Subscribers) -> Self {
+        let state = match config.state_path.as_deref().map(MonitorState::load) {
+            Some(Ok(state)) => state,
+            Some(Err(e)) => {
+                events.publish(MonitorEvent::Error {
+                    message: format!("Starting with a fresh monitor state: {}", e),
+                });
+                MonitorState::default()
+            }
+            None => MonitorState::default(),
+        };
+
+        Self {
+            state,
+            state_path: config.state_path.clone(),
+            recent_digests: config.recent_digests,
+            near_duplicates: config.near_duplicates.clone(),
+            recent_saves: VecDeque::new(),
+            last_too_large: None,
+        }
+    }
+
+    /// Remember `image` as read without saving it
+    fn mark_seen(&mut self, image: &RgbaImage) {
+        self.state.last_digest = Some(ImageDigest::of(image));
+        self.state.last_files.clear();
+    }
+
+    /// Remember a copied file list as imported
+    fn mark_files_seen(&mut self, copied: Vec<PathBuf>) {
+        self.state.last_files = copied;
+    }
+
+    /// Whether `image` differs from the last read; it is remembered either way
+    fn is_new(&mut self, image: &RgbaImage) -> bool {
+        let digest = ImageDigest::of(image);
+        if self.state.last_digest == Some(digest) {
+            return false;
+        }
+
+        self.mark_seen(image);
+        self.last_too_large = None;
+        true
+    }
+
+    /// Whether an undecoded image of this size differs from the last one
+    fn is_new_too_large(&mut self, width: u32, height: u32) -> bool {
+        self.last_too_large.replace((width, height)) != Some((width, height))
+    }
+
+    /// Skip a new image that repeats a recent save
+    fn check_recent(&self, image: &RgbaImage) -> Result<(), SkipReason> {
+        let digest = ImageDigest::of(image);
+        if self.state.recent_digests.contains(&digest) {
+            return Err(SkipReason::RecentDuplicate);
+        }
+
+        match self.check_near_duplicate(image) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+
+    fn check_near_duplicate(&self, image: &RgbaImage) -> Option<SkipReason> {
+        let filter = self.near_duplicates.as_ref()?;
+        let hash = PerceptualHash::of(image);
+
+        self.recent_saves
+            .iter()
+            .map(|saved| saved.distance(&hash))
+            .filter(|distance| *distance < filter.threshold)
+            .min()
+            .map(|distance| SkipReason::NearDuplicate { distance })
+    }
+
+    /// Count `image` as saved; only saved images make later copies duplicates
+    fn record_saved(&mut self, image: &RgbaImage) {
+        if self.recent_digests > 0 {
+            self.state.recent_digests.push_front(ImageDigest::of(image));
+            self.state.recent_digests.truncate(self.recent_digests);
+        }
+
+        if let Some(filter) = &self.near_duplicates {
+            self.recent_saves.push_front(PerceptualHash::of(image));
+            self.recent_saves.truncate(filter.recent);
+        }
+    }
+
+    fn persist(&self) -> Result<()> {
+        match &self.state_path {
+            Some(path) => self.state.save(path),
+            None => Ok(()),
+        }
+    }
+}
 
-    ImageBuffer::from_raw(
-        img_data.width as u32,
-        img_data.height as u32,
-        img_data.bytes.into_owned(),
-    )
+/// Get the current clipboard image if available
+pub fn get_clipboard_image(source: &mut dyn ClipboardSource) -> Option<RgbaImage> {
+    source.get_image().ok().flatten()
 }
 
-/// Compute a simple hash of an image for deduplication
-pub fn simple_hash(img: &RgbaImage) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    img.dimensions().hash(&mut hasher);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{S3Config, SinkTarget};
+    use crate::source::{DropDirSource, MemorySource};
+    use image::Rgba;
+
+    /// Wait for the next `Saved` event, `None` if none arrives within a second
+    fn next_saved(events: &Receiver<MonitorEvent>) -> Option<ImageInfo> {
+        while let Ok(event) = events.recv_timeout(Duration::from_secs(1)) {
+            if let MonitorEvent::Saved { info } = event {
+                return Some(*info);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_monitor_saves_each_new_image_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let clipboard = MemorySource::new();
+        clipboard.push(RgbaImage::from_pixel(8, 6, Rgba([255, 0, 0, 255])));
+
+        let monitor = ClipboardMonitor::new();
+        let events = monitor.subscribe();
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+
+        let first = next_saved(&events).unwrap();
+        assert_eq!((first.width, first.height), (8, 6));
+
+        // The same image still on the clipboard must not be saved again
+        assert!(next_saved(&events).is_none());
+
+        clipboard.push(RgbaImage::from_pixel(3, 3, Rgba([0, 255, 0, 255])));
+        let second = next_saved(&events).unwrap();
+        assert_eq!((second.width, second.height), (3, 3));
+
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_failed_uploads_stay_queued() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue_path = dir.path().join("upload-queue.json");
+        let clipboard = MemorySource::new();
+        clipboard.push(RgbaImage::from_pixel(4, 4, Rgba([0, 128, 0, 255])));
+
+        // Nothing listens on port 1, so the connection is refused
+        let monitor = ClipboardMonitor::with_config(MonitorConfig {
+            sinks: vec![SinkConfig {
+                name: "bucket".to_string(),
+                target: SinkTarget::S3(S3Config {
+                    endpoint: "http://127.0.0.1:1".to_string(),
+                    region: "us-east-1".to_string(),
+                    bucket: "shots".to_string(),
+                    prefix: String::new(),
+                    access_key: "minio".to_string(),
+                    secret_key: "minio123".to_string(),
+                    public_url: None,
+                }),
+            }],
+            upload_queue_path: Some(queue_path.clone()),
+            ..MonitorConfig::default()
+        });
+        let events = monitor.subscribe();
+        monitor.start(
+            clipboard.clone(),
+            dir.path().join("shots").to_string_lossy().to_string(),
+        );
+
+        let mut failed = None;
+        while let Ok(event) = events.recv_timeout(Duration::from_secs(5)) {
+            if let MonitorEvent::UploadFailed { .. } = event {
+                failed = Some(event);
+                break;
+            }
+        }
+        monitor.stop();
+        assert!(matches!(
+            failed,
+            Some(MonitorEvent::UploadFailed {
+                retry_at: Some(_),
+                ..
+            })
+        ));
+
+        let queue = UploadQueue::load(&queue_path).unwrap();
+        assert_eq!(queue.pending.len(), 1);
+        assert_eq!(queue.pending[0].sink, "bucket");
+        assert_eq!(queue.pending[0].attempts, 1);
+    }
+
+    #[test]
+    fn test_monitor_polls_sources_without_notifications() {
+        let drop_dir = tempfile::tempdir().unwrap();
+        let save_dir = tempfile::tempdir().unwrap();
+
+        let monitor = ClipboardMonitor::with_config(MonitorConfig {
+            poll_interval: Duration::from_millis(50),
+            ..MonitorConfig::default()
+        });
+        let events = monitor.subscribe();
+        monitor.start(
+            DropDirSource::new(drop_dir.path()),
+            save_dir.path().to_string_lossy().to_string(),
+        );
+
+        RgbaImage::from_pixel(7, 2, Rgba([0, 0, 255, 255]))
+            .save(drop_dir.path().join("copied.png"))
+            .unwrap();
+
+        let info = next_saved(&events).unwrap();
+        assert_eq!((info.width, info.height), (7, 2));
+
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_near_duplicates_are_skipped() {
+        let mut dedup = Dedup::new(
+            &MonitorConfig {
+                near_duplicates: Some(NearDuplicateFilter::default()),
+                ..MonitorConfig::default()
+            },
+            &Subscribers::default(),
+        );
+        let mut frame =
+            RgbaImage::from_fn(64, 64, |x, y| Rgba([(x * 4) as u8, (y * 4) as u8, 0, 255]));
+
+        assert!(dedup.is_new(&frame));
+        assert_eq!(dedup.check_recent(&frame), Ok(()));
+        dedup.record_saved(&frame);
+
+        // A single changed pixel is a new digest but looks the same
+        frame.put_pixel(10, 10, Rgba([255, 255, 255, 255]));
+        assert!(dedup.is_new(&frame));
+        assert!(matches!(
+            dedup.check_recent(&frame),
+            Err(SkipReason::NearDuplicate { .. })
+        ));
+
+        // Until it is saved, an image doesn't make its copies duplicates
+        let mirrored = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(252 - x * 4) as u8, (y * 4) as u8, 0, 255])
+        });
+        assert!(dedup.is_new(&mirrored));
+        assert_eq!(dedup.check_recent(&mirrored), Ok(()));
+        assert_eq!(dedup.check_recent(&mirrored), Ok(()));
+    }
+
+    #[test]
+    fn test_dedup_state_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = MonitorConfig {
+            recent_digests: 5,
+            state_path: Some(dir.path().join("state.json")),
+            ..MonitorConfig::default()
+        };
+        let a = RgbaImage::from_pixel(4, 4, Rgba([1, 1, 1, 255]));
+        let b = RgbaImage::from_pixel(4, 4, Rgba([2, 2, 2, 255]));
 
-    // Sample pixels for faster hashing
-    let (w, h) = img.dimensions();
-    let step = ((w * h) / 1000).max(1) as usize;
-    for (i, pixel) in img.pixels().enumerate() {
-        if i % step == 0 {
-            pixel.0.hash(&mut hasher);
+        let mut dedup = Dedup::new(&config, &Subscribers::default());
+        for image in [&a, &b] {
+            assert!(dedup.is_new(image));
+            assert_eq!(dedup.check_recent(image), Ok(()));
+            dedup.record_saved(image);
         }
+        dedup.persist().unwrap();
+
+        // Restarted with B still on the clipboard, then A copied again
+        let mut dedup = Dedup::new(&config, &Subscribers::default());
+        assert!(!dedup.is_new(&b));
+        assert!(dedup.is_new(&a));
+        assert_eq!(dedup.check_recent(&a), Err(SkipReason::RecentDuplicate));
     }
 
-    hasher.finish()
+    #[test]
+    fn test_monitor_stores_offered_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let clipboard = MemorySource::new();
+        let gif = {
+            let mut buf = std::io::Cursor::new(Vec::new());
+            RgbaImage::from_pixel(5, 5, Rgba([0, 0, 255, 255]))
+                .write_to(&mut buf, image::ImageFormat::Gif)
+                .unwrap();
+            buf.into_inner()
+        };
+        clipboard
+            .push_encoded(ImageFormat::Gif, gif.clone())
+            .unwrap();
+
+        let monitor = ClipboardMonitor::new();
+        let events = monitor.subscribe();
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+
+        let info = next_saved(&events).unwrap();
+        assert_eq!(info.format, ImageFormat::Gif);
+        assert!(info.filename.ends_with(".gif"));
+        assert_eq!(std::fs::read(&info.path).unwrap(), gif);
+
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_monitor_imports_copied_files_once() {
+        let files_dir = tempfile::tempdir().unwrap();
+        let save_dir = tempfile::tempdir().unwrap();
+        let a = files_dir.path().join("a.png");
+        let b = files_dir.path().join("b.jpg");
+        RgbaImage::from_pixel(4, 4, Rgba([1, 1, 1, 255]))
+            .save(&a)
+            .unwrap();
+        image::RgbImage::from_pixel(6, 2, image::Rgb([200, 0, 0]))
+            .save(&b)
+            .unwrap();
+        std::fs::write(files_dir.path().join("c.txt"), "text").unwrap();
+
+        let clipboard = MemorySource::new();
+        clipboard.push_files(vec![a, b, files_dir.path().join("c.txt")]);
+
+        let monitor = ClipboardMonitor::new();
+        let events = monitor.subscribe();
+        monitor.start(
+            clipboard.clone(),
+            save_dir.path().to_string_lossy().to_string(),
+        );
+
+        let first = next_saved(&events).unwrap();
+        let second = next_saved(&events).unwrap();
+        assert_eq!(first.format, ImageFormat::Png);
+        assert_eq!((second.format, second.width), (ImageFormat::Jpeg, 6));
+
+        // The list stays on the clipboard; it must not be imported again
+        assert!(next_saved(&events).is_none());
+
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_filtered_captures_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let clipboard = MemorySource::new();
+        let monitor = ClipboardMonitor::with_config(MonitorConfig {
+            filter: CaptureFilter {
+                min_width: 32,
+                min_height: 32,
+                max_pixels: Some(100 * 100),
+                ..CaptureFilter::default()
+            },
+            ..MonitorConfig::default()
+        });
+        let events = monitor.subscribe();
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+
+        let mut png = std::io::Cursor::new(Vec::new());
+        RgbaImage::new(200, 120)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        clipboard.push(RgbaImage::new(16, 16));
+        clipboard
+            .push_encoded(ImageFormat::Png, png.into_inner())
+            .unwrap();
+        clipboard.push(RgbaImage::new(64, 48));
+
+        let mut skipped = Vec::new();
+        while let Ok(event) = events.recv_timeout(Duration::from_secs(1)) {
+            match event {
+                MonitorEvent::Skipped { reason } => skipped.push(reason),
+                MonitorEvent::Saved { info } => {
+                    assert_eq!((info.width, info.height), (64, 48));
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            skipped,
+            [
+                SkipReason::TooSmall {
+                    width: 16,
+                    height: 16
+                },
+                SkipReason::TooLarge {
+                    width: 200,
+                    height: 120
+                },
+            ]
+        );
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_own_copies_are_not_saved_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let clipboard = MemorySource::new();
+        let monitor = ClipboardMonitor::new();
+        let events = monitor.subscribe();
+
+        let copied = RgbaImage::from_pixel(8, 8, Rgba([1, 2, 3, 255]));
+        let digest = ImageDigest::of(&copied);
+        monitor.mark_own_copy(digest);
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+
+        clipboard.push(copied.clone());
+        let skipped = events
+            .iter()
+            .find_map(|event| match event {
+                MonitorEvent::Skipped { reason } => Some(reason),
+                MonitorEvent::Saved { .. } => panic!("own copy was saved"),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(skipped, SkipReason::OwnCopy);
+
+        // The same image copied by someone else later is a capture again
+        clipboard.push(RgbaImage::new(8, 8));
+        clipboard.push(copied);
+        let saved = std::iter::from_fn(|| next_saved(&events)).nth(1).unwrap();
+        assert_eq!(saved.digest, digest);
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_restart_keeps_a_single_worker() {
+        let dir = tempfile::tempdir().unwrap();
+        let clipboard = MemorySource::new();
+        let monitor = ClipboardMonitor::new();
+        let events = monitor.subscribe();
+        let lifecycle = monitor.subscribe();
+
+        // Pause and resume in quick succession, like the tray toggle
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+        monitor.stop();
+        assert!(!monitor.is_running());
+        monitor.start(clipboard.clone(), dir.path().to_string_lossy().to_string());
+
+        clipboard.push(RgbaImage::from_pixel(2, 2, Rgba([9, 9, 9, 255])));
+        assert!(next_saved(&events).is_some());
+        assert!(next_saved(&events).is_none());
+        assert_eq!(
+            storage::list_saved_images(&dir.path().to_string_lossy()).len(),
+            1
+        );
+
+        monitor.stop();
+        let starts_and_stops: Vec<_> = lifecycle
+            .try_iter()
+            .filter_map(|e| match e {
+                MonitorEvent::Started => Some("started"),
+                MonitorEvent::Stopped => Some("stopped"),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            starts_and_stops,
+            ["started", "stopped", "started", "stopped"]
+        );
+    }
 }
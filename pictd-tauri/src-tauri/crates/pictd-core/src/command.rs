@@ -0,0 +1,441 @@
+//! Clipboard source that runs an external command and decodes its output.
+//!
+//! Used where arboard cannot reach the clipboard, most notably the Windows
+//! clipboard from inside WSL.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::RgbaImage;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::files;
+use crate::format::ImageFormat;
+use crate::source::ClipboardSource;
+
+const WSL_POWERSHELL_SCRIPT: &str = r#"
+Add-Type -AssemblyName System.Windows.Forms
+$clip = [System.Windows.Forms.Clipboard]::GetImage()
+if ($clip) {
+    $ms = New-Object System.IO.MemoryStream
+    $clip.Save($ms, [System.Drawing.Imaging.ImageFormat]::Png)
+    [Convert]::ToBase64String($ms.ToArray())
+}
+"#;
+
+/// How the command prints the image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// Encoded image bytes (PNG, JPEG, ...)
+    Raw,
+    /// Base64 of the encoded image bytes, surrounding whitespace ignored
+    Base64,
+    /// Raw if the output starts with a known image signature, base64 otherwise
+    Auto,
+}
+
+#[derive(Clone, Debug)]
+pub struct CommandSource {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub encoding: OutputEncoding,
+    /// Arguments that list the offered MIME types, one per line
+    pub list_args: Option<Vec<String>>,
+    /// Arguments that print the content of one MIME type as raw bytes,
+    /// with `{mime}` standing for the type
+    pub typed_args: Option<Vec<String>>,
+    /// The command is killed and the read fails once this elapses
+    pub timeout: Duration,
+}
+
+impl CommandSource {
+    pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+            encoding: OutputEncoding::Auto,
+            list_args: None,
+            typed_args: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Run the command and return its stdout, `None` if it exited unsuccessfully
+    fn run(&self, args: &[String]) -> Result<Option<Vec<u8>>> {
+        let mut child = Command::new(&self.program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| {
+                Error::ClipboardUnavailable(format!(
+                    "Failed to run {}: {}",
+                    self.program.display(),
+                    e
+                ))
+            })?;
+
+        // Drain stdout on its own thread so a large image can't fill the pipe
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let (output_tx, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = output_tx.send(stdout.read_to_end(&mut buf).map(|_| buf));
+        });
+
+        let started = Instant::now();
+        let status = loop {
+            match child.try_wait().map_err(Error::clipboard)? {
+                Some(status) => break status,
+                None if started.elapsed() >= self.timeout => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(Error::ClipboardUnavailable(format!(
+                        "{} timed out after {:?}",
+                        self.program.display(),
+                        self.timeout
+                    )));
+                }
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // wl-paste and xclip exit non-zero when no image is on offer
+        if !status.success() {
+            return Ok(None);
+        }
+
+        // A helper the command forked may keep stdout open after it exits,
+        // so the rest of the output gets what is left of the timeout
+        let remaining = self.timeout.saturating_sub(started.elapsed());
+        match output.recv_timeout(remaining) {
+            Ok(read) => read.map(Some).map_err(Error::clipboard),
+            Err(_) => Err(Error::ClipboardUnavailable(format!(
+                "Output of {} still open after {:?}",
+                self.program.display(),
+                self.timeout
+            ))),
+        }
+    }
+}
+
+impl ClipboardSource for CommandSource {
+    fn get_image(&mut self) -> Result<Option<RgbaImage>> {
+        match self.run(&self.args)? {
+            Some(output) => decode_output(&output, self.encoding),
+            None => Ok(None),
+        }
+    }
+
+    fn formats(&mut self) -> Result<Vec<ImageFormat>> {
+        let Some(list_args) = &self.list_args else {
+            return Ok(Vec::new());
+        };
+
+        let Some(output) = self.run(list_args)? else {
+            return Ok(Vec::new());
+        };
+
+        let mut formats = Vec::new();
+        for format in String::from_utf8_lossy(&output)
+            .lines()
+            .filter_map(ImageFormat::from_mime_type)
+        {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+
+        Ok(formats)
+    }
+
+    fn get_encoded(&mut self, format: ImageFormat) -> Result<Option<Vec<u8>>> {
+        let Some(typed_args) = &self.typed_args else {
+            return Ok(None);
+        };
+
+        let args: Vec<String> = typed_args
+            .iter()
+            .map(|arg| arg.replace("{mime}", format.mime_type()))
+            .collect();
+
+        Ok(self.run(&args)?.filter(|output| !output.is_empty()))
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        let Some(typed_args) = &self.typed_args else {
+            return Ok(Vec::new());
+        };
+
+        let args: Vec<String> = typed_args
+            .iter()
+            .map(|arg| arg.replace("{mime}", "text/uri-list"))
+            .collect();
+
+        Ok(match self.run(&args)? {
+            Some(output) => files::parse_uri_list(&String::from_utf8_lossy(&output)),
+            None => Vec::new(),
+        })
+    }
+}
+
+/// Decode what a clipboard command printed, `Ok(None)` for empty output
+pub fn decode_output(output: &[u8], encoding: OutputEncoding) -> Result<Option<RgbaImage>> {
+    if output.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    let is_raw = match encoding {
+        OutputEncoding::Raw => true,
+        OutputEncoding::Base64 => false,
+        OutputEncoding::Auto => image::guess_format(output).is_ok(),
+    };
+
+    let bytes = if is_raw {
+        output.to_vec()
+    } else {
+        let text: Vec<u8> = output
+            .iter()
+            .copied()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        STANDARD
+            .decode(text)
+            .map_err(|e| Error::Decode(format!("Invalid base64 image: {}", e)))?
+    };
+
+    let img = image::load_from_memory(&bytes).map_err(|e| Error::from_decoding(e, None))?;
+    Ok(Some(img.to_rgba8()))
+}
+
+/// Ready-made commands for common clipboard tools
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandPreset {
+    /// `wl-paste` from wl-clipboard (Wayland)
+    WlPaste,
+    /// `xclip` (X11)
+    Xclip,
+    /// `powershell.exe` reading the Windows clipboard from WSL
+    WslPowershell,
+}
+
+impl CommandPreset {
+    /// Pick a preset for the current session: WSL first, then Wayland, then X11
+    pub fn detect() -> Option<Self> {
+        if is_wsl() {
+            Some(Self::WslPowershell)
+        } else if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            Some(Self::WlPaste)
+        } else if std::env::var_os("DISPLAY").is_some() {
+            Some(Self::Xclip)
+        } else {
+            None
+        }
+    }
+
+    pub fn program(self) -> &'static str {
+        match self {
+            Self::WlPaste => "wl-paste",
+            Self::Xclip => "xclip",
+            Self::WslPowershell => "powershell.exe",
+        }
+    }
+
+    pub fn source(self) -> CommandSource {
+        self.with_program(self.program())
+    }
+
+    /// Whether the preset's program is on `PATH`
+    pub fn is_installed(self) -> bool {
+        std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|dir| dir.join(self.program()).is_file())
+        })
+    }
+
+    /// The preset's arguments and decoding, run through a different executable
+    pub fn with_program(self, program: impl Into<PathBuf>) -> CommandSource {
+        let (args, encoding): (&[&str], _) = match self {
+            Self::WlPaste => (
+                &["--no-newline", "--type", "image/png"],
+                OutputEncoding::Raw,
+            ),
+            Self::Xclip => (
+                &["-selection", "clipboard", "-o", "-t", "image/png"],
+                OutputEncoding::Raw,
+            ),
+            Self::WslPowershell => (
+                &[
+                    "-NoProfile",
+                    "-NonInteractive",
+                    "-Command",
+                    WSL_POWERSHELL_SCRIPT,
+                ],
+                OutputEncoding::Base64,
+            ),
+        };
+
+        let (list_args, typed_args): (&[&str], &[&str]) = match self {
+            Self::WlPaste => (&["--list-types"], &["--no-newline", "--type", "{mime}"]),
+            Self::Xclip => (
+                &["-selection", "clipboard", "-o", "-t", "TARGETS"],
+                &["-selection", "clipboard", "-o", "-t", "{mime}"],
+            ),
+            Self::WslPowershell => (&[], &[]),
+        };
+
+        let to_args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let typed = !list_args.is_empty();
+
+        CommandSource {
+            encoding,
+            list_args: typed.then(|| to_args(list_args)),
+            typed_args: typed.then(|| to_args(typed_args)),
+            ..CommandSource::new(program, to_args(args))
+        }
+    }
+}
+
+/// Whether we are running inside the Windows Subsystem for Linux
+pub fn is_wsl() -> bool {
+    if std::env::var_os("WSL_DISTRO_NAME").is_some() || std::env::var_os("WSL_INTEROP").is_some() {
+        return true;
+    }
+
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|release| release.to_lowercase().contains("microsoft"))
+        .unwrap_or(false)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::source::{ReadOptions, ReadOutcome};
+    use image::Rgba;
+    use std::io::Cursor;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    fn png_bytes() -> Vec<u8> {
+        let mut buf = Cursor::new(Vec::new());
+        RgbaImage::from_pixel(3, 2, Rgba([1, 2, 3, 255]))
+            .write_to(&mut buf, image::ImageFormat::Png)
+            .unwrap();
+        buf.into_inner()
+    }
+
+    /// Write an executable script that checks its first argument and prints `payload`
+    fn fake_tool(dir: &Path, name: &str, first_arg: &str, payload: &Path) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = \"{}\" ] || exit 1\ncat '{}'\n",
+                first_arg,
+                payload.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_presets_decode_fake_tool_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = dir.path().join("image.png");
+        let b64 = dir.path().join("image.b64");
+        fs::write(&raw, png_bytes()).unwrap();
+        fs::write(&b64, format!("{}\r\n", STANDARD.encode(png_bytes()))).unwrap();
+
+        let cases = [
+            (CommandPreset::WlPaste, "--no-newline", &raw),
+            (CommandPreset::Xclip, "-selection", &raw),
+            (CommandPreset::WslPowershell, "-NoProfile", &b64),
+        ];
+
+        for (preset, first_arg, payload) in cases {
+            let tool = fake_tool(dir.path(), preset.program(), first_arg, payload);
+            let mut source = preset.with_program(tool);
+            let img = source.get_image().unwrap().unwrap();
+            assert_eq!(img.dimensions(), (3, 2), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn test_typed_read_keeps_offered_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = dir.path().join("image.png");
+        let jpeg = dir.path().join("image.jpg");
+        fs::write(&png, png_bytes()).unwrap();
+        let mut jpeg_bytes = Cursor::new(Vec::new());
+        image::RgbImage::from_pixel(3, 2, image::Rgb([1, 2, 3]))
+            .write_to(&mut jpeg_bytes, image::ImageFormat::Jpeg)
+            .unwrap();
+        fs::write(&jpeg, jpeg_bytes.get_ref()).unwrap();
+
+        // Offers JPEG first, like a browser copying a photo
+        let tool = dir.path().join("wl-paste");
+        fs::write(
+            &tool,
+            format!(
+                "#!/bin/sh\n\
+                 [ \"$1\" = --list-types ] && printf 'text/html\\nimage/jpeg\\nimage/png\\n' && exit 0\n\
+                 [ \"$3\" = image/jpeg ] && cat '{}' && exit 0\n\
+                 [ \"$3\" = image/png ] && cat '{}' && exit 0\n\
+                 exit 1\n",
+                jpeg.display(),
+                png.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&tool, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut source = CommandPreset::WlPaste.with_program(tool);
+
+        assert_eq!(
+            source.formats().unwrap(),
+            [ImageFormat::Jpeg, ImageFormat::Png]
+        );
+
+        let read = source.read(&ReadOptions::default()).unwrap();
+        let encoded = read.and_then(ReadOutcome::image).unwrap().encoded.unwrap();
+        assert_eq!(encoded.format, ImageFormat::Jpeg);
+        assert_eq!(encoded.bytes, jpeg_bytes.into_inner());
+
+        let png_only = ReadOptions {
+            formats: vec![ImageFormat::Png],
+            ..ReadOptions::default()
+        };
+        let read = source.read(&png_only).unwrap();
+        let encoded = read.and_then(ReadOutcome::image).unwrap().encoded.unwrap();
+        assert_eq!(encoded.format, ImageFormat::Png);
+    }
+
+    #[test]
+    fn test_failed_command_means_no_image() {
+        let mut source = CommandSource::new("false", Vec::new());
+        assert_eq!(source.get_image().unwrap(), None);
+    }
+
+    #[test]
+    fn test_command_times_out() {
+        let mut source = CommandSource::new("sleep", vec!["5".to_string()]);
+        source.timeout = Duration::from_millis(100);
+        assert!(source.get_image().is_err());
+
+        // Also when a forked helper holds stdout after the command exits
+        let mut source = CommandSource::new(
+            "sh",
+            vec!["-c".to_string(), "sleep 5 & printf x".to_string()],
+        );
+        source.timeout = Duration::from_millis(300);
+        let started = Instant::now();
+        assert!(source.get_image().is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
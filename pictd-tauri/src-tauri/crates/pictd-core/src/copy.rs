@@ -0,0 +1,523 @@
+//! Putting a saved image back on the clipboard.
+//!
+//! The image is offered both as `image/png` and as a file URI, so it pastes
+//! into chat and issue trackers as well as into file managers. arboard can
+//! only offer one of them at a time, so on Linux pictd owns the selection
+//! itself: through `wlr-data-control` on wlroots compositors and as an X11
+//! selection owner elsewhere (XWayland syncs it to Wayland clients). The
+//! selection is served from a background thread until another application
+//! takes the clipboard.
+
+use image::RgbaImage;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::digest::ImageDigest;
+use crate::error::{Error, Result};
+use crate::files;
+use crate::format;
+
+/// A saved image, ready to be put on the clipboard
+pub struct ClipboardCopy {
+    image: RgbaImage,
+    png: Vec<u8>,
+    uri_list: String,
+}
+
+impl ClipboardCopy {
+    /// Prepare the image file at `path`
+    pub fn of_file(path: &Path) -> Result<Self> {
+        let path = path.canonicalize().map_err(Error::at(path))?;
+        let image = format::open_image(&path)?;
+
+        let mut png = Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, image::ImageFormat::Png)
+            .map_err(Error::from_encoding)?;
+
+        Ok(Self {
+            image,
+            png: png.into_inner(),
+            uri_list: files::to_uri_list(&[path]),
+        })
+    }
+
+    /// Digest of the pixels a clipboard read will get back
+    pub fn digest(&self) -> ImageDigest {
+        ImageDigest::of(&self.image)
+    }
+
+    /// Clipboard targets and their bytes, preferred first
+    pub fn targets(&self) -> Vec<(&'static str, Vec<u8>)> {
+        let uri_list = self.uri_list.clone().into_bytes();
+        let nautilus = [b"copy\n".as_slice(), self.uri_list.trim_end().as_bytes()].concat();
+
+        vec![
+            ("image/png", self.png.clone()),
+            ("text/uri-list", uri_list),
+            ("x-special/gnome-copied-files", nautilus),
+        ]
+    }
+
+    /// Put the image on the system clipboard
+    pub fn write(&self) -> Result<()> {
+        #[cfg(all(
+            unix,
+            not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+        ))]
+        {
+            if std::env::var_os("WAYLAND_DISPLAY").is_some()
+                && wayland::offer(self.targets()).is_ok()
+            {
+                return Ok(());
+            }
+            if std::env::var_os("DISPLAY").is_some() {
+                return x11::offer(self.targets());
+            }
+        }
+
+        // Elsewhere only the pixels are offered
+        let mut clipboard = arboard::Clipboard::new().map_err(Error::clipboard)?;
+        clipboard
+            .set_image(arboard::ImageData {
+                width: self.image.width() as usize,
+                height: self.image.height() as usize,
+                bytes: self.image.as_raw().into(),
+            })
+            .map_err(Error::clipboard)
+    }
+}
+
+/// Put the image file at `path` on the system clipboard, returning the
+/// digest a clipboard read will see
+pub fn copy_image_to_clipboard(path: &Path) -> Result<ImageDigest> {
+    let copy = ClipboardCopy::of_file(path)?;
+    copy.write()?;
+    Ok(copy.digest())
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod x11 {
+    use crate::error::{Error, Result};
+    use std::thread;
+    use x11rb::connection::{Connection, RequestConnection};
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt as _, CreateWindowAux, EventMask,
+        PropMode, Property, PropertyNotifyEvent, SelectionNotifyEvent, SelectionRequestEvent,
+        Window, WindowClass, SELECTION_NOTIFY_EVENT,
+    };
+    use x11rb::protocol::Event;
+    use x11rb::rust_connection::RustConnection;
+    use x11rb::wrapper::ConnectionExt as _;
+    use x11rb::{COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE};
+
+    /// A target sent in chunks through the `INCR` protocol
+    struct Transfer {
+        requestor: Window,
+        property: Atom,
+        target: Atom,
+        offset: usize,
+    }
+
+    struct Owner {
+        conn: RustConnection,
+        targets_atom: Atom,
+        incr: Atom,
+        offers: Vec<(Atom, Vec<u8>)>,
+        chunk: usize,
+        transfers: Vec<Transfer>,
+    }
+
+    fn intern(conn: &RustConnection, name: &str) -> Result<Atom> {
+        Ok(conn
+            .intern_atom(false, name.as_bytes())
+            .map_err(Error::clipboard)?
+            .reply()
+            .map_err(Error::clipboard)?
+            .atom)
+    }
+
+    pub fn offer(targets: Vec<(&'static str, Vec<u8>)>) -> Result<()> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(Error::clipboard)?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let window = conn.generate_id().map_err(Error::clipboard)?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_ONLY,
+            COPY_FROM_PARENT,
+            &CreateWindowAux::new(),
+        )
+        .map_err(Error::clipboard)?;
+
+        let clipboard = intern(&conn, "CLIPBOARD")?;
+        let mut offers = Vec::new();
+        for (mime, bytes) in targets {
+            offers.push((intern(&conn, mime)?, bytes));
+        }
+
+        conn.set_selection_owner(window, clipboard, CURRENT_TIME)
+            .map_err(Error::clipboard)?;
+        let owner = conn
+            .get_selection_owner(clipboard)
+            .map_err(Error::clipboard)?
+            .reply()
+            .map_err(Error::clipboard)?
+            .owner;
+        if owner != window {
+            return Err(Error::clipboard("Another application kept the clipboard"));
+        }
+
+        let owner = Owner {
+            targets_atom: intern(&conn, "TARGETS")?,
+            incr: intern(&conn, "INCR")?,
+            offers,
+            // Well below the request size limit, leaving room for the header
+            chunk: (conn.maximum_request_bytes() / 4).max(4096),
+            transfers: Vec::new(),
+            conn,
+        };
+        thread::spawn(move || owner.serve());
+
+        Ok(())
+    }
+
+    impl Owner {
+        /// Answer requests until another client owns the clipboard
+        fn serve(mut self) {
+            while let Ok(event) = self.conn.wait_for_event() {
+                let served = match event {
+                    Event::SelectionClear(_) => break,
+                    Event::SelectionRequest(request) => self.answer(&request),
+                    Event::PropertyNotify(notify) => self.continue_transfer(&notify),
+                    _ => Ok(()),
+                };
+                if served.and_then(|()| self.flush()).is_err() {
+                    break;
+                }
+            }
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.conn.flush().map_err(Error::clipboard)
+        }
+
+        fn answer(&mut self, request: &SelectionRequestEvent) -> Result<()> {
+            // Obsolete clients leave the property to the owner
+            let property = if request.property == NONE {
+                request.target
+            } else {
+                request.property
+            };
+
+            let stored = if request.target == self.targets_atom {
+                let mut atoms = vec![self.targets_atom];
+                atoms.extend(self.offers.iter().map(|(atom, _)| *atom));
+                self.conn
+                    .change_property32(
+                        PropMode::REPLACE,
+                        request.requestor,
+                        property,
+                        AtomEnum::ATOM,
+                        &atoms,
+                    )
+                    .map_err(Error::clipboard)?;
+                true
+            } else if let Some((target, bytes)) =
+                self.offers.iter().find(|(atom, _)| *atom == request.target)
+            {
+                if bytes.len() > self.chunk {
+                    self.conn
+                        .change_window_attributes(
+                            request.requestor,
+                            &ChangeWindowAttributesAux::new()
+                                .event_mask(EventMask::PROPERTY_CHANGE),
+                        )
+                        .map_err(Error::clipboard)?;
+                    self.conn
+                        .change_property32(
+                            PropMode::REPLACE,
+                            request.requestor,
+                            property,
+                            self.incr,
+                            &[bytes.len() as u32],
+                        )
+                        .map_err(Error::clipboard)?;
+                    self.transfers.push(Transfer {
+                        requestor: request.requestor,
+                        property,
+                        target: *target,
+                        offset: 0,
+                    });
+                } else {
+                    self.conn
+                        .change_property8(
+                            PropMode::REPLACE,
+                            request.requestor,
+                            property,
+                            *target,
+                            bytes,
+                        )
+                        .map_err(Error::clipboard)?;
+                }
+                true
+            } else {
+                false
+            };
+
+            let notify = SelectionNotifyEvent {
+                response_type: SELECTION_NOTIFY_EVENT,
+                sequence: 0,
+                time: request.time,
+                requestor: request.requestor,
+                selection: request.selection,
+                target: request.target,
+                property: if stored { property } else { NONE },
+            };
+            self.conn
+                .send_event(false, request.requestor, EventMask::NO_EVENT, notify)
+                .map_err(Error::clipboard)?;
+            Ok(())
+        }
+
+        /// Send the next chunk once the requestor deleted the previous one;
+        /// an empty chunk ends the transfer
+        fn continue_transfer(&mut self, notify: &PropertyNotifyEvent) -> Result<()> {
+            if notify.state != Property::DELETE {
+                return Ok(());
+            }
+            let Some(index) = self
+                .transfers
+                .iter()
+                .position(|t| t.requestor == notify.window && t.property == notify.atom)
+            else {
+                return Ok(());
+            };
+
+            let transfer = &mut self.transfers[index];
+            let Some((_, bytes)) = self
+                .offers
+                .iter()
+                .find(|(atom, _)| *atom == transfer.target)
+            else {
+                return Ok(());
+            };
+            let end = (transfer.offset + self.chunk).min(bytes.len());
+            let chunk = &bytes[transfer.offset..end];
+
+            self.conn
+                .change_property8(
+                    PropMode::REPLACE,
+                    transfer.requestor,
+                    transfer.property,
+                    transfer.target,
+                    chunk,
+                )
+                .map_err(Error::clipboard)?;
+
+            if chunk.is_empty() {
+                self.transfers.remove(index);
+            } else {
+                transfer.offset = end;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod wayland {
+    use crate::error::{Error, Result};
+    use std::fs::File;
+    use std::io::Write;
+    use std::thread;
+    use wayland_client::globals::{registry_queue_init, GlobalListContents};
+    use wayland_client::protocol::{wl_registry::WlRegistry, wl_seat::WlSeat};
+    use wayland_client::{event_created_child, Connection, Dispatch, Proxy, QueueHandle};
+    use wayland_protocols_wlr::data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+        zwlr_data_control_source_v1::{self, ZwlrDataControlSourceV1},
+    };
+
+    struct State {
+        offers: Vec<(&'static str, Vec<u8>)>,
+        done: bool,
+    }
+
+    pub fn offer(targets: Vec<(&'static str, Vec<u8>)>) -> Result<()> {
+        let conn = Connection::connect_to_env().map_err(Error::clipboard)?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(Error::clipboard)?;
+        let qh = queue.handle();
+
+        let seat: WlSeat = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|e| Error::clipboard(format!("No seat: {}", e)))?;
+        let manager: ZwlrDataControlManagerV1 = globals
+            .bind(&qh, 1..=2, ())
+            .map_err(|e| Error::clipboard(format!("Compositor lacks data-control: {}", e)))?;
+
+        let source = manager.create_data_source(&qh, ());
+        for (mime, _) in &targets {
+            source.offer(mime.to_string());
+        }
+        let device = manager.get_data_device(&seat, &qh, ());
+        device.set_selection(Some(&source));
+
+        let mut state = State {
+            offers: targets,
+            done: false,
+        };
+        queue.roundtrip(&mut state).map_err(Error::clipboard)?;
+
+        thread::spawn(move || {
+            while !state.done {
+                if queue.blocking_dispatch(&mut state).is_err() {
+                    break;
+                }
+            }
+            device.destroy();
+        });
+
+        Ok(())
+    }
+
+    impl Dispatch<ZwlrDataControlSourceV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            source: &ZwlrDataControlSourceV1,
+            event: zwlr_data_control_source_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            match event {
+                zwlr_data_control_source_v1::Event::Send { mime_type, fd } => {
+                    if let Some((_, bytes)) = state.offers.iter().find(|(m, _)| *m == mime_type) {
+                        // A reader that went away is not our problem
+                        let _ = File::from(fd).write_all(bytes);
+                    }
+                }
+                zwlr_data_control_source_v1::Event::Cancelled => {
+                    source.destroy();
+                    state.done = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    impl Dispatch<WlRegistry, GlobalListContents> for State {
+        fn event(
+            _: &mut Self,
+            _: &WlRegistry,
+            _: <WlRegistry as Proxy>::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<WlSeat, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &WlSeat,
+            _: <WlSeat as Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrDataControlManagerV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrDataControlManagerV1,
+            _: <ZwlrDataControlManagerV1 as Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrDataControlOfferV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrDataControlOfferV1,
+            _: <ZwlrDataControlOfferV1 as Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrDataControlDeviceV1,
+            event: zwlr_data_control_device_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            // Offers of the current selection, ours included, are not needed
+            match event {
+                zwlr_data_control_device_v1::Event::Selection { id: Some(offer) }
+                | zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                    offer.destroy();
+                }
+                _ => {}
+            }
+        }
+
+        event_created_child!(State, ZwlrDataControlDeviceV1, [
+            zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_copy_offers_png_and_file_uri() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shot 1.jpg");
+        let image = RgbaImage::from_pixel(4, 3, Rgba([10, 20, 30, 255]));
+        image::DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .save(&path)
+            .unwrap();
+
+        let copy = ClipboardCopy::of_file(&path).unwrap();
+        let targets = copy.targets();
+        assert_eq!(targets[0].0, "image/png");
+
+        // What a reader decodes is what the monitor will expect
+        let decoded = image::load_from_memory(&targets[0].1).unwrap().to_rgba8();
+        assert_eq!(ImageDigest::of(&decoded), copy.digest());
+
+        let uris = String::from_utf8(targets[1].1.clone()).unwrap();
+        assert_eq!(files::parse_uri_list(&uris), [path.canonicalize().unwrap()]);
+    }
+}
@@ -0,0 +1,188 @@
+//! Encoding images for saving.
+
+use image::codecs::avif::AvifEncoder;
+use image::codecs::gif::GifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use crate::error::{Error, Result};
+use crate::format::ImageFormat;
+use crate::naming::FilenameTemplate;
+use crate::optimize::OptimizeOptions;
+
+/// AVIF encoder speed, 1 (slowest, smallest) to 10
+const AVIF_SPEED: u8 = 6;
+
+/// How images are encoded when saved
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SaveOptions {
+    /// Encode to this format. `None` keeps the clipboard's encoding, or PNG
+    /// for pixels; saving to a path always uses the path's extension.
+    pub format: Option<ImageFormat>,
+    /// Lossy quality from 1 to 100 (JPEG, AVIF and lossy WebP)
+    pub quality: u8,
+    /// Flatten transparency onto this color. Formats without alpha (JPEG)
+    /// are flattened onto white when unset.
+    pub background: Option<[u8; 3]>,
+    /// Encode WebP losslessly; PNG and GIF always are, JPEG and AVIF never
+    pub lossless: bool,
+    /// Recompress saved PNGs, see [`crate::optimize`]
+    pub optimize: Option<OptimizeOptions>,
+    /// Names of auto-saved captures, see [`crate::naming`]
+    pub filename: FilenameTemplate,
+    /// Embed capture time, source window and software in saved files, see
+    /// [`crate::metadata`]
+    #[serde(default)]
+    pub embed_metadata: bool,
+    /// Display scale captures are taken at, written as their resolution so
+    /// viewers show them at logical size
+    #[serde(default)]
+    pub scale_factor: Option<f32>,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            quality: 90,
+            background: None,
+            lossless: true,
+            optimize: None,
+            filename: FilenameTemplate::default(),
+            embed_metadata: false,
+            scale_factor: None,
+        }
+    }
+}
+
+impl SaveOptions {
+    /// Whether bytes already in `format` can be stored without re-encoding
+    pub fn keeps(&self, format: ImageFormat) -> bool {
+        self.background.is_none() && self.format.is_none_or(|f| f == format)
+    }
+
+    fn quality(&self) -> u8 {
+        self.quality.clamp(1, 100)
+    }
+}
+
+/// Blend `image` onto an opaque `background`
+pub fn flatten(image: &RgbaImage, background: [u8; 3]) -> RgbaImage {
+    let mut flat = image.clone();
+    for pixel in flat.pixels_mut() {
+        let [r, g, b, a] = pixel.0;
+        let blend =
+            |c: u8, bg: u8| ((c as u32 * a as u32 + bg as u32 * (255 - a as u32)) / 255) as u8;
+        *pixel = Rgba([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+            255,
+        ]);
+    }
+    flat
+}
+
+/// Encode `image` as `format`
+pub fn encode(image: &RgbaImage, format: ImageFormat, options: &SaveOptions) -> Result<Vec<u8>> {
+    let background = options
+        .background
+        .or((!format.has_alpha()).then_some([255, 255, 255]));
+    let image = match background {
+        Some(background) => Cow::Owned(flatten(image, background)),
+        None => Cow::Borrowed(image),
+    };
+    let (width, height) = image.dimensions();
+
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Png => PngEncoder::new(&mut bytes)
+            .write_image(image.as_raw(), width, height, ExtendedColorType::Rgba8)
+            .map_err(Error::from_encoding)?,
+        ImageFormat::Jpeg => {
+            let rgb = image::DynamicImage::ImageRgba8(image.into_owned()).to_rgb8();
+            JpegEncoder::new_with_quality(&mut bytes, options.quality())
+                .write_image(rgb.as_raw(), width, height, ExtendedColorType::Rgb8)
+                .map_err(Error::from_encoding)?
+        }
+        ImageFormat::Webp => {
+            let encoder = webp::Encoder::from_rgba(image.as_raw(), width, height);
+            let memory = if options.lossless {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(options.quality() as f32)
+            };
+            bytes = memory.to_vec();
+        }
+        ImageFormat::Gif => GifEncoder::new(&mut bytes)
+            .encode(image.as_raw(), width, height, ExtendedColorType::Rgba8)
+            .map_err(Error::from_encoding)?,
+        ImageFormat::Avif => {
+            AvifEncoder::new_with_speed_quality(&mut bytes, AVIF_SPEED, options.quality())
+                .write_image(image.as_raw(), width, height, ExtendedColorType::Rgba8)
+                .map_err(Error::from_encoding)?
+        }
+        ImageFormat::Svg => {
+            return Err(Error::UnsupportedFormat(
+                "SVG can't be encoded from pixels".to_string(),
+            ))
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translucent() -> RgbaImage {
+        RgbaImage::from_pixel(8, 8, Rgba([255, 0, 0, 0]))
+    }
+
+    #[test]
+    fn test_jpeg_is_flattened_onto_white() {
+        let bytes = encode(&translucent(), ImageFormat::Jpeg, &SaveOptions::default()).unwrap();
+        let decoded = ImageFormat::Jpeg.decode(&bytes).unwrap();
+
+        let [r, g, b, a] = decoded.get_pixel(4, 4).0;
+        assert!(r > 245 && g > 245 && b > 245, "{:?}", (r, g, b));
+        assert_eq!(a, 255);
+    }
+
+    #[test]
+    fn test_background_and_lossless_options() {
+        let options = SaveOptions {
+            background: Some([0, 0, 255]),
+            ..SaveOptions::default()
+        };
+        let bytes = encode(&translucent(), ImageFormat::Png, &options).unwrap();
+        assert_eq!(
+            ImageFormat::Png.decode(&bytes).unwrap().get_pixel(0, 0).0,
+            [0, 0, 255, 255]
+        );
+
+        let photo = RgbaImage::from_fn(64, 64, |x, y| Rgba([x as u8 * 4, y as u8 * 4, 128, 255]));
+        let lossless = encode(&photo, ImageFormat::Webp, &SaveOptions::default()).unwrap();
+        assert_eq!(ImageFormat::Webp.decode(&lossless).unwrap(), photo);
+
+        let lossy = SaveOptions {
+            lossless: false,
+            quality: 50,
+            ..SaveOptions::default()
+        };
+        let bytes = encode(&photo, ImageFormat::Webp, &lossy).unwrap();
+        assert_eq!(
+            ImageFormat::Webp.decode(&bytes).unwrap().dimensions(),
+            (64, 64)
+        );
+
+        assert!(!encode(&photo, ImageFormat::Avif, &lossy)
+            .unwrap()
+            .is_empty());
+        assert!(encode(&photo, ImageFormat::Svg, &lossy).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+//! Errors of pictd-core.
+//!
+//! Serialized for the frontend as `{ "kind", "message" }`, plus `path` for
+//! the variants that have one.
+
+use image::error::{ImageError, UnsupportedErrorKind};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing `path` failed
+    Io { path: PathBuf, source: io::Error },
+    /// `path` holds JSON that doesn't parse, or couldn't be written as JSON
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    /// Bytes that aren't a valid image of their format
+    Decode(String),
+    /// An image that couldn't be encoded or written out
+    Encode(String),
+    /// A format or extension pictd has no codec for
+    UnsupportedFormat(String),
+    /// `path` resolves outside `root`, the directory it must stay in
+    PathEscape { path: PathBuf, root: PathBuf },
+    /// No clipboard to read or write, or it refused the request
+    ClipboardUnavailable(String),
+    /// A remote sink couldn't be reached or refused an upload
+    Upload(String),
+    /// A post-save hook failed, timed out or couldn't be started
+    Hook(String),
+    /// A filename template, digest or other value given by the user that
+    /// doesn't parse
+    InvalidInput(String),
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Adapter for `map_err` on IO results about `path`
+    pub fn at(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| Error::io(path, source)
+    }
+
+    /// Adapter for `map_err` on JSON results about `path`
+    pub fn json(path: &Path) -> impl FnOnce(serde_json::Error) -> Self + '_ {
+        move |source| Error::Json {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+
+    pub fn invalid(message: impl fmt::Display) -> Self {
+        Error::InvalidInput(message.to_string())
+    }
+
+    pub fn decode(error: impl fmt::Display) -> Self {
+        Error::Decode(error.to_string())
+    }
+
+    pub fn encode(error: impl fmt::Display) -> Self {
+        Error::Encode(error.to_string())
+    }
+
+    pub fn clipboard(error: impl fmt::Display) -> Self {
+        Error::ClipboardUnavailable(error.to_string())
+    }
+
+    pub fn upload(error: impl fmt::Display) -> Self {
+        Error::Upload(error.to_string())
+    }
+
+    pub fn hook(error: impl fmt::Display) -> Self {
+        Error::Hook(error.to_string())
+    }
+
+    /// An `image` crate decoding error, for a file at `path` if given
+    pub(crate) fn from_decoding(error: ImageError, path: Option<&Path>) -> Self {
+        if let Some(format) = unsupported_format(&error) {
+            return Error::UnsupportedFormat(format);
+        }
+        match (error, path) {
+            (ImageError::IoError(source), Some(path)) => Error::io(path, source),
+            (error, Some(path)) => Error::Decode(format!("{}: {}", path.display(), error)),
+            (error, None) => Error::decode(error),
+        }
+    }
+
+    /// An `image` crate encoding error
+    pub(crate) fn from_encoding(error: ImageError) -> Self {
+        match unsupported_format(&error) {
+            Some(format) => Error::UnsupportedFormat(format),
+            None => Error::encode(error),
+        }
+    }
+
+    /// Short name of the variant, as serialized
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io { .. } => "io",
+            Error::Json { .. } => "json",
+            Error::Decode(_) => "decode",
+            Error::Encode(_) => "encode",
+            Error::UnsupportedFormat(_) => "unsupported_format",
+            Error::PathEscape { .. } => "path_escape",
+            Error::ClipboardUnavailable(_) => "clipboard_unavailable",
+            Error::Upload(_) => "upload",
+            Error::Hook(_) => "hook",
+            Error::InvalidInput(_) => "invalid_input",
+        }
+    }
+
+    /// The file or directory the error is about, if any
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::Io { path, .. } | Error::Json { path, .. } | Error::PathEscape { path, .. } => {
+                Some(path)
+            }
+            _ => None,
+        }
+    }
+
+    /// For IO errors, what went wrong, such as not found or permission denied
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Error::Io { source, .. } => Some(source.kind()),
+            _ => None,
+        }
+    }
+}
+
+/// The format named by an `image` error about a format it lacks
+fn unsupported_format(error: &ImageError) -> Option<String> {
+    match error {
+        ImageError::Unsupported(e) => match e.kind() {
+            UnsupportedErrorKind::Format(hint) => Some(hint.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Json { path, source } => {
+                write!(f, "{}: invalid JSON: {}", path.display(), source)
+            }
+            Error::Decode(message) => write!(f, "Failed to decode image: {}", message),
+            Error::Encode(message) => write!(f, "Failed to encode image: {}", message),
+            Error::UnsupportedFormat(format) => write!(f, "Unsupported image format: {}", format),
+            Error::PathEscape { path, root } => {
+                write!(f, "{} is outside {}", path.display(), root.display())
+            }
+            Error::ClipboardUnavailable(message) => {
+                write!(f, "Clipboard unavailable: {}", message)
+            }
+            Error::Upload(message) => write!(f, "Upload failed: {}", message),
+            Error::Hook(message) => write!(f, "Hook failed: {}", message),
+            Error::InvalidInput(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Error {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = self.path();
+        let mut state = serializer.serialize_struct("Error", 2 + path.is_some() as usize)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        if let Some(path) = path {
+            state.serialize_field("path", &path.to_string_lossy())?;
+        }
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serializes_kind_message_and_path() {
+        let missing = Error::io("notes/shot.png", io::ErrorKind::NotFound.into());
+        assert_eq!(missing.io_kind(), Some(io::ErrorKind::NotFound));
+        assert_eq!(
+            serde_json::to_value(&missing).unwrap(),
+            serde_json::json!({
+                "kind": "io",
+                "message": format!("notes/shot.png: {}", io::Error::from(io::ErrorKind::NotFound)),
+                "path": "notes/shot.png",
+            })
+        );
+
+        let corrupt = serde_json::from_str::<Vec<u8>>("[1,")
+            .map_err(Error::json(Path::new("state.json")))
+            .unwrap_err();
+        assert_eq!(corrupt.kind(), "json");
+        assert_eq!(corrupt.path(), Some(Path::new("state.json")));
+        assert_eq!(corrupt.io_kind(), None);
+
+        let unsupported = Error::UnsupportedFormat("tiff".to_string());
+        assert_eq!(
+            serde_json::to_value(&unsupported).unwrap(),
+            serde_json::json!({
+                "kind": "unsupported_format",
+                "message": "Unsupported image format: tiff",
+            })
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Image files copied in a file manager.
+//!
+//! Nautilus, Dolphin and friends put a `text/uri-list` on the clipboard
+//! rather than pixels, so these are imported from disk instead.
+
+use chrono::Local;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::atomic::write_atomic;
+use crate::error::{Error, Result};
+use crate::format::{self, ImageFormat};
+use crate::source::{ClipboardImage, EncodedImage, ReadOptions, ReadOutcome};
+use crate::storage::{self, ImageInfo};
+
+/// Local paths in a `text/uri-list` (RFC 2483) or GNOME
+/// `x-special/gnome-copied-files` payload. Other URIs are left out.
+pub fn parse_uri_list(text: &str) -> Vec<PathBuf> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let rest = line.strip_prefix("file://")?;
+            let path = rest.strip_prefix("localhost").unwrap_or(rest);
+            if !path.starts_with('/') {
+                return None;
+            }
+            percent_decode(path).map(PathBuf::from)
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// A `text/uri-list` naming `paths`, which should be absolute
+pub fn to_uri_list(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| format!("file://{}\r\n", percent_encode(&path.to_string_lossy())))
+        .collect()
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn is_image_file(path: &Path) -> bool {
+    path.is_file()
+        && (ImageFormat::from_path(path).is_some() || image::ImageFormat::from_path(path).is_ok())
+}
+
+/// The existing image files among `paths`, in order
+pub fn image_files(paths: &[PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .filter(|path| is_image_file(path))
+        .cloned()
+        .collect()
+}
+
+/// Read an image file the way a clipboard read would, keeping its bytes
+/// when it is in one of `options.formats`
+pub fn read_image_file(path: &Path, options: &ReadOptions) -> Result<ReadOutcome> {
+    Ok(match decode_image_file(path, options)? {
+        ReadOutcome::Image(image) => ReadOutcome::Image(ClipboardImage {
+            from_file: true,
+            ..image
+        }),
+        too_large => too_large,
+    })
+}
+
+fn decode_image_file(path: &Path, options: &ReadOptions) -> Result<ReadOutcome> {
+    if let Some(format) = ImageFormat::from_path(path) {
+        let bytes = fs::read(path).map_err(Error::at(path))?;
+        return options.decode(EncodedImage { format, bytes });
+    }
+
+    // Anything else `image` reads, such as BMP, is checked against its header too
+    let (width, height) =
+        image::image_dimensions(path).map_err(|e| Error::from_decoding(e, Some(path)))?;
+    if options.too_large(width, height) {
+        return Ok(ReadOutcome::TooLarge { width, height });
+    }
+
+    Ok(options.admit(ClipboardImage::decoded(format::open_image(path)?)))
+}
+
+/// Put an image file at `target_path`, copied as-is when both have the
+/// same format and re-encoded for the target's extension otherwise
+pub fn import_file_to_path(path: &Path, target_path: &Path) -> Result<ImageInfo> {
+    let image = format::open_image(path)?;
+
+    let Some(format) = ImageFormat::from_path(target_path)
+        .filter(|format| Some(*format) == ImageFormat::from_path(path))
+    else {
+        return storage::save_image_to_path(&image, target_path);
+    };
+
+    if let Some(parent) = target_path.parent() {
+        fs::create_dir_all(parent).map_err(Error::at(parent))?;
+    }
+    let bytes = fs::read(path).map_err(Error::at(path))?;
+    write_atomic(target_path, &bytes)?;
+
+    Ok(ImageInfo {
+        timestamp: Local::now().format("%Y-%m-%d_%H-%M-%S").to_string(),
+        ..storage::describe_image(&image, target_path, format)?
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_parse_uri_list() {
+        let list = "# copied from Dolphin\r\n\
+                    file:///home/me/Pictures/My%20Shot.png\r\n\
+                    file://localhost/tmp/a.jpg\r\n\
+                    https://example.com/b.png\r\n";
+        assert_eq!(
+            parse_uri_list(list),
+            [
+                PathBuf::from("/home/me/Pictures/My Shot.png"),
+                PathBuf::from("/tmp/a.jpg")
+            ]
+        );
+
+        // Nautilus prefixes the operation
+        assert_eq!(
+            parse_uri_list("copy\nfile:///tmp/c.webp"),
+            [PathBuf::from("/tmp/c.webp")]
+        );
+
+        let paths = vec![PathBuf::from("/home/me/Café shot #2.png")];
+        let list = to_uri_list(&paths);
+        assert_eq!(list, "file:///home/me/Caf%C3%A9%20shot%20%232.png\r\n");
+        assert_eq!(parse_uri_list(&list), paths);
+    }
+
+    #[test]
+    fn test_import_copies_or_converts() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("shot.png");
+        RgbaImage::from_pixel(3, 3, Rgba([1, 2, 3, 255]))
+            .save(&source)
+            .unwrap();
+        fs::write(dir.path().join("notes.txt"), "text").unwrap();
+
+        let listed = image_files(&[
+            source.clone(),
+            dir.path().join("notes.txt"),
+            dir.path().join("missing.png"),
+        ]);
+        assert_eq!(listed, vec![source.clone()]);
+
+        let copied = import_file_to_path(&source, &dir.path().join("doc/one.png")).unwrap();
+        assert_eq!(fs::read(&copied.path).unwrap(), fs::read(&source).unwrap());
+
+        let converted = import_file_to_path(&source, &dir.path().join("doc/two.webp")).unwrap();
+        assert_eq!(converted.format, ImageFormat::Webp);
+        assert_eq!((converted.width, converted.height), (3, 3));
+    }
+}
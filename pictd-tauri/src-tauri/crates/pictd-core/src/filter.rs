@@ -0,0 +1,158 @@
+//! Rules for which clipboard images are worth saving.
+
+use regex::Regex;
+
+use crate::clipboard::SkipReason;
+use crate::source::ClipboardImage;
+
+/// Size and source limits a capture must meet to be saved.
+///
+/// The default accepts everything.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureFilter {
+    /// Narrower images are skipped, e.g. icons copied from a menu
+    pub min_width: u32,
+    pub min_height: u32,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Larger images are skipped. Encoded images are checked against their
+    /// header before decoding; arboard only hands over decoded pixels.
+    pub max_pixels: Option<u64>,
+    /// Images whose original encoding is smaller are skipped. Images only
+    /// available as pixels are not checked.
+    pub min_bytes: usize,
+    /// Only save while the active window's class matches (or doesn't)
+    pub window_class: Option<WindowClassRule>,
+}
+
+/// Rule on the `WM_CLASS` class name (Wayland app id) of the active window
+#[derive(Clone, Debug)]
+pub enum WindowClassRule {
+    /// Save only when the class matches; nothing is saved if it is unknown
+    Only(Regex),
+    /// Save unless the class matches
+    Except(Regex),
+}
+
+impl CaptureFilter {
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), SkipReason> {
+        if width < self.min_width || height < self.min_height {
+            return Err(SkipReason::TooSmall { width, height });
+        }
+
+        let too_large = self.max_width.is_some_and(|max| width > max)
+            || self.max_height.is_some_and(|max| height > max)
+            || self
+                .max_pixels
+                .is_some_and(|max| u64::from(width) * u64::from(height) > max);
+        if too_large {
+            return Err(SkipReason::TooLarge { width, height });
+        }
+
+        Ok(())
+    }
+
+    /// Check a decoded capture against the size limits
+    pub fn check(&self, image: &ClipboardImage) -> Result<(), SkipReason> {
+        self.check_dimensions(image.image.width(), image.image.height())?;
+
+        if let Some(encoded) = &image.encoded {
+            if encoded.bytes.len() < self.min_bytes {
+                return Err(SkipReason::TooFewBytes {
+                    bytes: encoded.bytes.len(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the window rule against the class of the active window
+    pub fn check_window(&self, class: Option<&str>) -> Result<(), SkipReason> {
+        let allowed = match &self.window_class {
+            None => true,
+            Some(WindowClassRule::Only(pattern)) => class.is_some_and(|c| pattern.is_match(c)),
+            Some(WindowClassRule::Except(pattern)) => !class.is_some_and(|c| pattern.is_match(c)),
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(SkipReason::WindowClass {
+                class: class.map(str::to_string),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::ImageFormat;
+    use crate::source::EncodedImage;
+    use image::RgbaImage;
+
+    #[test]
+    fn test_size_limits() {
+        let filter = CaptureFilter {
+            min_width: 32,
+            min_height: 32,
+            max_pixels: Some(1000 * 1000),
+            min_bytes: 100,
+            ..CaptureFilter::default()
+        };
+
+        assert_eq!(
+            filter.check_dimensions(16, 16),
+            Err(SkipReason::TooSmall {
+                width: 16,
+                height: 16
+            })
+        );
+        assert_eq!(
+            filter.check_dimensions(20000, 20000),
+            Err(SkipReason::TooLarge {
+                width: 20000,
+                height: 20000
+            })
+        );
+        assert_eq!(filter.check_dimensions(800, 600), Ok(()));
+
+        let tiny = ClipboardImage {
+            image: RgbaImage::new(64, 64),
+            encoded: Some(EncodedImage {
+                format: ImageFormat::Png,
+                bytes: vec![0; 10],
+            }),
+            from_file: false,
+        };
+        assert_eq!(
+            filter.check(&tiny),
+            Err(SkipReason::TooFewBytes { bytes: 10 })
+        );
+        assert_eq!(filter.check(&ClipboardImage::decoded(tiny.image)), Ok(()));
+    }
+
+    #[test]
+    fn test_window_class_rules() {
+        let only = CaptureFilter {
+            window_class: Some(WindowClassRule::Only(Regex::new("(?i)^gimp").unwrap())),
+            ..CaptureFilter::default()
+        };
+        assert_eq!(only.check_window(Some("Gimp-2.10")), Ok(()));
+        assert!(only.check_window(Some("firefox")).is_err());
+        assert!(only.check_window(None).is_err());
+
+        let except = CaptureFilter {
+            window_class: Some(WindowClassRule::Except(Regex::new("KeePassXC").unwrap())),
+            ..CaptureFilter::default()
+        };
+        assert_eq!(
+            except.check_window(Some("KeePassXC")),
+            Err(SkipReason::WindowClass {
+                class: Some("KeePassXC".to_string())
+            })
+        );
+        assert_eq!(except.check_window(None), Ok(()));
+    }
+}
@@ -0,0 +1,220 @@
+//! Image formats pictd can store exactly as the clipboard offered them.
+
+use image::{ImageReader, RgbaImage};
+use resvg::{tiny_skia, usvg};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+/// SVGs are rasterized no larger than this on their longest side
+const MAX_SVG_RASTER: u32 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Webp,
+    Gif,
+    Svg,
+    /// Written when chosen as the output format; not read from the clipboard
+    Avif,
+}
+
+impl ImageFormat {
+    /// Formats kept exactly as the clipboard offers them
+    pub const ALL: [ImageFormat; 5] = [
+        ImageFormat::Png,
+        ImageFormat::Jpeg,
+        ImageFormat::Webp,
+        ImageFormat::Gif,
+        ImageFormat::Svg,
+    ];
+
+    /// Whether captures can be encoded in this format from their pixels
+    pub fn encodes_pixels(self) -> bool {
+        self != Self::Svg
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Webp => "image/webp",
+            Self::Gif => "image/gif",
+            Self::Svg => "image/svg+xml",
+            Self::Avif => "image/avif",
+        }
+    }
+
+    /// Extension used for saved files, without the dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+            Self::Gif => "gif",
+            Self::Svg => "svg",
+            Self::Avif => "avif",
+        }
+    }
+
+    /// Parse a clipboard target such as `image/jpeg` or `image/svg+xml;charset=utf-8`
+    pub fn from_mime_type(mime: &str) -> Option<Self> {
+        let essence = mime.split(';').next().unwrap_or_default().trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "image/png" => Some(Self::Png),
+            "image/jpeg" | "image/jpg" => Some(Self::Jpeg),
+            "image/webp" => Some(Self::Webp),
+            "image/gif" => Some(Self::Gif),
+            "image/svg+xml" | "image/svg" => Some(Self::Svg),
+            "image/avif" => Some(Self::Avif),
+            _ => None,
+        }
+    }
+
+    /// Format of a file, judged by its extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(Self::Png),
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "webp" => Some(Self::Webp),
+            "gif" => Some(Self::Gif),
+            "svg" => Some(Self::Svg),
+            "avif" => Some(Self::Avif),
+            _ => None,
+        }
+    }
+
+    fn raster_format(self) -> Option<image::ImageFormat> {
+        match self {
+            Self::Png => Some(image::ImageFormat::Png),
+            Self::Jpeg => Some(image::ImageFormat::Jpeg),
+            Self::Webp => Some(image::ImageFormat::WebP),
+            Self::Gif => Some(image::ImageFormat::Gif),
+            Self::Avif => Some(image::ImageFormat::Avif),
+            Self::Svg => None,
+        }
+    }
+
+    /// Whether encoded images keep an alpha channel
+    pub fn has_alpha(self) -> bool {
+        self != Self::Jpeg
+    }
+
+    /// Decode image bytes in this format; SVGs are rasterized at their
+    /// natural size. AVIF can be written but not decoded.
+    pub fn decode(self, bytes: &[u8]) -> Result<RgbaImage> {
+        let Some(format) = self.raster_format() else {
+            return rasterize_svg(bytes);
+        };
+
+        image::load_from_memory_with_format(bytes, format)
+            .map(|img| img.to_rgba8())
+            .map_err(|e| Error::from_decoding(e, None))
+    }
+
+    /// Size `decode` would produce, read from the header without decoding the pixels
+    pub fn dimensions(self, bytes: &[u8]) -> Result<(u32, u32)> {
+        let Some(format) = self.raster_format() else {
+            let (width, height, _) = svg_raster_size(&parse_svg(bytes)?);
+            return Ok((width, height));
+        };
+
+        ImageReader::with_format(Cursor::new(bytes), format)
+            .into_dimensions()
+            .map_err(|e| Error::from_decoding(e, None))
+    }
+}
+
+/// Decode an image file, including SVGs
+pub fn open_image(path: &Path) -> Result<RgbaImage> {
+    match ImageFormat::from_path(path) {
+        Some(ImageFormat::Svg) => {
+            let bytes = fs::read(path).map_err(Error::at(path))?;
+            rasterize_svg(&bytes)
+        }
+        _ => image::open(path)
+            .map(|img| img.to_rgba8())
+            .map_err(|e| Error::from_decoding(e, Some(path))),
+    }
+}
+
+fn parse_svg(bytes: &[u8]) -> Result<usvg::Tree> {
+    usvg::Tree::from_data(bytes, &usvg::Options::default())
+        .map_err(|e| Error::Decode(format!("Invalid SVG: {}", e)))
+}
+
+/// Raster width, height and scale of an SVG
+fn svg_raster_size(tree: &usvg::Tree) -> (u32, u32, f32) {
+    let size = tree.size();
+    let scale = (MAX_SVG_RASTER as f32 / size.width().max(size.height())).min(1.0);
+    let width = ((size.width() * scale).ceil() as u32).max(1);
+    let height = ((size.height() * scale).ceil() as u32).max(1);
+    (width, height, scale)
+}
+
+fn rasterize_svg(bytes: &[u8]) -> Result<RgbaImage> {
+    let tree = parse_svg(bytes)?;
+    let (width, height, scale) = svg_raster_size(&tree);
+
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| Error::Decode(format!("Cannot rasterize a {}x{} SVG", width, height)))?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia keeps premultiplied alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|p| {
+            let c = p.demultiply();
+            [c.red(), c.green(), c.blue(), c.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, pixels)
+        .ok_or_else(|| Error::Decode("Invalid SVG raster".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_types_round_trip() {
+        for format in ImageFormat::ALL {
+            assert_eq!(
+                ImageFormat::from_mime_type(format.mime_type()),
+                Some(format)
+            );
+        }
+        assert_eq!(
+            ImageFormat::from_mime_type("image/svg+xml; charset=utf-8"),
+            Some(ImageFormat::Svg)
+        );
+        assert_eq!(ImageFormat::from_mime_type("image/bmp"), None);
+        assert_eq!(ImageFormat::from_mime_type("text/plain"), None);
+    }
+
+    #[test]
+    fn test_svg_is_rasterized() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20">
+            <rect width="20" height="20" fill="#ff0000"/>
+        </svg>"##;
+
+        let img = ImageFormat::Svg.decode(svg).unwrap();
+
+        assert_eq!(img.dimensions(), (40, 20));
+        assert_eq!(ImageFormat::Svg.dimensions(svg).unwrap(), (40, 20));
+        assert_eq!(img.get_pixel(5, 5).0, [255, 0, 0, 255]);
+        assert_eq!(img.get_pixel(35, 5).0[3], 0);
+    }
+}
@@ -0,0 +1,522 @@
+//! Commands and webhooks run after each save.
+//!
+//! A command hook runs through the shell with the capture described in
+//! `PICTD_*` environment variables and as JSON on stdin. A webhook receives
+//! the same JSON in a `POST`. Hooks run on a few background threads, so a
+//! slow one never holds up capture; failures are reported, not returned.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::atomic::write_atomic;
+use crate::error::{Error, Result};
+use crate::markdown::ImagePlaceholder;
+use crate::storage::ImageInfo;
+
+/// Hook runs waiting for a free thread beyond this are skipped
+const QUEUE_LIMIT: usize = 64;
+
+/// Something to run after each save
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hook {
+    #[serde(flatten)]
+    pub action: HookAction,
+    /// A command is killed, and a request abandoned, after this many seconds
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HookAction {
+    /// Run with `sh -c`, or `cmd /C` on Windows
+    Command { command: String },
+    /// `POST` the JSON to `url`
+    Webhook { url: String },
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+impl Hook {
+    pub fn command(command: impl Into<String>) -> Self {
+        Self {
+            action: HookAction::Command {
+                command: command.into(),
+            },
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    pub fn webhook(url: impl Into<String>) -> Self {
+        Self {
+            action: HookAction::Webhook { url: url.into() },
+            timeout_secs: default_timeout_secs(),
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Run the hook for one save, waiting for it to finish
+    pub fn run(&self, input: &HookInput) -> Result<()> {
+        let json = serde_json::to_vec(input).map_err(Error::hook)?;
+        match &self.action {
+            HookAction::Command { command } => {
+                run_command(command, &input.environment(), json, self.timeout())
+            }
+            HookAction::Webhook { url } => {
+                let agent = ureq::AgentBuilder::new().timeout(self.timeout()).build();
+                let response = agent
+                    .post(url)
+                    .set("Content-Type", "application/json")
+                    .send_bytes(&json);
+                match response {
+                    Ok(_) => Ok(()),
+                    Err(ureq::Error::Status(status, _)) => {
+                        Err(Error::hook(format!("{}: HTTP {}", url, status)))
+                    }
+                    Err(e) => Err(Error::hook(e)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.action {
+            HookAction::Command { command } => write!(f, "{}", command),
+            HookAction::Webhook { url } => write!(f, "POST {}", url),
+        }
+    }
+}
+
+/// The hooks to run and how many may run at once
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HookConfig {
+    pub hooks: Vec<Hook>,
+    /// Hook runs at the same time; the others wait their turn
+    pub max_concurrent: usize,
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            hooks: Vec::new(),
+            max_concurrent: 4,
+        }
+    }
+}
+
+impl HookConfig {
+    /// Load the configuration at `path`; no hooks if it is missing
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(Error::json(path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// Write the configuration to `path`, as read by [`HookConfig::load`]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::at(parent))?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(Error::json(path))?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+/// `pictd/hooks.json` under the platform's config directory
+pub fn default_hooks_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("pictd")
+        .join("hooks.json")
+}
+
+/// What hooks are told about a save: the [`ImageInfo`] without its inline
+/// thumbnail, and the placeholder it filled for pictd-md saves
+#[derive(Clone, Debug, Serialize)]
+pub struct HookInput {
+    #[serde(flatten)]
+    pub info: ImageInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<PlaceholderInput>,
+}
+
+/// A filled markdown placeholder
+#[derive(Clone, Debug, Serialize)]
+pub struct PlaceholderInput {
+    /// The markdown file
+    pub markdown: String,
+    pub alt_text: String,
+    /// The image path as written in the markdown
+    pub relative_path: String,
+    pub line: usize,
+}
+
+impl HookInput {
+    pub fn new(info: &ImageInfo) -> Self {
+        Self {
+            info: ImageInfo {
+                thumbnail: None,
+                ..info.clone()
+            },
+            placeholder: None,
+        }
+    }
+
+    /// A save filling `placeholder` of the markdown file `markdown`
+    pub fn for_placeholder(
+        info: &ImageInfo,
+        markdown: &str,
+        placeholder: &ImagePlaceholder,
+    ) -> Self {
+        Self {
+            placeholder: Some(PlaceholderInput {
+                markdown: markdown.to_string(),
+                alt_text: placeholder.alt_text.clone(),
+                relative_path: placeholder.relative_path.clone(),
+                line: placeholder.line_number,
+            }),
+            ..Self::new(info)
+        }
+    }
+
+    /// The `PICTD_*` variables command hooks get; unknown fields are left out
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        let info = &self.info;
+        let mut env = vec![
+            ("PICTD_PATH", info.path.clone()),
+            ("PICTD_FILENAME", info.filename.clone()),
+            ("PICTD_WIDTH", info.width.to_string()),
+            ("PICTD_HEIGHT", info.height.to_string()),
+            ("PICTD_FORMAT", info.format.extension().to_string()),
+            ("PICTD_DIGEST", info.digest.to_hex()),
+            ("PICTD_TIMESTAMP", info.timestamp.clone()),
+        ];
+        if let Some(source) = &info.source {
+            env.extend(source.app.clone().map(|app| ("PICTD_APP", app)));
+            env.extend(source.title.clone().map(|title| ("PICTD_TITLE", title)));
+        }
+        if let Some(placeholder) = &self.placeholder {
+            env.extend([
+                ("PICTD_MARKDOWN", placeholder.markdown.clone()),
+                ("PICTD_ALT_TEXT", placeholder.alt_text.clone()),
+                ("PICTD_PLACEHOLDER", placeholder.relative_path.clone()),
+                ("PICTD_LINE", placeholder.line.to_string()),
+            ]);
+        }
+        env
+    }
+}
+
+/// A hook run that failed
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HookFailure {
+    /// The command, or `POST` and the URL
+    pub hook: String,
+    /// The saved file
+    pub path: String,
+    pub message: String,
+}
+
+type Report = Arc<dyn Fn(HookFailure) + Send + Sync>;
+
+/// Runs hooks on up to [`HookConfig::max_concurrent`] background threads.
+///
+/// Dropping the runner lets queued hooks finish without waiting for them.
+pub struct HookRunner {
+    hooks: Arc<Vec<Hook>>,
+    jobs: SyncSender<(usize, Arc<HookInput>)>,
+    report: Report,
+}
+
+impl HookRunner {
+    /// Start the threads, which pass failures to `report`; `None` without hooks
+    pub fn start(
+        config: &HookConfig,
+        report: impl Fn(HookFailure) + Send + Sync + 'static,
+    ) -> Option<Self> {
+        if config.hooks.is_empty() {
+            return None;
+        }
+
+        let hooks = Arc::new(config.hooks.clone());
+        let report: Report = Arc::new(report);
+        let (jobs, queued) = mpsc::sync_channel(QUEUE_LIMIT);
+        let queued = Arc::new(Mutex::new(queued));
+        for _ in 0..config.max_concurrent.max(1) {
+            let hooks = hooks.clone();
+            let queued = queued.clone();
+            let report = report.clone();
+            thread::spawn(move || run_jobs(&hooks, &queued, &*report));
+        }
+
+        Some(Self {
+            hooks,
+            jobs,
+            report,
+        })
+    }
+
+    /// Queue every hook for one save
+    pub fn run(&self, input: HookInput) {
+        let input = Arc::new(input);
+        for (index, hook) in self.hooks.iter().enumerate() {
+            if let Err(TrySendError::Full(_)) = self.jobs.try_send((index, input.clone())) {
+                (self.report)(HookFailure {
+                    hook: hook.to_string(),
+                    path: input.info.path.clone(),
+                    message: "Skipped, too many hooks are waiting".to_string(),
+                });
+            }
+        }
+    }
+}
+
+/// Run queued hooks until the runner is dropped and the queue is empty
+fn run_jobs(
+    hooks: &[Hook],
+    queued: &Mutex<Receiver<(usize, Arc<HookInput>)>>,
+    report: &dyn Fn(HookFailure),
+) {
+    loop {
+        let job = queued.lock().unwrap().recv();
+        let Ok((index, input)) = job else {
+            return;
+        };
+
+        let hook = &hooks[index];
+        if let Err(e) = hook.run(&input) {
+            report(HookFailure {
+                hook: hook.to_string(),
+                path: input.info.path.clone(),
+                message: e.to_string(),
+            });
+        }
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Run `command` with `env`, writing `stdin` to it, and fail unless it
+/// exits successfully within `timeout`
+fn run_command(
+    command: &str,
+    env: &[(&str, String)],
+    stdin: Vec<u8>,
+    timeout: Duration,
+) -> Result<()> {
+    let mut child = shell(command)
+        .envs(env.iter().map(|(name, value)| (*name, value)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::hook(format!("Failed to run {}: {}", command, e)))?;
+
+    // On their own threads, so a command that ignores stdin or writes a lot
+    // to stderr can't stall
+    let mut input = child.stdin.take().expect("stdin is piped");
+    thread::spawn(move || input.write_all(&stdin));
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let (errors_tx, errors) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = String::new();
+        let _ = errors_tx.send(stderr.read_to_string(&mut buf).map(|_| buf));
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait().map_err(Error::hook)? {
+            Some(status) => break status,
+            None if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::hook(format!(
+                    "{} timed out after {:?}",
+                    command, timeout
+                )));
+            }
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    if status.success() {
+        return Ok(());
+    }
+    // A background process the command started may keep stderr open
+    let remaining = timeout.saturating_sub(started.elapsed());
+    let errors = errors
+        .recv_timeout(remaining)
+        .ok()
+        .and_then(|read| read.ok())
+        .unwrap_or_default();
+    match errors.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(line) => Err(Error::hook(format!(
+            "{} {}: {}",
+            command,
+            status,
+            line.trim()
+        ))),
+        None => Err(Error::hook(format!("{} {}", command, status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::markdown::FileStatus;
+    use crate::storage;
+    use image::{Rgba, RgbaImage};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    fn saved(dir: &std::path::Path) -> ImageInfo {
+        let image = RgbaImage::from_pixel(3, 2, Rgba([9, 8, 7, 255]));
+        storage::save_image(&image, &dir.to_string_lossy()).unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_gets_environment_and_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = saved(dir.path());
+        let placeholder = ImagePlaceholder {
+            alt_text: "Settings".to_string(),
+            relative_path: "img/settings.png".to_string(),
+            absolute_path: PathBuf::from(&info.path),
+            line_number: 12,
+            exists: false,
+            status: FileStatus::Missing,
+        };
+        let input = HookInput::for_placeholder(&info, "guide.md", &placeholder);
+
+        let out = dir.path().join("out");
+        let hook = Hook::command(format!(
+            "cat > '{0}.json'; echo \"$PICTD_WIDTH $PICTD_HEIGHT $PICTD_ALT_TEXT $PICTD_LINE\" > '{0}.txt'",
+            out.display()
+        ));
+        hook.run(&input).unwrap();
+
+        let env = std::fs::read_to_string(out.with_extension("txt")).unwrap();
+        assert_eq!(env, "3 2 Settings 12\n");
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(out.with_extension("json")).unwrap()).unwrap();
+        assert_eq!(json["path"], info.path.as_str());
+        assert_eq!(json["digest"], serde_json::json!(info.digest));
+        assert_eq!(json["placeholder"]["relative_path"], "img/settings.png");
+        assert!(json["thumbnail"].is_null());
+    }
+
+    #[test]
+    fn test_webhook_posts_image_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = saved(dir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/captures", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                &stream,
+                "HTTP/1.1 500 Oops\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+            .unwrap();
+            body
+        });
+
+        let error = Hook::webhook(&url).run(&HookInput::new(&info)).unwrap_err();
+        assert_eq!(error.to_string(), format!("Hook failed: {}: HTTP 500", url));
+
+        let body: serde_json::Value = serde_json::from_slice(&server.join().unwrap()).unwrap();
+        assert_eq!(body["filename"], info.filename.as_str());
+        assert_eq!(body["width"], 3);
+        assert!(body.get("placeholder").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_failures_and_timeouts_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = saved(dir.path());
+        let (tx, failures) = mpsc::channel();
+        let config = HookConfig {
+            hooks: vec![
+                Hook::command("echo 'wiki is down' >&2; exit 3"),
+                Hook {
+                    timeout_secs: 1,
+                    ..Hook::command("sleep 10")
+                },
+                // Exits at once, but leaves stderr open for 10 seconds
+                Hook {
+                    timeout_secs: 1,
+                    ..Hook::command("sleep 10 & exit 4")
+                },
+                Hook::command("true"),
+            ],
+            max_concurrent: 2,
+        };
+        let runner = HookRunner::start(&config, move |failure| {
+            let _ = tx.send(failure);
+        })
+        .unwrap();
+
+        let started = Instant::now();
+        runner.run(HookInput::new(&info));
+        drop(runner);
+
+        let mut messages: Vec<String> = failures
+            .iter()
+            .map(|failure: HookFailure| {
+                assert_eq!(failure.path, info.path);
+                failure.message
+            })
+            .collect();
+        messages.sort();
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].ends_with("exit status: 3: wiki is down"));
+        assert!(messages[1].ends_with("sleep 10 & exit 4 exit status: 4"));
+        assert!(messages[2].contains("sleep 10 timed out after 1s"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
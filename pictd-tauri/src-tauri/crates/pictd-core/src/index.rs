@@ -0,0 +1,446 @@
+//! Persistent metadata index of the capture library.
+//!
+//! An append-only JSON-lines log in the save directory, one record per
+//! line: `{"op":"put",...}` adds or replaces an entry, `{"op":"remove",...}`
+//! drops one. Listing reads the log instead of decoding every image.
+//!
+//! Saves append to the log without reading it. [`CaptureIndex::reconcile`]
+//! compares the index with the directory, picking up files added, changed
+//! or deleted outside pictd, and rewrites the log once it is mostly
+//! superseded records. A record lost to a concurrent rewrite is recovered
+//! by the next reconcile, since its file is still there.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::atomic::write_atomic;
+use crate::digest::ImageDigest;
+use crate::error::{Error, Result};
+use crate::format::{self, ImageFormat};
+use crate::naming::FilenameTemplate;
+use crate::optimize::OptimizeReport;
+use crate::perceptual::PerceptualHash;
+use crate::storage::{self, ImageInfo};
+use crate::thumbnail;
+use crate::window::SourceWindow;
+
+/// Name of the log in the save directory; hidden, so never listed as an image
+pub const INDEX_FILE: &str = ".pictd-index.jsonl";
+
+/// Rewrite the log when it holds this many more records than entries
+const COMPACT_SLACK: usize = 256;
+
+/// What the index knows about a saved image
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Relative to the save directory, with `/` separators
+    pub path: String,
+    pub digest: ImageDigest,
+    pub width: u32,
+    pub height: u32,
+    /// File size
+    pub bytes: u64,
+    /// File modification time in milliseconds since the Unix epoch
+    pub modified: u64,
+    /// Capture time, as in [`ImageInfo::timestamp`]
+    pub timestamp: String,
+    pub format: ImageFormat,
+    pub source: Option<SourceWindow>,
+    pub optimization: Option<OptimizeReport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_url: Option<String>,
+    /// Saved by pictd, rather than found in the save directory
+    #[serde(default)]
+    pub captured: bool,
+    /// For [`find_similar`](crate::find_similar); entries indexed before it
+    /// was recorded are decoded again by the next reconcile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<PerceptualHash>,
+}
+
+impl IndexEntry {
+    /// Subdirectories between the save directory and the file
+    pub fn depth(&self) -> usize {
+        self.path.matches('/').count()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put(Box<IndexEntry>),
+    Remove { path: String },
+}
+
+/// Changes made by a reconcile pass
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReconcileReport {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// The index of one save directory
+pub struct CaptureIndex {
+    save_dir: PathBuf,
+    entries: BTreeMap<String, IndexEntry>,
+    /// Records in the log, counting superseded ones
+    records: usize,
+}
+
+impl CaptureIndex {
+    /// Load the index of `save_dir`; a missing log is an empty index, and
+    /// unreadable lines (a write cut short by a crash) are skipped
+    pub fn open(save_dir: &Path) -> Self {
+        let mut index = Self {
+            save_dir: save_dir.to_path_buf(),
+            entries: BTreeMap::new(),
+            records: 0,
+        };
+
+        if let Ok(file) = fs::File::open(index.log_path()) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str(&line) {
+                    Ok(Record::Put(entry)) => {
+                        index.entries.insert(entry.path.clone(), *entry);
+                    }
+                    Ok(Record::Remove { path }) => {
+                        index.entries.remove(&path);
+                    }
+                    Err(_) => continue,
+                }
+                index.records += 1;
+            }
+        }
+        index
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.save_dir.join(INDEX_FILE)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&IndexEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &IndexEntry> {
+        self.entries.values()
+    }
+
+    /// Add or replace an entry
+    pub fn put(&mut self, entry: IndexEntry) -> Result<()> {
+        self.entries.insert(entry.path.clone(), entry.clone());
+        self.records += 1;
+        append(&self.save_dir, &Record::Put(Box::new(entry)))
+    }
+
+    /// Drop the entry for `path`, relative to the save directory
+    pub fn remove(&mut self, path: &str) -> Result<()> {
+        if self.entries.remove(path).is_none() {
+            return Ok(());
+        }
+        self.records += 1;
+        append(
+            &self.save_dir,
+            &Record::Remove {
+                path: path.to_string(),
+            },
+        )
+    }
+
+    /// Change the entry of the saved image at `image_path`, if indexed
+    pub fn update(
+        &mut self,
+        image_path: &Path,
+        change: impl FnOnce(&mut IndexEntry),
+    ) -> Result<()> {
+        let Some(key) = relative_key(&self.save_dir, image_path) else {
+            return Ok(());
+        };
+        let Some(mut entry) = self.entries.get(&key).cloned() else {
+            return Ok(());
+        };
+        change(&mut entry);
+        self.put(entry)
+    }
+
+    /// Bring the index in line with the files on disk, `depth` levels of
+    /// subdirectories deep; deeper entries are left alone. Files are only
+    /// decoded when new or changed, and `template` dates new ones.
+    pub fn reconcile(
+        &mut self,
+        template: &FilenameTemplate,
+        depth: usize,
+    ) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        let mut seen = HashSet::new();
+        let mut first_error = None;
+
+        for (path, format) in storage::image_files(&self.save_dir, depth) {
+            let Some(key) = relative_key(&self.save_dir, &path) else {
+                continue;
+            };
+            seen.insert(key.clone());
+
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+            let modified = modified_millis(&metadata);
+            let existing = self.entries.get(&key);
+            if existing.is_some_and(|e| {
+                e.bytes == metadata.len() && e.modified == modified && e.phash.is_some()
+            }) {
+                continue;
+            }
+
+            // Undecodable files stay out of the index until they change
+            let Ok(image) = format::open_image(&path) else {
+                continue;
+            };
+            let timestamp = match template.parse_time(Path::new(&key)) {
+                Some(time) => time.format(storage::TIMESTAMP_FORMAT).to_string(),
+                None => existing.map_or_else(
+                    || storage::timestamp_from_stem(&path),
+                    |e| e.timestamp.clone(),
+                ),
+            };
+            if existing.is_some() {
+                report.updated += 1;
+            } else {
+                report.added += 1;
+            }
+
+            let entry = IndexEntry {
+                path: key,
+                digest: ImageDigest::of(&image),
+                width: image.width(),
+                height: image.height(),
+                bytes: metadata.len(),
+                modified,
+                timestamp,
+                format,
+                source: storage::read_source(&path),
+                optimization: None,
+                tags: storage::read_tags(&path),
+                public_url: storage::read_public_url(&path),
+                captured: existing.is_some_and(|e| e.captured),
+                phash: Some(PerceptualHash::of(&image)),
+            };
+            if let Err(e) = self.put(entry) {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        let gone: Vec<String> = self
+            .entries
+            .values()
+            .filter(|entry| entry.depth() <= depth && !seen.contains(&entry.path))
+            .map(|entry| entry.path.clone())
+            .collect();
+        for key in gone {
+            report.removed += 1;
+            if let Err(e) = self.remove(&key) {
+                first_error.get_or_insert(e);
+            }
+        }
+
+        if first_error.is_none() && self.records > self.entries.len() + COMPACT_SLACK {
+            if let Err(e) = self.compact() {
+                first_error = Some(e);
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(report),
+        }
+    }
+
+    /// Rewrite the log with one record per entry
+    pub fn compact(&mut self) -> Result<()> {
+        let mut log = String::new();
+        for entry in self.entries.values() {
+            let record = Record::Put(Box::new(entry.clone()));
+            let line = serde_json::to_string(&record);
+            log.push_str(&line.map_err(Error::json(&self.log_path()))?);
+            log.push('\n');
+        }
+
+        write_atomic(&self.log_path(), log.as_bytes())?;
+        self.records = self.entries.len();
+        Ok(())
+    }
+
+    /// The listing form of `entry`, with a thumbnail URL instead of inline data
+    pub fn info(&self, entry: &IndexEntry) -> ImageInfo {
+        let path = self.save_dir.join(&entry.path);
+        ImageInfo {
+            filename: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+            path: path.to_string_lossy().to_string(),
+            timestamp: entry.timestamp.clone(),
+            width: entry.width,
+            height: entry.height,
+            format: entry.format,
+            digest: entry.digest,
+            thumbnail: None,
+            thumbnail_url: Some(thumbnail::thumbnail_url(&thumbnail::thumbnail_id(
+                &entry.digest,
+                entry.modified,
+            ))),
+            source: entry.source.clone(),
+            optimization: entry.optimization,
+            tags: entry.tags.clone(),
+            public_url: entry.public_url.clone(),
+        }
+    }
+}
+
+/// Record a just-saved image without loading the index
+pub fn record_saved(save_dir: &Path, info: &ImageInfo, phash: PerceptualHash) -> Result<()> {
+    let path = Path::new(&info.path);
+    let key = relative_key(save_dir, path).ok_or_else(|| Error::PathEscape {
+        path: path.to_path_buf(),
+        root: save_dir.to_path_buf(),
+    })?;
+    let metadata = fs::metadata(path).map_err(Error::at(path))?;
+
+    let entry = IndexEntry {
+        path: key,
+        digest: info.digest,
+        width: info.width,
+        height: info.height,
+        bytes: metadata.len(),
+        modified: modified_millis(&metadata),
+        timestamp: info.timestamp.clone(),
+        format: info.format,
+        source: info.source.clone(),
+        optimization: info.optimization,
+        tags: info.tags.clone(),
+        public_url: info.public_url.clone(),
+        captured: true,
+        phash: Some(phash),
+    };
+    append(save_dir, &Record::Put(Box::new(entry)))
+}
+
+fn append(save_dir: &Path, record: &Record) -> Result<()> {
+    let log_path = save_dir.join(INDEX_FILE);
+    let mut line = serde_json::to_string(record).map_err(Error::json(&log_path))?;
+    line.push('\n');
+
+    // One write per record, so appends from several processes don't interleave
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .map_err(Error::at(&log_path))?;
+    file.write_all(line.as_bytes())
+        .map_err(Error::at(&log_path))
+}
+
+fn relative_key(save_dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(save_dir).ok()?;
+    Some(relative.to_str()?.replace('\\', "/"))
+}
+
+pub(crate) fn modified_millis(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::SaveOptions;
+    use crate::source::ClipboardImage;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_saves_are_indexed() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let source = SourceWindow {
+            app: Some("firefox".to_string()),
+            title: None,
+        };
+
+        let capture = ClipboardImage::decoded(RgbaImage::new(5, 3));
+        let info =
+            storage::save_capture(&capture, Some(&source), &save_dir, &SaveOptions::default())
+                .unwrap();
+
+        let index = CaptureIndex::open(dir.path());
+        assert_eq!(index.len(), 1);
+        let entry = index.entries().next().unwrap();
+        assert_eq!(entry.path, info.filename);
+        assert_eq!((entry.width, entry.height), (5, 3));
+        assert_eq!(entry.digest, info.digest);
+        assert_eq!(entry.source.as_ref(), Some(&source));
+        assert_eq!(entry.bytes, fs::metadata(&info.path).unwrap().len());
+    }
+
+    #[test]
+    fn test_reconcile_picks_up_outside_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let template = FilenameTemplate::default();
+
+        let saved = storage::save_image(&RgbaImage::new(2, 2), &save_dir).unwrap();
+        RgbaImage::from_pixel(7, 4, Rgba([1, 2, 3, 255]))
+            .save(dir.path().join("2020-05-06_07-08-09.png"))
+            .unwrap();
+        fs::write(dir.path().join("broken.png"), b"not a png").unwrap();
+
+        let mut index = CaptureIndex::open(dir.path());
+        let report = index.reconcile(&template, 0).unwrap();
+        assert_eq!(
+            report,
+            ReconcileReport {
+                added: 1,
+                updated: 0,
+                removed: 0
+            }
+        );
+        let outside = index.get("2020-05-06_07-08-09.png").unwrap().clone();
+        assert_eq!((outside.width, outside.height), (7, 4));
+        assert_eq!(outside.timestamp, "2020-05-06_07-08-09");
+
+        // Nothing changed, nothing decoded
+        assert_eq!(
+            index.reconcile(&template, 0).unwrap(),
+            ReconcileReport::default()
+        );
+
+        fs::remove_file(&saved.path).unwrap();
+        let report = index.reconcile(&template, 0).unwrap();
+        assert_eq!(report.removed, 1);
+        assert_eq!(CaptureIndex::open(dir.path()).len(), 1);
+
+        // Compaction keeps the entries
+        index.compact().unwrap();
+        let reopened = CaptureIndex::open(dir.path());
+        assert_eq!(reopened.records, 1);
+        assert_eq!(reopened.get("2020-05-06_07-08-09.png"), Some(&outside));
+    }
+}
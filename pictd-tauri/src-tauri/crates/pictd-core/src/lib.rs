@@ -1,7 +1,59 @@
+pub mod atomic;
 pub mod clipboard;
+pub mod command;
+pub mod copy;
+pub mod digest;
+pub mod encode;
+pub mod error;
+pub mod files;
+pub mod filter;
+pub mod format;
+pub mod hook;
+pub mod index;
 pub mod markdown;
+pub mod metadata;
+pub mod naming;
+pub mod optimize;
+pub mod perceptual;
+pub mod query;
+pub mod retention;
+pub mod sink;
+pub mod source;
+pub mod state;
 pub mod storage;
+pub mod thumbnail;
+pub mod upload;
+pub mod watch;
+pub mod window;
 
-pub use clipboard::ClipboardMonitor;
-pub use markdown::{get_unfilled_placeholders, parse_markdown, ImagePlaceholder};
-pub use storage::{get_downloads_dir, list_saved_images, save_image, save_image_to_path, ImageInfo};
+pub use clipboard::{ClipboardMonitor, MonitorConfig, MonitorEvent, SkipReason};
+pub use command::{CommandPreset, CommandSource};
+pub use copy::{copy_image_to_clipboard, ClipboardCopy};
+pub use digest::ImageDigest;
+pub use encode::SaveOptions;
+pub use error::{Error, Result};
+pub use filter::{CaptureFilter, WindowClassRule};
+pub use format::ImageFormat;
+pub use hook::{Hook, HookConfig, HookFailure, HookInput, HookRunner};
+pub use index::{CaptureIndex, IndexEntry, ReconcileReport};
+pub use markdown::{get_unfilled_placeholders, parse_markdown, FileStatus, ImagePlaceholder};
+pub use metadata::{read_metadata, CaptureMetadata};
+pub use naming::FilenameTemplate;
+pub use optimize::{OptimizeOptions, OptimizeReport, QuantizeOptions};
+pub use perceptual::{find_similar, NearDuplicateFilter, PerceptualHash, SimilarImage};
+pub use query::{query_saved_images, ImagePage, ImageQuery, SortOrder};
+pub use retention::{apply_retention, plan_retention, RetentionPolicy, RetentionReport};
+pub use sink::{S3Config, S3Sink, Sink, SinkConfig, SinkTarget, WebDavConfig, WebDavSink};
+pub use source::{
+    system_source, ArboardSource, ClipboardImage, ClipboardSource, DropDirSource, EncodedImage,
+    MemorySource, ReadOptions, ReadOutcome,
+};
+pub use state::MonitorState;
+pub use storage::{
+    get_downloads_dir, list_saved_images, list_saved_images_with, load_thumbnail, save_capture,
+    save_image, save_image_to_path, save_image_to_path_with, save_image_to_path_with_metadata,
+    search_saved_images, set_tags, ImageInfo, ThumbnailFilter, ThumbnailOptions,
+};
+pub use thumbnail::ThumbnailCache;
+pub use upload::{PendingUpload, UploadOutcome, UploadQueue};
+pub use window::SourceWindow;
@@ -2,6 +2,21 @@ use regex::Regex;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+use crate::error::{Error, Result};
+use crate::format::{self, ImageFormat};
+
+/// What is at a placeholder's path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    Missing,
+    /// Zero bytes, as left by a crash or an interrupted copy
+    Empty,
+    /// An image format pictd reads, but not decodable
+    Corrupt,
+    /// A decodable image, or a file pictd can't check
+    Filled,
+}
+
 #[derive(Debug, Clone)]
 pub struct ImagePlaceholder {
     /// Alt text from markdown ![alt](path)
@@ -12,22 +27,20 @@ pub struct ImagePlaceholder {
     pub absolute_path: PathBuf,
     /// Line number in the markdown file (1-indexed)
     pub line_number: usize,
-    /// Whether the image file already exists
+    /// Whether a usable image already fills the placeholder
     pub exists: bool,
+    /// What is at `absolute_path`
+    pub status: FileStatus,
 }
 
 /// Parse a markdown file and extract all image placeholders
-pub fn parse_markdown(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>, String> {
-    let content = fs::read_to_string(markdown_path)
-        .map_err(|e| format!("Failed to read markdown file: {}", e))?;
+pub fn parse_markdown(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>> {
+    let content = fs::read_to_string(markdown_path).map_err(Error::at(markdown_path))?;
 
-    let markdown_dir = markdown_path
-        .parent()
-        .ok_or_else(|| "Invalid markdown path".to_string())?;
+    let markdown_dir = markdown_path.parent().unwrap_or(Path::new("."));
 
     // Pattern: ![alt text](path/to/image.ext)
-    let re = Regex::new(r"!\[([^\]]*)\]\(([^)]+)\)")
-        .map_err(|e| format!("Regex error: {}", e))?;
+    let re = Regex::new(r"!\[([^\]]*)\]\(([^)]+)\)").expect("placeholder pattern is valid");
 
     let mut placeholders = Vec::new();
 
@@ -45,14 +58,15 @@ pub fn parse_markdown(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>, Str
             }
 
             let absolute_path = markdown_dir.join(&relative_path);
-            let exists = absolute_path.exists();
+            let status = file_status(&absolute_path);
 
             placeholders.push(ImagePlaceholder {
                 alt_text,
                 relative_path,
                 absolute_path,
                 line_number: line_idx + 1,
-                exists,
+                exists: status == FileStatus::Filled,
+                status,
             });
         }
     }
@@ -60,8 +74,26 @@ pub fn parse_markdown(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>, Str
     Ok(placeholders)
 }
 
-/// Get only the placeholders that don't have existing images
-pub fn get_unfilled_placeholders(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>, String> {
+/// Check the file at `path`; one left empty or truncated doesn't fill a placeholder
+pub fn file_status(path: &Path) -> FileStatus {
+    let Ok(metadata) = fs::metadata(path) else {
+        return FileStatus::Missing;
+    };
+    if metadata.is_file() && metadata.len() == 0 {
+        return FileStatus::Empty;
+    }
+
+    // Files pictd can't decode (AVIF, or not images at all) are taken as they are
+    let checkable = ImageFormat::from_path(path) == Some(ImageFormat::Svg)
+        || image::ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled());
+    if metadata.is_file() && checkable && format::open_image(path).is_err() {
+        return FileStatus::Corrupt;
+    }
+    FileStatus::Filled
+}
+
+/// Get only the placeholders that don't have usable images
+pub fn get_unfilled_placeholders(markdown_path: &Path) -> Result<Vec<ImagePlaceholder>> {
     let all = parse_markdown(markdown_path)?;
     Ok(all.into_iter().filter(|p| !p.exists).collect())
 }
@@ -95,4 +127,43 @@ Some text here.
         assert_eq!(placeholders[0].alt_text, "Hero Image");
         assert_eq!(placeholders[1].relative_path, "screenshots/step1.png");
     }
+
+    #[test]
+    fn test_empty_and_corrupt_images_are_unfilled() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = dir.path().join("doc.md");
+        fs::write(
+            &markdown,
+            "![](ok.png) ![](empty.png) ![](cut.png) ![](missing.png) ![](diagram.drawio)",
+        )
+        .unwrap();
+
+        let mut png = Vec::new();
+        image::RgbaImage::new(4, 4)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        fs::write(dir.path().join("ok.png"), &png).unwrap();
+        fs::write(dir.path().join("empty.png"), b"").unwrap();
+        fs::write(dir.path().join("cut.png"), &png[..png.len() / 2]).unwrap();
+        fs::write(dir.path().join("diagram.drawio"), "<mxfile/>").unwrap();
+
+        let statuses: Vec<FileStatus> = parse_markdown(&markdown)
+            .unwrap()
+            .iter()
+            .map(|p| p.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                FileStatus::Filled,
+                FileStatus::Empty,
+                FileStatus::Corrupt,
+                FileStatus::Missing,
+                FileStatus::Filled,
+            ]
+        );
+
+        let unfilled = get_unfilled_placeholders(&markdown).unwrap();
+        assert_eq!(unfilled.len(), 3);
+    }
 }
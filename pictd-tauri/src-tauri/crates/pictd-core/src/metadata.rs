@@ -0,0 +1,808 @@
+//! Capture metadata embedded in saved images.
+//!
+//! PNGs get `tEXt`/`iTXt` chunks and a `pHYs` chunk. JPEG and WebP get an
+//! EXIF block (description, software, date and resolution) and an XMP
+//! packet carrying every field; files that already have EXIF or XMP, such
+//! as copied photos, keep theirs. Other formats are saved without.
+//!
+//! Embedding comes after PNG optimization, which strips text chunks.
+
+use chrono::{DateTime, Local, NaiveDateTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::error::{Error, Result};
+use crate::format::ImageFormat;
+use crate::window::SourceWindow;
+
+/// What saved files name as their software
+pub const SOFTWARE: &str = concat!("pictd ", env!("CARGO_PKG_VERSION"));
+
+/// Resolution of a display at scale factor 1
+const BASE_DPI: f32 = 96.0;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG text keywords
+const KEY_CREATED: &str = "Creation Time";
+const KEY_SOFTWARE: &str = "Software";
+const KEY_APP: &str = "Source";
+const KEY_WINDOW: &str = "Window Title";
+const KEY_ALT_TEXT: &str = "Description";
+const KEY_DOCUMENT: &str = "Document";
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const XMP_NAMESPACE: &str = "https://github.com/jdubray/picd/ns/1.0/";
+
+/// EXIF tags written to IFD0
+const TAG_DESCRIPTION: u16 = 0x010e;
+const TAG_X_RESOLUTION: u16 = 0x011a;
+const TAG_Y_RESOLUTION: u16 = 0x011b;
+const TAG_RESOLUTION_UNIT: u16 = 0x0128;
+const TAG_SOFTWARE: u16 = 0x0131;
+const TAG_DATE_TIME: u16 = 0x0132;
+
+const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+/// Context of a capture, as embedded in the file
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CaptureMetadata {
+    /// Capture time, ISO 8601
+    pub captured_at: Option<String>,
+    /// Application the image was copied from
+    pub app: Option<String>,
+    pub window_title: Option<String>,
+    /// Program and version that saved the file
+    pub software: Option<String>,
+    /// Alt text of the markdown placeholder the image fills
+    pub alt_text: Option<String>,
+    /// Markdown file the image was saved for
+    pub document: Option<String>,
+    /// Pixels per inch; 96 times the display scale it was captured at
+    pub dpi: Option<u32>,
+}
+
+impl CaptureMetadata {
+    /// Capture time, source window and software of a capture taken at `time`
+    pub fn capture(time: DateTime<Local>, source: Option<&SourceWindow>) -> Self {
+        Self {
+            captured_at: Some(time.format("%Y-%m-%dT%H:%M:%S%:z").to_string()),
+            app: source.and_then(|s| s.app.clone()),
+            window_title: source.and_then(|s| s.title.clone()),
+            software: Some(SOFTWARE.to_string()),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Text fields with their PNG keywords
+    fn text_fields(&self) -> [(&'static str, Option<&str>); 6] {
+        [
+            (KEY_CREATED, self.captured_at.as_deref()),
+            (KEY_SOFTWARE, self.software.as_deref()),
+            (KEY_APP, self.app.as_deref()),
+            (KEY_WINDOW, self.window_title.as_deref()),
+            (KEY_ALT_TEXT, self.alt_text.as_deref()),
+            (KEY_DOCUMENT, self.document.as_deref()),
+        ]
+    }
+
+    fn text_field_mut(&mut self, keyword: &str) -> Option<&mut Option<String>> {
+        match keyword {
+            KEY_CREATED => Some(&mut self.captured_at),
+            KEY_SOFTWARE => Some(&mut self.software),
+            KEY_APP => Some(&mut self.app),
+            KEY_WINDOW => Some(&mut self.window_title),
+            KEY_ALT_TEXT => Some(&mut self.alt_text),
+            KEY_DOCUMENT => Some(&mut self.document),
+            _ => None,
+        }
+    }
+
+    /// Fill the fields still unset from `other`
+    fn merge(&mut self, other: CaptureMetadata) {
+        let fill = |field: &mut Option<String>, value: Option<String>| {
+            if field.is_none() {
+                *field = value;
+            }
+        };
+        fill(&mut self.captured_at, other.captured_at);
+        fill(&mut self.app, other.app);
+        fill(&mut self.window_title, other.window_title);
+        fill(&mut self.software, other.software);
+        fill(&mut self.alt_text, other.alt_text);
+        fill(&mut self.document, other.document);
+        self.dpi = self.dpi.or(other.dpi);
+    }
+}
+
+/// Resolution that shows a capture taken at `scale_factor` at its logical size
+pub fn dpi_for_scale(scale_factor: f32) -> u32 {
+    (BASE_DPI * scale_factor).round().max(1.0) as u32
+}
+
+/// Add `metadata` to image bytes encoded as `format`. Formats without a
+/// place for it are returned unchanged.
+pub fn embed(bytes: &[u8], format: ImageFormat, metadata: &CaptureMetadata) -> Result<Vec<u8>> {
+    if metadata.is_empty() {
+        return Ok(bytes.to_vec());
+    }
+    match format {
+        ImageFormat::Png => embed_png(bytes, metadata),
+        ImageFormat::Jpeg => embed_jpeg(bytes, metadata),
+        ImageFormat::Webp => embed_webp(bytes, metadata),
+        _ => Ok(bytes.to_vec()),
+    }
+}
+
+/// The metadata embedded in the image file at `path`
+pub fn read_metadata(path: &Path) -> Result<CaptureMetadata> {
+    let bytes = fs::read(path).map_err(Error::at(path))?;
+    Ok(read(&bytes))
+}
+
+/// The metadata embedded in encoded image bytes of any format
+pub fn read(bytes: &[u8]) -> CaptureMetadata {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        read_png(bytes)
+    } else if bytes.starts_with(&[0xff, 0xd8]) {
+        read_jpeg(bytes)
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        read_webp(bytes)
+    } else {
+        CaptureMetadata::default()
+    }
+}
+
+/// A PNG or RIFF chunk, as (type, data)
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+// PNG
+
+/// Chunks of a PNG after its signature
+fn png_chunks(bytes: &[u8]) -> Result<Vec<Chunk<'_>>> {
+    let mut chunks = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= bytes.len() {
+        let len = u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = bytes[pos + 4..pos + 8].try_into().unwrap();
+        let data = bytes
+            .get(pos + 8..pos + 8 + len)
+            .ok_or_else(|| Error::decode("Truncated PNG chunk"))?;
+        chunks.push((kind, data));
+        pos += 12 + len;
+        if &kind == b"IEND" {
+            break;
+        }
+    }
+    Ok(chunks)
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// Keyword of a text chunk
+fn png_keyword(data: &[u8]) -> &[u8] {
+    data.split(|b| *b == 0).next().unwrap_or_default()
+}
+
+fn embed_png(bytes: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>> {
+    if !bytes.starts_with(PNG_SIGNATURE) {
+        return Err(Error::decode("Not a PNG"));
+    }
+    let chunks = png_chunks(bytes)?;
+    if chunks.first().map(|(kind, _)| kind) != Some(b"IHDR") {
+        return Err(Error::decode("PNG doesn't start with IHDR"));
+    }
+
+    let fields: Vec<(&str, &str)> = metadata
+        .text_fields()
+        .into_iter()
+        .filter_map(|(keyword, value)| Some((keyword, value?)))
+        .collect();
+    // Written anew, so saving again doesn't repeat them
+    let replaced = |kind: &[u8; 4], data: &[u8]| match kind {
+        b"pHYs" => metadata.dpi.is_some(),
+        b"tEXt" | b"iTXt" | b"zTXt" => {
+            let keyword = png_keyword(data);
+            fields.iter().any(|(key, _)| key.as_bytes() == keyword)
+        }
+        _ => false,
+    };
+
+    let mut out = Vec::with_capacity(bytes.len() + 512);
+    out.extend_from_slice(PNG_SIGNATURE);
+    for (i, (kind, data)) in chunks.iter().enumerate() {
+        if replaced(kind, data) {
+            continue;
+        }
+        write_png_chunk(&mut out, kind, data);
+        if i > 0 {
+            continue;
+        }
+
+        // pHYs must come before IDAT; text is allowed anywhere
+        if let Some(dpi) = metadata.dpi {
+            let per_metre = (dpi as f64 / 0.0254).round() as u32;
+            let mut phys = Vec::with_capacity(9);
+            phys.extend_from_slice(&per_metre.to_be_bytes());
+            phys.extend_from_slice(&per_metre.to_be_bytes());
+            phys.push(1);
+            write_png_chunk(&mut out, b"pHYs", &phys);
+        }
+        for (keyword, value) in &fields {
+            let mut data = keyword.as_bytes().to_vec();
+            data.push(0);
+            if value
+                .bytes()
+                .all(|b| b == b'\n' || (0x20..0x7f).contains(&b))
+            {
+                data.extend_from_slice(value.as_bytes());
+                write_png_chunk(&mut out, b"tEXt", &data);
+            } else {
+                // Uncompressed, no language tag or translated keyword
+                data.extend_from_slice(&[0, 0, 0, 0]);
+                data.extend_from_slice(value.as_bytes());
+                write_png_chunk(&mut out, b"iTXt", &data);
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn read_png(bytes: &[u8]) -> CaptureMetadata {
+    let mut metadata = CaptureMetadata::default();
+    for (kind, data) in png_chunks(bytes).unwrap_or_default() {
+        let keyword = String::from_utf8_lossy(png_keyword(data)).to_string();
+        let rest = data.get(keyword.len() + 1..).unwrap_or_default();
+        let text = match &kind {
+            // Latin-1
+            b"tEXt" => Some(rest.iter().map(|b| *b as char).collect()),
+            b"iTXt" if rest.first() == Some(&0) => {
+                // Skip the compression fields, language tag and translated keyword
+                let text = rest
+                    .get(2..)
+                    .unwrap_or_default()
+                    .splitn(3, |b| *b == 0)
+                    .nth(2);
+                text.map(|text| String::from_utf8_lossy(text).to_string())
+            }
+            b"pHYs" if data.len() == 9 && data[8] == 1 => {
+                let per_metre = u32::from_be_bytes(data[..4].try_into().unwrap());
+                metadata.dpi = Some((per_metre as f64 * 0.0254).round() as u32);
+                None
+            }
+            _ => None,
+        };
+        if let (Some(text), Some(field)) = (text, metadata.text_field_mut(&keyword)) {
+            *field = Some(text);
+        }
+    }
+    metadata
+}
+
+// JPEG
+
+/// APPn segments before the image data, as (marker, data without length)
+fn jpeg_segments(bytes: &[u8]) -> Vec<(u8, usize, &[u8])> {
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= bytes.len() && bytes[pos] == 0xff {
+        let marker = bytes[pos + 1];
+        if !(0xe0..=0xef).contains(&marker) && marker != 0xfe && marker != 0xdb {
+            break;
+        }
+        let len = u16::from_be_bytes([bytes[pos + 2], bytes[pos + 3]]) as usize;
+        let Some(data) = bytes.get(pos + 4..pos + 2 + len) else {
+            break;
+        };
+        segments.push((marker, pos, data));
+        pos += 2 + len;
+    }
+    segments
+}
+
+fn is_exif(marker: u8, data: &[u8]) -> bool {
+    marker == 0xe1 && data.starts_with(EXIF_HEADER)
+}
+
+fn is_xmp(marker: u8, data: &[u8]) -> bool {
+    marker == 0xe1 && data.starts_with(XMP_HEADER)
+}
+
+fn write_jpeg_segment(out: &mut Vec<u8>, marker: u8, parts: &[&[u8]]) -> Result<()> {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>() + 2;
+    let len =
+        u16::try_from(len).map_err(|_| Error::encode("Metadata too large for a JPEG segment"))?;
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&len.to_be_bytes());
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    Ok(())
+}
+
+fn embed_jpeg(bytes: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>> {
+    if !bytes.starts_with(&[0xff, 0xd8]) {
+        return Err(Error::decode("Not a JPEG"));
+    }
+    let segments = jpeg_segments(bytes);
+    let has_exif = segments.iter().any(|(m, _, d)| is_exif(*m, d));
+    let has_xmp = segments.iter().any(|(m, _, d)| is_xmp(*m, d));
+
+    // After SOI and a JFIF APP0, which must come first
+    let mut out = Vec::with_capacity(bytes.len() + 1024);
+    out.extend_from_slice(&bytes[..2]);
+    let mut rest = 2;
+    if let Some((0xe0, pos, data)) = segments.first() {
+        if data.starts_with(b"JFIF\0") && data.len() >= 12 {
+            let mut jfif = data.to_vec();
+            if let Some(dpi) = metadata.dpi {
+                let density = u16::try_from(dpi).unwrap_or(u16::MAX).to_be_bytes();
+                jfif[7] = 1;
+                jfif[8..10].copy_from_slice(&density);
+                jfif[10..12].copy_from_slice(&density);
+            }
+            write_jpeg_segment(&mut out, 0xe0, &[&jfif])?;
+            rest = pos + 4 + data.len();
+        }
+    }
+
+    if !has_exif {
+        write_jpeg_segment(&mut out, 0xe1, &[EXIF_HEADER, &exif_tiff(metadata)])?;
+    }
+    if !has_xmp {
+        write_jpeg_segment(
+            &mut out,
+            0xe1,
+            &[XMP_HEADER, xmp_packet(metadata).as_bytes()],
+        )?;
+    }
+    out.extend_from_slice(&bytes[rest..]);
+    Ok(out)
+}
+
+fn read_jpeg(bytes: &[u8]) -> CaptureMetadata {
+    let mut exif = CaptureMetadata::default();
+    let mut xmp = CaptureMetadata::default();
+    for (marker, _, data) in jpeg_segments(bytes) {
+        if is_exif(marker, data) {
+            exif = read_exif(&data[EXIF_HEADER.len()..]);
+        } else if is_xmp(marker, data) {
+            xmp = read_xmp(&String::from_utf8_lossy(&data[XMP_HEADER.len()..]));
+        } else if marker == 0xe0 && data.starts_with(b"JFIF\0") && data.len() >= 12 && data[7] == 1
+        {
+            exif.dpi = exif
+                .dpi
+                .or(Some(u16::from_be_bytes([data[8], data[9]]).into()));
+        }
+    }
+    xmp.merge(exif);
+    xmp
+}
+
+// WebP
+
+/// Chunks of a RIFF WebP file
+fn webp_chunks(bytes: &[u8]) -> Vec<Chunk<'_>> {
+    let mut chunks = Vec::new();
+    let mut pos = 12;
+    while pos + 8 <= bytes.len() {
+        let fourcc: [u8; 4] = bytes[pos..pos + 4].try_into().unwrap();
+        let len = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        let Some(data) = bytes.get(pos + 8..pos + 8 + len) else {
+            break;
+        };
+        chunks.push((fourcc, data));
+        pos += 8 + len + (len & 1);
+    }
+    chunks
+}
+
+fn write_webp_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() & 1 == 1 {
+        out.push(0);
+    }
+}
+
+/// Canvas width, height and alpha of a simple (non-VP8X) WebP bitstream
+fn webp_canvas(fourcc: &[u8; 4], data: &[u8]) -> Option<(u32, u32, bool)> {
+    match fourcc {
+        b"VP8L" if data.len() >= 5 && data[0] == 0x2f => {
+            let bits = u32::from_le_bytes(data[1..5].try_into().unwrap());
+            Some((
+                (bits & 0x3fff) + 1,
+                ((bits >> 14) & 0x3fff) + 1,
+                (bits >> 28) & 1 == 1,
+            ))
+        }
+        b"VP8 " if data.len() >= 10 && data[3..6] == [0x9d, 0x01, 0x2a] => {
+            let width = u16::from_le_bytes([data[6], data[7]]) & 0x3fff;
+            let height = u16::from_le_bytes([data[8], data[9]]) & 0x3fff;
+            Some((width.into(), height.into(), false))
+        }
+        _ => None,
+    }
+}
+
+fn embed_webp(bytes: &[u8], metadata: &CaptureMetadata) -> Result<Vec<u8>> {
+    if bytes.len() < 12 || &bytes[..4] != b"RIFF" || &bytes[8..12] != b"WEBP" {
+        return Err(Error::decode("Not a WebP"));
+    }
+    let chunks = webp_chunks(bytes);
+    let has_exif = chunks.iter().any(|(fourcc, _)| fourcc == b"EXIF");
+    let has_xmp = chunks.iter().any(|(fourcc, _)| fourcc == b"XMP ");
+
+    // Metadata needs the extended format's VP8X header
+    let mut vp8x = match chunks.first() {
+        Some((fourcc, data)) if fourcc == b"VP8X" && data.len() >= 10 => data.to_vec(),
+        Some((fourcc, data)) => {
+            let (width, height, alpha) =
+                webp_canvas(fourcc, data).ok_or_else(|| Error::decode("Unknown WebP bitstream"))?;
+            let mut header = vec![if alpha { 0x10 } else { 0 }, 0, 0, 0];
+            header.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            header.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            header
+        }
+        None => return Err(Error::decode("Empty WebP")),
+    };
+    if !has_exif {
+        vp8x[0] |= 0x08;
+    }
+    if !has_xmp {
+        vp8x[0] |= 0x04;
+    }
+
+    let mut out = Vec::with_capacity(bytes.len() + 1024);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    write_webp_chunk(&mut out, b"VP8X", &vp8x);
+    for (fourcc, data) in &chunks {
+        if fourcc != b"VP8X" {
+            write_webp_chunk(&mut out, fourcc, data);
+        }
+    }
+    if !has_exif {
+        write_webp_chunk(&mut out, b"EXIF", &exif_tiff(metadata));
+    }
+    if !has_xmp {
+        write_webp_chunk(&mut out, b"XMP ", xmp_packet(metadata).as_bytes());
+    }
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+fn read_webp(bytes: &[u8]) -> CaptureMetadata {
+    let mut exif = CaptureMetadata::default();
+    let mut xmp = CaptureMetadata::default();
+    for (fourcc, data) in webp_chunks(bytes) {
+        match &fourcc {
+            // Some writers keep the JPEG segment header
+            b"EXIF" => exif = read_exif(data.strip_prefix(EXIF_HEADER).unwrap_or(data)),
+            b"XMP " => xmp = read_xmp(&String::from_utf8_lossy(data)),
+            _ => {}
+        }
+    }
+    xmp.merge(exif);
+    xmp
+}
+
+// EXIF
+
+/// A little-endian TIFF structure with one IFD holding the EXIF fields
+fn exif_tiff(metadata: &CaptureMetadata) -> Vec<u8> {
+    enum Value {
+        Ascii(Vec<u8>),
+        Short(u16),
+        Rational(u32, u32),
+    }
+
+    let ascii = |text: &str| {
+        let mut bytes = text.as_bytes().to_vec();
+        bytes.push(0);
+        Value::Ascii(bytes)
+    };
+    let date = metadata
+        .captured_at
+        .as_deref()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| time.format(EXIF_DATE_FORMAT).to_string());
+
+    // In tag order, as TIFF requires
+    let mut entries = Vec::new();
+    if let Some(alt_text) = &metadata.alt_text {
+        entries.push((TAG_DESCRIPTION, ascii(alt_text)));
+    }
+    if let Some(dpi) = metadata.dpi {
+        entries.push((TAG_X_RESOLUTION, Value::Rational(dpi, 1)));
+        entries.push((TAG_Y_RESOLUTION, Value::Rational(dpi, 1)));
+        entries.push((TAG_RESOLUTION_UNIT, Value::Short(2)));
+    }
+    if let Some(software) = &metadata.software {
+        entries.push((TAG_SOFTWARE, ascii(software)));
+    }
+    if let Some(date) = &date {
+        entries.push((TAG_DATE_TIME, ascii(date)));
+    }
+
+    let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+    let mut values = Vec::new();
+    let values_start = 8 + 2 + entries.len() * 12 + 4;
+    tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, value) in &entries {
+        let (kind, count, data) = match value {
+            Value::Ascii(bytes) => (2u16, bytes.len(), bytes.clone()),
+            Value::Short(n) => (3, 1, n.to_le_bytes().to_vec()),
+            Value::Rational(n, d) => (5, 1, [n.to_le_bytes(), d.to_le_bytes()].concat()),
+        };
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&kind.to_le_bytes());
+        tiff.extend_from_slice(&(count as u32).to_le_bytes());
+        if data.len() <= 4 {
+            let mut inline = data;
+            inline.resize(4, 0);
+            tiff.extend_from_slice(&inline);
+        } else {
+            let offset = (values_start + values.len()) as u32;
+            tiff.extend_from_slice(&offset.to_le_bytes());
+            values.extend_from_slice(&data);
+            // Values start on word boundaries
+            if values.len() & 1 == 1 {
+                values.push(0);
+            }
+        }
+    }
+    tiff.extend_from_slice(&[0; 4]);
+    tiff.extend_from_slice(&values);
+    tiff
+}
+
+/// The fields of IFD0 this module writes, from a TIFF structure of either byte order
+fn read_exif(tiff: &[u8]) -> CaptureMetadata {
+    let mut metadata = CaptureMetadata::default();
+    let little = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return metadata,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(if little {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if little {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    let Some(ifd) = u32_at(4).map(|offset| offset as usize) else {
+        return metadata;
+    };
+    let count = u16_at(ifd).unwrap_or(0) as usize;
+    for i in 0..count {
+        let entry = ifd + 2 + i * 12;
+        let (Some(tag), Some(kind), Some(len)) =
+            (u16_at(entry), u16_at(entry + 2), u32_at(entry + 4))
+        else {
+            break;
+        };
+        let len = len as usize;
+        let ascii = || {
+            let start = if len <= 4 {
+                entry + 8
+            } else {
+                u32_at(entry + 8)? as usize
+            };
+            let bytes = tiff.get(start..start + len)?;
+            let text = String::from_utf8_lossy(bytes);
+            Some(text.trim_end_matches('\0').to_string())
+        };
+
+        match (tag, kind) {
+            (TAG_DESCRIPTION, 2) => metadata.alt_text = ascii(),
+            (TAG_SOFTWARE, 2) => metadata.software = ascii(),
+            (TAG_DATE_TIME, 2) => {
+                metadata.captured_at = ascii()
+                    .and_then(|date| NaiveDateTime::parse_from_str(&date, EXIF_DATE_FORMAT).ok())
+                    .map(|date| date.format("%Y-%m-%dT%H:%M:%S").to_string());
+            }
+            (TAG_X_RESOLUTION, 5) => {
+                let offset = u32_at(entry + 8).unwrap_or(0) as usize;
+                if let (Some(n), Some(d)) = (u32_at(offset), u32_at(offset + 4)) {
+                    metadata.dpi = (d > 0).then(|| (n as f64 / d as f64).round() as u32);
+                }
+            }
+            _ => {}
+        }
+    }
+    metadata
+}
+
+// XMP
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c if c.is_control() => escaped.push_str(&format!("&#{};", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn xml_unescape(text: &str) -> String {
+    static ENTITY: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"&(amp|lt|gt|quot|apos|#x[0-9a-fA-F]+|#[0-9]+);").unwrap());
+    ENTITY
+        .replace_all(text, |caps: &regex::Captures| {
+            let entity = &caps[1];
+            let code = match entity {
+                "amp" => Some('&' as u32),
+                "lt" => Some('<' as u32),
+                "gt" => Some('>' as u32),
+                "quot" => Some('"' as u32),
+                "apos" => Some('\'' as u32),
+                _ => match entity.strip_prefix("#x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity[1..].parse().ok(),
+                },
+            };
+            code.and_then(char::from_u32)
+                .unwrap_or('\u{fffd}')
+                .to_string()
+        })
+        .to_string()
+}
+
+/// An XMP packet with every field as an attribute of one description
+fn xmp_packet(metadata: &CaptureMetadata) -> String {
+    let mut attributes = String::new();
+    let mut attribute = |name: &str, value: Option<&str>| {
+        if let Some(value) = value {
+            attributes.push_str(&format!("\n   {}=\"{}\"", name, xml_escape(value)));
+        }
+    };
+    attribute("xmp:CreateDate", metadata.captured_at.as_deref());
+    attribute("xmp:CreatorTool", metadata.software.as_deref());
+    attribute("pictd:App", metadata.app.as_deref());
+    attribute("pictd:WindowTitle", metadata.window_title.as_deref());
+    attribute("pictd:AltText", metadata.alt_text.as_deref());
+    attribute("pictd:Document", metadata.document.as_deref());
+    let dpi = metadata.dpi.map(|dpi| format!("{}/1", dpi));
+    attribute("tiff:XResolution", dpi.as_deref());
+    attribute("tiff:YResolution", dpi.as_deref());
+    attribute("tiff:ResolutionUnit", dpi.as_ref().map(|_| "2"));
+
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         <rdf:Description rdf:about=\"\"\n   \
+         xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n   \
+         xmlns:tiff=\"http://ns.adobe.com/tiff/1.0/\"\n   \
+         xmlns:pictd=\"{}\"{}/>\n\
+         </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        XMP_NAMESPACE, attributes
+    )
+}
+
+/// The fields of a packet written by [`xmp_packet`]
+fn read_xmp(packet: &str) -> CaptureMetadata {
+    static ATTRIBUTE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"\b(xmp|pictd|tiff):(\w+)="([^"]*)""#).unwrap());
+
+    let mut metadata = CaptureMetadata::default();
+    for caps in ATTRIBUTE.captures_iter(packet) {
+        let value = xml_unescape(&caps[3]);
+        let field = match (&caps[1], &caps[2]) {
+            ("xmp", "CreateDate") => &mut metadata.captured_at,
+            ("xmp", "CreatorTool") => &mut metadata.software,
+            ("pictd", "App") => &mut metadata.app,
+            ("pictd", "WindowTitle") => &mut metadata.window_title,
+            ("pictd", "AltText") => &mut metadata.alt_text,
+            ("pictd", "Document") => &mut metadata.document,
+            ("tiff", "XResolution") => {
+                let (n, d) = value.split_once('/').unwrap_or((&value, "1"));
+                if let (Ok(n), Ok(d)) = (n.parse::<f64>(), d.parse::<f64>()) {
+                    metadata.dpi = (d > 0.0).then(|| (n / d).round() as u32);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        *field = Some(value);
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{self, SaveOptions};
+    use image::{Rgba, RgbaImage};
+
+    fn metadata() -> CaptureMetadata {
+        CaptureMetadata {
+            captured_at: Some("2024-03-09T14:05:07+01:00".to_string()),
+            app: Some("firefox".to_string()),
+            window_title: Some("Ünïcode & <friends> \"quoted\"".to_string()),
+            software: Some(SOFTWARE.to_string()),
+            alt_text: Some("Settings dialog".to_string()),
+            document: Some("docs/guide.md".to_string()),
+            dpi: Some(192),
+        }
+    }
+
+    #[test]
+    fn test_round_trips_through_every_format() {
+        let image = RgbaImage::from_pixel(33, 17, Rgba([10, 200, 30, 128]));
+        let expected = metadata();
+
+        for (format, lossless) in [
+            (ImageFormat::Png, true),
+            (ImageFormat::Jpeg, false),
+            (ImageFormat::Webp, true),
+            (ImageFormat::Webp, false),
+        ] {
+            let options = SaveOptions {
+                lossless,
+                ..SaveOptions::default()
+            };
+            let bytes = encode::encode(&image, format, &options).unwrap();
+            let embedded = embed(&bytes, format, &expected).unwrap();
+
+            let decoded = format.decode(&embedded).unwrap();
+            assert_eq!(decoded.dimensions(), (33, 17), "{:?}", format);
+            assert_eq!(read(&embedded), expected, "{:?}", format);
+
+            // Embedding again replaces rather than repeats
+            let again = embed(&embedded, format, &expected).unwrap();
+            assert_eq!(read(&again), expected, "{:?}", format);
+            if format == ImageFormat::Png {
+                assert_eq!(again.len(), embedded.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_exif_alone_is_readable() {
+        let exif = read_exif(&exif_tiff(&metadata()));
+        assert_eq!(exif.alt_text.as_deref(), Some("Settings dialog"));
+        assert_eq!(exif.software.as_deref(), Some(SOFTWARE));
+        assert_eq!(exif.captured_at.as_deref(), Some("2024-03-09T14:05:07"));
+        assert_eq!(exif.dpi, Some(192));
+        assert_eq!(dpi_for_scale(1.5), 144);
+    }
+}
@@ -0,0 +1,508 @@
+//! Filename templates for auto-saved captures.
+//!
+//! A template such as `{year}/{month}/{date:%Y%m%d}-{time}-{app}-{w}x{h}-{seq:03}.{ext}`
+//! is made of literal text and `{field}` or `{field:spec}` placeholders.
+//! `/` in the literal text separates directories; fields never do, since
+//! anything that could contain a separator is sanitized.
+//!
+//! | Field | Value |
+//! |---|---|
+//! | `date[:fmt]` | capture date, `%Y-%m-%d` by default |
+//! | `time[:fmt]` | capture time, `%H-%M-%S` by default |
+//! | `year`, `month`, `day`, `hour`, `minute`, `second` | zero-padded parts of the capture time |
+//! | `app`, `title` | source window, `unknown` if not recorded |
+//! | `w`, `h` | image dimensions |
+//! | `digest` | first 8 hex digits of the image digest |
+//! | `seq[:0N]` | collision counter, optionally zero-padded to N digits |
+//! | `ext` | extension of the stored format |
+//!
+//! Templates without `{seq}` get a `_N` suffix before the extension on collision.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::atomic::TempFile;
+use crate::error::{Error, Result};
+
+const DEFAULT_TEMPLATE: &str = "{date}_{time}.{ext}";
+const DEFAULT_DATE: &str = "%Y-%m-%d";
+const DEFAULT_TIME: &str = "%H-%M-%S";
+
+/// Longest text taken from a window app or title
+const MAX_FIELD_CHARS: usize = 64;
+
+/// Give up on a name after this many collisions
+const MAX_ATTEMPTS: u32 = 10_000;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field { name: Field, spec: Option<String> },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Date,
+    Time,
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    App,
+    Title,
+    Width,
+    Height,
+    Digest,
+    Seq,
+    Ext,
+}
+
+impl FromStr for Field {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        Ok(match name {
+            "date" => Self::Date,
+            "time" => Self::Time,
+            "year" => Self::Year,
+            "month" => Self::Month,
+            "day" => Self::Day,
+            "hour" => Self::Hour,
+            "minute" => Self::Minute,
+            "second" => Self::Second,
+            "app" => Self::App,
+            "title" => Self::Title,
+            "w" => Self::Width,
+            "h" => Self::Height,
+            "digest" => Self::Digest,
+            "seq" => Self::Seq,
+            "ext" => Self::Ext,
+            _ => {
+                return Err(Error::invalid(format!(
+                    "Unknown filename field {{{}}}",
+                    name
+                )))
+            }
+        })
+    }
+}
+
+/// What a capture's name is made from
+#[derive(Clone, Debug)]
+pub struct NameContext<'a> {
+    pub time: DateTime<Local>,
+    pub app: Option<&'a str>,
+    pub title: Option<&'a str>,
+    pub width: u32,
+    pub height: u32,
+    pub digest: &'a str,
+    pub ext: &'a str,
+}
+
+/// A parsed filename template, see the [module docs](self)
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilenameTemplate {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+impl Default for FilenameTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl fmt::Display for FilenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl FromStr for FilenameTemplate {
+    type Err = Error;
+
+    fn from_str(pattern: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = pattern;
+
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                segments.push(Segment::Literal(rest[..open].to_string()));
+            }
+            let close = rest[open..].find('}').ok_or_else(|| {
+                Error::invalid(format!("Unclosed {{ in filename template {}", pattern))
+            })?;
+            let field = &rest[open + 1..open + close];
+            let (name, spec) = match field.split_once(':') {
+                Some((name, spec)) => (name, Some(spec.to_string())),
+                None => (field, None),
+            };
+            let name = name.parse()?;
+            // chrono panics on bad specs when rendering, so catch them here
+            if let (Field::Date | Field::Time, Some(spec)) = (name, &spec) {
+                if StrftimeItems::new(spec).any(|item| item == Item::Error) {
+                    return Err(Error::invalid(format!("Invalid date format {{{}}}", field)));
+                }
+            }
+            segments.push(Segment::Field { name, spec });
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        // Literal text may only add plain subdirectories
+        let literal_path: String = segments
+            .iter()
+            .map(|s| match s {
+                Segment::Literal(text) => text.as_str(),
+                Segment::Field { .. } => "x",
+            })
+            .collect();
+        let escapes = Path::new(&literal_path)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)));
+        if escapes || literal_path.ends_with('/') || literal_path.contains('\\') {
+            return Err(Error::invalid(format!(
+                "Filename template {} must be a relative file path",
+                pattern
+            )));
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+}
+
+impl Serialize for FilenameTemplate {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.pattern)
+    }
+}
+
+impl<'de> Deserialize<'de> for FilenameTemplate {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Make window text safe as part of a filename
+pub fn sanitize(text: &str) -> String {
+    let cleaned: String = text
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(MAX_FIELD_CHARS)
+        .collect();
+
+    // No hidden files, `..` or trailing dots Windows would drop
+    let trimmed = cleaned.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() {
+        "unknown".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+impl FilenameTemplate {
+    fn has_seq(&self) -> bool {
+        self.segments.iter().any(|s| {
+            matches!(
+                s,
+                Segment::Field {
+                    name: Field::Seq,
+                    ..
+                }
+            )
+        })
+    }
+
+    /// Directory levels the template adds under the save directory
+    pub fn depth(&self) -> usize {
+        self.segments
+            .iter()
+            .map(|s| match s {
+                Segment::Literal(text) => text.matches('/').count(),
+                Segment::Field { .. } => 0,
+            })
+            .sum()
+    }
+
+    /// Relative path for `context`, with collision counter `seq`
+    pub fn render(&self, context: &NameContext, seq: u32) -> PathBuf {
+        let mut path = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => path.push_str(text),
+                Segment::Field { name, spec } => {
+                    path.push_str(&render_field(*name, spec.as_deref(), context, seq))
+                }
+            }
+        }
+
+        // Without {seq}, collisions are numbered before the extension
+        if seq > 0 && !self.has_seq() {
+            let name_start = path.rfind('/').map_or(0, |i| i + 1);
+            let insert_at = path[name_start..]
+                .rfind('.')
+                .map_or(path.len(), |i| name_start + i);
+            path.insert_str(insert_at, &format!("_{}", seq));
+        }
+
+        PathBuf::from(path)
+    }
+
+    /// Write `bytes` to a new file for `context` under `save_dir`, numbering
+    /// the name until one is free. The bytes are synced to a temporary file
+    /// first and then claim the name with create-new semantics, so
+    /// concurrent saves never share a name and no one sees half a file.
+    pub fn write(&self, save_dir: &Path, context: &NameContext, bytes: &[u8]) -> Result<PathBuf> {
+        let first_seq = u32::from(self.has_seq());
+        let first = save_dir.join(self.render(context, first_seq));
+        let dir = first.parent().unwrap_or(save_dir);
+        let mut temp = TempFile::write(dir, bytes).map_err(Error::at(dir))?;
+
+        for seq in first_seq..first_seq + MAX_ATTEMPTS {
+            let path = save_dir.join(self.render(context, seq));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(Error::at(parent))?;
+            }
+
+            match temp.persist_new(&path) {
+                Ok(()) => return Ok(path),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(Error::io(path, e)),
+            }
+        }
+
+        let message = format!("No free filename for {}", self.pattern);
+        Err(Error::io(
+            save_dir,
+            std::io::Error::new(ErrorKind::AlreadyExists, message),
+        ))
+    }
+
+    /// Capture time encoded in a path relative to the save directory, if
+    /// the template puts one in and the path follows the template
+    pub fn parse_time(&self, relative: &Path) -> Option<NaiveDateTime> {
+        let text = relative.to_str()?.replace('\\', "/");
+        let regex = self.regex()?;
+
+        // Templates without {seq} may have a `_N` collision suffix
+        let text = if regex.is_match(&text) || self.has_seq() {
+            text
+        } else {
+            let suffix = Regex::new(r"_\d+(\.[^./]*)?$").ok()?;
+            suffix.replace(&text, "$1").into_owned()
+        };
+        let captures = regex.captures(&text)?;
+
+        let mut date: Option<NaiveDate> = None;
+        let mut time: Option<NaiveTime> = None;
+        let (mut year, mut month, mut day) = (None, None, None);
+        let (mut hour, mut minute, mut second) = (None, None, None);
+
+        let fields = self.segments.iter().filter_map(|s| match s {
+            Segment::Field { name, spec } => Some((*name, spec.as_deref())),
+            Segment::Literal(_) => None,
+        });
+        for ((name, spec), value) in fields.zip(captures.iter().skip(1)) {
+            let value = value?.as_str();
+            let number = || value.parse::<u32>().ok();
+            match name {
+                Field::Date => {
+                    let format = spec.unwrap_or(DEFAULT_DATE);
+                    match NaiveDateTime::parse_from_str(value, format) {
+                        Ok(datetime) => {
+                            date = Some(datetime.date());
+                            time = time.or(Some(datetime.time()));
+                        }
+                        Err(_) => date = Some(NaiveDate::parse_from_str(value, format).ok()?),
+                    }
+                }
+                Field::Time => {
+                    time =
+                        Some(NaiveTime::parse_from_str(value, spec.unwrap_or(DEFAULT_TIME)).ok()?)
+                }
+                Field::Year => year = number(),
+                Field::Month => month = number(),
+                Field::Day => day = number(),
+                Field::Hour => hour = number(),
+                Field::Minute => minute = number(),
+                Field::Second => second = number(),
+                _ => {}
+            }
+        }
+
+        let date = date.or_else(|| NaiveDate::from_ymd_opt(year? as i32, month?, day?))?;
+        let time = time
+            .or_else(|| NaiveTime::from_hms_opt(hour?, minute.unwrap_or(0), second.unwrap_or(0)))
+            .unwrap_or(NaiveTime::MIN);
+        Some(date.and_time(time))
+    }
+
+    /// A regex matching rendered paths, with one group per field
+    fn regex(&self) -> Option<Regex> {
+        let mut pattern = String::from("^");
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => pattern.push_str(&regex::escape(text)),
+                Segment::Field { name, spec } => {
+                    let group = match name {
+                        Field::Date => strftime_regex(spec.as_deref().unwrap_or(DEFAULT_DATE)),
+                        Field::Time => strftime_regex(spec.as_deref().unwrap_or(DEFAULT_TIME)),
+                        Field::Year => r"\d{4}".to_string(),
+                        Field::Month | Field::Day | Field::Hour | Field::Minute | Field::Second => {
+                            r"\d{2}".to_string()
+                        }
+                        Field::Width | Field::Height | Field::Seq => r"\d+".to_string(),
+                        Field::Digest => "[0-9a-f]+".to_string(),
+                        Field::Ext => "[A-Za-z0-9]+".to_string(),
+                        Field::App | Field::Title => "[^/]*?".to_string(),
+                    };
+                    pattern.push_str(&format!("({})", group));
+                }
+            }
+        }
+        pattern.push('$');
+        Regex::new(&pattern).ok()
+    }
+}
+
+fn render_field(name: Field, spec: Option<&str>, context: &NameContext, seq: u32) -> String {
+    let time = &context.time;
+    match name {
+        // A format that yields a separator must not create directories
+        Field::Date => sanitize(&time.format(spec.unwrap_or(DEFAULT_DATE)).to_string()),
+        Field::Time => sanitize(&time.format(spec.unwrap_or(DEFAULT_TIME)).to_string()),
+        Field::Year => time.format("%Y").to_string(),
+        Field::Month => time.format("%m").to_string(),
+        Field::Day => time.format("%d").to_string(),
+        Field::Hour => time.format("%H").to_string(),
+        Field::Minute => time.format("%M").to_string(),
+        Field::Second => time.format("%S").to_string(),
+        Field::App => sanitize(context.app.unwrap_or_default()),
+        Field::Title => sanitize(context.title.unwrap_or_default()),
+        Field::Width => context.width.to_string(),
+        Field::Height => context.height.to_string(),
+        Field::Digest => context.digest.chars().take(8).collect(),
+        Field::Seq => {
+            let width = spec
+                .and_then(|spec| spec.trim_start_matches('0').parse().ok())
+                .unwrap_or(0);
+            format!("{:0width$}", seq, width = width)
+        }
+        Field::Ext => context.ext.to_string(),
+    }
+}
+
+/// A regex for the text chrono writes for `format`
+fn strftime_regex(format: &str) -> String {
+    let mut pattern = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            pattern.push_str(&regex::escape(&c.to_string()));
+            continue;
+        }
+        pattern.push_str(match chars.next() {
+            Some('Y') => r"\d{4}",
+            Some('m' | 'd' | 'H' | 'M' | 'S' | 'y' | 'I') => r"\d{2}",
+            Some('j') => r"\d{3}",
+            Some('%') => "%",
+            _ => "[^/]+?",
+        });
+    }
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn context<'a>(app: Option<&'a str>) -> NameContext<'a> {
+        NameContext {
+            time: Local.with_ymd_and_hms(2024, 3, 9, 14, 5, 7).unwrap(),
+            app,
+            title: None,
+            width: 800,
+            height: 600,
+            digest: "0123456789abcdef",
+            ext: "png",
+        }
+    }
+
+    #[test]
+    fn test_render_and_parse_back() {
+        let template: FilenameTemplate =
+            "{year}/{month}/{date:%Y%m%d}-{time}-{app}-{w}x{h}-{seq:03}.{ext}"
+                .parse()
+                .unwrap();
+        assert_eq!(template.depth(), 2);
+
+        let path = template.render(&context(Some("Firefox/Nightly")), 1);
+        assert_eq!(
+            path,
+            Path::new("2024/03/20240309-14-05-07-Firefox_Nightly-800x600-001.png")
+        );
+
+        let expected = NaiveDate::from_ymd_opt(2024, 3, 9)
+            .unwrap()
+            .and_hms_opt(14, 5, 7)
+            .unwrap();
+        assert_eq!(template.parse_time(&path), Some(expected));
+        assert_eq!(template.parse_time(Path::new("notes/readme.png")), None);
+
+        // The default names, including their collision suffix
+        let default = FilenameTemplate::default();
+        let path = default.render(&context(None), 2);
+        assert_eq!(path, Path::new("2024-03-09_14-05-07_2.png"));
+        assert_eq!(default.parse_time(&path), Some(expected));
+    }
+
+    #[test]
+    fn test_sanitize_and_reject_escapes() {
+        assert_eq!(sanitize("../../etc/passwd"), "_.._etc_passwd");
+        assert_eq!(sanitize("  ..  "), "unknown");
+        assert_eq!(sanitize("a\nb:c"), "a_b_c");
+
+        assert!("../{date}.{ext}".parse::<FilenameTemplate>().is_err());
+        assert!("/tmp/{date}.{ext}".parse::<FilenameTemplate>().is_err());
+        assert!("{date}/".parse::<FilenameTemplate>().is_err());
+        assert!("{nope}.{ext}".parse::<FilenameTemplate>().is_err());
+        assert!("{date".parse::<FilenameTemplate>().is_err());
+        assert!("{date:%Q}.{ext}".parse::<FilenameTemplate>().is_err());
+        assert!("{time:%H-%}.{ext}".parse::<FilenameTemplate>().is_err());
+    }
+
+    #[test]
+    fn test_write_claims_a_new_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let template = FilenameTemplate::default();
+
+        let first = template.write(dir.path(), &context(None), b"a").unwrap();
+        let second = template.write(dir.path(), &context(None), b"b").unwrap();
+
+        assert_eq!(first.file_name().unwrap(), "2024-03-09_14-05-07.png");
+        assert_eq!(second.file_name().unwrap(), "2024-03-09_14-05-07_1.png");
+        assert_eq!(fs::read(&first).unwrap(), b"a");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+}
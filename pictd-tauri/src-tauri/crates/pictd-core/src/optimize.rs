@@ -0,0 +1,213 @@
+//! Shrinking saved PNGs.
+//!
+//! Lossless recompression through oxipng, which also strips chunks that
+//! don't affect display and reduces the color type and bit depth where the
+//! pixels allow. Flat UI screenshots can additionally be quantized to a
+//! palette, which is lossy and therefore opt-in.
+
+use color_quant::NeuQuant;
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::error::{Error, Result};
+
+/// NeuQuant sampling factor, 1 (best) to 30 (fastest)
+const SAMPLE_FACTOR: i32 = 10;
+
+/// How saved PNGs are optimized
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OptimizeOptions {
+    /// oxipng preset, 0 (fastest) to 6 (smallest)
+    pub level: u8,
+    /// Drop chunks that don't affect display, such as text and timestamps
+    pub strip: bool,
+    /// Quantize to a palette when the result stays good enough
+    pub quantize: Option<QuantizeOptions>,
+}
+
+impl Default for OptimizeOptions {
+    fn default() -> Self {
+        Self {
+            level: 2,
+            strip: true,
+            quantize: None,
+        }
+    }
+}
+
+/// Lossy palette reduction for images with more colors than fit a palette
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuantizeOptions {
+    /// Palette size, at most 256
+    pub max_colors: u16,
+    /// Quality floor as PSNR in dB; images that would fall below it are
+    /// kept at full color. Around 40 is hard to tell apart from the original.
+    pub min_psnr: f64,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            max_colors: 256,
+            min_psnr: 40.0,
+        }
+    }
+}
+
+/// Sizes of an optimized file
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OptimizeReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Whether the pixels were quantized to a palette
+    pub quantized: bool,
+}
+
+/// Optimize `png`, the encoding of `image`. The result is never larger.
+pub fn optimize_png(
+    png: &[u8],
+    image: &RgbaImage,
+    options: &OptimizeOptions,
+) -> Result<(Vec<u8>, OptimizeReport)> {
+    let mut oxipng_options = oxipng::Options::from_preset(options.level.min(6));
+    oxipng_options.strip = if options.strip {
+        oxipng::StripChunks::Safe
+    } else {
+        oxipng::StripChunks::None
+    };
+
+    let quantized = options.quantize.as_ref().and_then(|q| quantize(image, q));
+    let source = match &quantized {
+        Some(quantized) => encode_png(quantized)?,
+        None => png.to_vec(),
+    };
+
+    let mut optimized =
+        oxipng::optimize_from_memory(&source, &oxipng_options).map_err(Error::encode)?;
+    let mut was_quantized = quantized.is_some();
+    if optimized.len() > png.len() {
+        optimized = png.to_vec();
+        was_quantized = false;
+    }
+
+    let report = OptimizeReport {
+        bytes_before: png.len() as u64,
+        bytes_after: optimized.len() as u64,
+        quantized: was_quantized,
+    };
+    Ok((optimized, report))
+}
+
+/// `image` reduced to a palette, if it has more colors than the palette
+/// holds and stays above the quality floor
+fn quantize(image: &RgbaImage, options: &QuantizeOptions) -> Option<RgbaImage> {
+    let max_colors = usize::from(options.max_colors.clamp(2, 256));
+
+    // Few enough colors are made a palette losslessly by oxipng
+    let mut colors = HashSet::new();
+    for pixel in image.pixels() {
+        colors.insert(pixel.0);
+        if colors.len() > max_colors {
+            break;
+        }
+    }
+    if colors.len() <= max_colors {
+        return None;
+    }
+
+    let quant = NeuQuant::new(SAMPLE_FACTOR, max_colors, image.as_raw());
+    let mut quantized = image.clone();
+    for pixel in quantized.pixels_mut() {
+        quant.map_pixel(&mut pixel.0);
+    }
+
+    (psnr(image, &quantized) >= options.min_psnr).then_some(quantized)
+}
+
+/// Peak signal-to-noise ratio over the RGBA channels, in dB
+fn psnr(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(x, y)| (f64::from(*x) - f64::from(*y)).powi(2))
+        .sum();
+    let mse = squared_error / a.as_raw().len().max(1) as f64;
+
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+fn encode_png(image: &RgbaImage) -> Result<Vec<u8>> {
+    let mut png = std::io::Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(Error::from_encoding)?;
+    Ok(png.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    /// A UI-like image: flat panels with a soft gradient bar
+    fn screenshot() -> RgbaImage {
+        RgbaImage::from_fn(200, 120, |x, y| match y {
+            0..=19 => Rgba([40, 40, 60 + (x / 4) as u8, 255]),
+            _ if x < 50 => Rgba([230, 230, 230, 255]),
+            _ => Rgba([255, 255, 255, 255]),
+        })
+    }
+
+    #[test]
+    fn test_lossless_optimization_keeps_pixels() {
+        let image = screenshot();
+        let png = encode_png(&image).unwrap();
+
+        let (optimized, report) = optimize_png(&png, &image, &OptimizeOptions::default()).unwrap();
+
+        assert_eq!(report.bytes_before, png.len() as u64);
+        assert_eq!(report.bytes_after, optimized.len() as u64);
+        assert!(report.bytes_after <= report.bytes_before);
+        assert!(!report.quantized);
+        assert_eq!(
+            image::load_from_memory(&optimized).unwrap().to_rgba8(),
+            image
+        );
+    }
+
+    #[test]
+    fn test_quantization_respects_quality_floor() {
+        let noisy = RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8, 255])
+        });
+        let png = encode_png(&noisy).unwrap();
+
+        let lenient = OptimizeOptions {
+            quantize: Some(QuantizeOptions {
+                max_colors: 256,
+                min_psnr: 20.0,
+            }),
+            ..OptimizeOptions::default()
+        };
+        let (quantized, report) = optimize_png(&png, &noisy, &lenient).unwrap();
+        assert!(report.quantized);
+        assert!(quantized.len() < png.len());
+
+        let strict = OptimizeOptions {
+            quantize: Some(QuantizeOptions {
+                max_colors: 4,
+                min_psnr: 45.0,
+            }),
+            ..OptimizeOptions::default()
+        };
+        let (kept, report) = optimize_png(&png, &noisy, &strict).unwrap();
+        assert!(!report.quantized);
+        assert_eq!(image::load_from_memory(&kept).unwrap().to_rgba8(), noisy);
+    }
+}
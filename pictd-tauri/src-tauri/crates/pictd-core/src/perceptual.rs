@@ -0,0 +1,170 @@
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+use crate::index::CaptureIndex;
+use crate::naming::FilenameTemplate;
+use crate::storage::ImageInfo;
+
+/// 64-bit difference hash (dHash) of an image.
+///
+/// The image is reduced to a 9x8 grayscale grid and each bit records
+/// whether a cell is darker than its right-hand neighbour. Visually
+/// similar images end up a small Hamming distance apart, so a blinking
+/// cursor or a spinning throbber barely moves the hash.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PerceptualHash(u64);
+
+impl PerceptualHash {
+    pub fn of(image: &RgbaImage) -> Self {
+        let gray = imageops::grayscale(image);
+        let small = imageops::resize(&gray, 9, 8, FilterType::Triangle);
+
+        let mut bits = 0u64;
+        for y in 0..8 {
+            for x in 0..8 {
+                let left = small.get_pixel(x, y).0[0];
+                let right = small.get_pixel(x + 1, y).0[0];
+                bits = (bits << 1) | u64::from(left < right);
+            }
+        }
+
+        Self(bits)
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Number of differing bits, 0 (identical) to 64
+    pub fn distance(&self, other: &PerceptualHash) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+impl fmt::Debug for PerceptualHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PerceptualHash({:016x})", self.0)
+    }
+}
+
+/// Suppresses captures that look like one of the recent saves
+#[derive(Clone, Debug)]
+pub struct NearDuplicateFilter {
+    /// How many of the most recent saves to compare against
+    pub recent: usize,
+    /// Captures closer than this many bits to a recent save are skipped
+    pub threshold: u32,
+}
+
+impl Default for NearDuplicateFilter {
+    fn default() -> Self {
+        Self {
+            recent: 10,
+            threshold: 5,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SimilarImage {
+    #[serde(flatten)]
+    pub info: ImageInfo,
+    /// Hamming distance between perceptual hashes, lower is more similar
+    pub distance: u32,
+}
+
+/// Rank the images saved in `save_dir`, as deep as `template` puts them, by
+/// visual similarity to `image`, most similar first. Hashes come from the
+/// index, so only new or changed files are decoded.
+pub fn find_similar(
+    image: &RgbaImage,
+    save_dir: &str,
+    template: &FilenameTemplate,
+) -> Vec<SimilarImage> {
+    let target = PerceptualHash::of(image);
+    let depth = template.depth();
+    let mut index = CaptureIndex::open(Path::new(save_dir));
+    let _ = index.reconcile(template, depth);
+
+    let mut similar: Vec<SimilarImage> = index
+        .entries()
+        .filter(|entry| entry.depth() <= depth)
+        .filter_map(|entry| {
+            Some(SimilarImage {
+                distance: entry.phash?.distance(&target),
+                info: index.info(entry),
+            })
+        })
+        .collect();
+
+    similar.sort_by(|a, b| {
+        a.distance
+            .cmp(&b.distance)
+            .then_with(|| b.info.filename.cmp(&a.info.filename))
+    });
+    similar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use std::fs;
+
+    /// A fake "window": light background with a dark title bar and some text lines
+    fn screenshot(cursor_visible: bool) -> RgbaImage {
+        RgbaImage::from_fn(320, 200, |x, y| {
+            let dark = y < 24
+                || (y % 30 == 10 && x > 20 && x < 20 + (y * 3 % 250))
+                || (cursor_visible && x == 200 && (100..116).contains(&y));
+            if dark {
+                Rgba([30, 30, 40, 255])
+            } else {
+                Rgba([240, 240, 240, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn test_cursor_blink_is_near_duplicate() {
+        let on = PerceptualHash::of(&screenshot(true));
+        let off = PerceptualHash::of(&screenshot(false));
+        let other = PerceptualHash::of(&RgbaImage::from_fn(320, 200, |x, _| {
+            Rgba([(x % 256) as u8, 0, 0, 255])
+        }));
+
+        assert!(on.distance(&off) < NearDuplicateFilter::default().threshold);
+        assert!(on.distance(&other) > 10);
+    }
+
+    #[test]
+    fn test_find_similar_ranks_by_distance() {
+        let dir = tempfile::tempdir().unwrap();
+        let template: FilenameTemplate = "{year}/{date}_{time}.{ext}".parse().unwrap();
+        fs::create_dir(dir.path().join("2024")).unwrap();
+        screenshot(false)
+            .save(dir.path().join("2024/a.png"))
+            .unwrap();
+        RgbaImage::from_fn(320, 200, |x, _| Rgba([(x % 256) as u8, 0, 0, 255]))
+            .save(dir.path().join("b.png"))
+            .unwrap();
+
+        let ranked = find_similar(&screenshot(true), &dir.path().to_string_lossy(), &template);
+
+        assert_eq!(ranked.len(), 2);
+        assert_eq!(ranked[0].info.filename, "a.png");
+        assert!(ranked[0].distance < ranked[1].distance);
+
+        // Hashes are kept in the index for the next search
+        let index = CaptureIndex::open(dir.path());
+        assert!(index.get("2024/a.png").unwrap().phash.is_some());
+    }
+}
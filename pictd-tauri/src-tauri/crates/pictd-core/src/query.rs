@@ -0,0 +1,287 @@
+//! Paged, filtered queries over the capture library.
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::Path;
+
+use crate::format::ImageFormat;
+use crate::index::CaptureIndex;
+use crate::naming::FilenameTemplate;
+use crate::storage::{ImageInfo, TIMESTAMP_FORMAT};
+
+/// Order of query results
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    NewestFirst,
+    OldestFirst,
+    /// By filename
+    Name,
+    /// Most pixels first
+    Largest,
+}
+
+/// Which saved images to return; every filter left unset matches all
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ImageQuery {
+    /// Results to skip, for paging
+    pub offset: usize,
+    /// Most results to return
+    pub limit: Option<usize>,
+    /// Captured at or after
+    pub since: Option<NaiveDateTime>,
+    /// Captured before
+    pub until: Option<NaiveDateTime>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Stored in one of these formats
+    pub formats: Vec<ImageFormat>,
+    /// Source app contains this, ignoring case
+    pub app: Option<String>,
+    /// Has all of these tags
+    pub tags: Vec<String>,
+    /// Filename, source window or a tag contains this, ignoring case
+    pub text: Option<String>,
+    pub sort: SortOrder,
+    /// Include every subdirectory, not just those the template creates
+    pub recursive: bool,
+}
+
+/// One page of query results
+#[derive(Clone, Debug, Serialize)]
+pub struct ImagePage {
+    pub images: Vec<ImageInfo>,
+    /// Matches across all pages
+    pub total: usize,
+}
+
+impl ImageQuery {
+    /// Whether `info` passes every filter
+    pub fn matches(&self, info: &ImageInfo) -> bool {
+        let in_range = |value: u32, min: Option<u32>, max: Option<u32>| {
+            min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max)
+        };
+        if !in_range(info.width, self.min_width, self.max_width)
+            || !in_range(info.height, self.min_height, self.max_height)
+        {
+            return false;
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let Ok(time) = NaiveDateTime::parse_from_str(&info.timestamp, TIMESTAMP_FORMAT) else {
+                return false;
+            };
+            if self.since.is_some_and(|since| time < since)
+                || self.until.is_some_and(|until| time >= until)
+            {
+                return false;
+            }
+        }
+
+        if !self.formats.is_empty() && !self.formats.contains(&info.format) {
+            return false;
+        }
+
+        if let Some(app) = self.app.as_deref().filter(|app| !app.is_empty()) {
+            let app = app.to_lowercase();
+            let source_app = info.source.as_ref().and_then(|s| s.app.as_deref());
+            if !source_app.is_some_and(|source| source.to_lowercase().contains(&app)) {
+                return false;
+            }
+        }
+
+        let has_tag = |wanted: &String| {
+            info.tags
+                .iter()
+                .any(|tag| tag.eq_ignore_ascii_case(wanted.trim()))
+        };
+        if !self.tags.iter().all(has_tag) {
+            return false;
+        }
+
+        self.text
+            .as_deref()
+            .map(str::trim)
+            .is_none_or(|text| text.is_empty() || info.matches(text))
+    }
+
+    fn sort(&self, images: &mut [ImageInfo]) {
+        match self.sort {
+            SortOrder::NewestFirst => images.sort_by(|a, b| {
+                b.timestamp
+                    .cmp(&a.timestamp)
+                    .then_with(|| b.filename.cmp(&a.filename))
+            }),
+            SortOrder::OldestFirst => images.sort_by(|a, b| {
+                a.timestamp
+                    .cmp(&b.timestamp)
+                    .then_with(|| a.filename.cmp(&b.filename))
+            }),
+            SortOrder::Name => images.sort_by(|a, b| {
+                a.filename
+                    .cmp(&b.filename)
+                    .then_with(|| a.path.cmp(&b.path))
+            }),
+            SortOrder::Largest => images.sort_by_key(|info| {
+                (
+                    Reverse(u64::from(info.width) * u64::from(info.height)),
+                    Reverse(info.timestamp.clone()),
+                )
+            }),
+        }
+    }
+}
+
+/// Saved images named by `template` that match `query`. Served from the
+/// directory's [`CaptureIndex`] after reconciling it with the files, so
+/// only new or changed images are decoded. Subdirectories are searched as
+/// deep as the template creates them, or all of them if `query.recursive`.
+pub fn query_saved_images(
+    save_dir: &str,
+    template: &FilenameTemplate,
+    query: &ImageQuery,
+) -> ImagePage {
+    let depth = if query.recursive {
+        usize::MAX
+    } else {
+        template.depth()
+    };
+
+    let mut index = CaptureIndex::open(Path::new(save_dir));
+    // Listing still works from memory when the log can't be written
+    let _ = index.reconcile(template, depth);
+
+    let mut images: Vec<ImageInfo> = index
+        .entries()
+        .filter(|entry| entry.depth() <= depth)
+        .map(|entry| index.info(entry))
+        .filter(|info| query.matches(info))
+        .collect();
+    query.sort(&mut images);
+
+    let total = images.len();
+    let images = images
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect();
+    ImagePage { images, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::SaveOptions;
+    use crate::source::ClipboardImage;
+    use crate::storage::{self, save_capture};
+    use crate::window::SourceWindow;
+    use image::RgbaImage;
+    use std::fs;
+
+    #[test]
+    fn test_filters_sorting_and_paging() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let template = FilenameTemplate::default();
+
+        let gimp = SourceWindow {
+            app: Some("Gimp".to_string()),
+            title: None,
+        };
+        let wide = save_capture(
+            &ClipboardImage::decoded(RgbaImage::new(400, 100)),
+            Some(&gimp),
+            &save_dir,
+            &SaveOptions::default(),
+        )
+        .unwrap();
+        RgbaImage::new(50, 50)
+            .save(dir.path().join("2021-01-01_00-00-00.png"))
+            .unwrap();
+        RgbaImage::new(60, 60)
+            .save(dir.path().join("2022-06-01_12-00-00.webp"))
+            .unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        RgbaImage::new(70, 70)
+            .save(dir.path().join("nested/2023-01-01_00-00-00.png"))
+            .unwrap();
+
+        let run = |query: ImageQuery| query_saved_images(&save_dir, &template, &query);
+
+        let all = run(ImageQuery::default());
+        assert_eq!(all.total, 3);
+        assert_eq!(all.images[0].path, wide.path);
+        assert_eq!(all.images[2].filename, "2021-01-01_00-00-00.png");
+
+        let page = run(ImageQuery {
+            offset: 1,
+            limit: Some(1),
+            sort: SortOrder::OldestFirst,
+            ..ImageQuery::default()
+        });
+        assert_eq!(page.total, 3);
+        assert_eq!(page.images.len(), 1);
+        assert_eq!(page.images[0].filename, "2022-06-01_12-00-00.webp");
+
+        let ranged = run(ImageQuery {
+            since: Some("2021-06-01T00:00:00".parse().unwrap()),
+            until: Some("2023-01-01T00:00:00".parse().unwrap()),
+            ..ImageQuery::default()
+        });
+        assert_eq!(ranged.total, 1);
+        assert_eq!(ranged.images[0].format, ImageFormat::Webp);
+
+        let sized = run(ImageQuery {
+            min_width: Some(55),
+            max_height: Some(100),
+            sort: SortOrder::Largest,
+            ..ImageQuery::default()
+        });
+        assert_eq!(
+            sized.images.iter().map(|i| i.width).collect::<Vec<_>>(),
+            [400, 60]
+        );
+
+        let from_gimp = run(ImageQuery {
+            app: Some("gimp".to_string()),
+            formats: vec![ImageFormat::Png],
+            ..ImageQuery::default()
+        });
+        assert_eq!(from_gimp.total, 1);
+
+        storage::set_tags(
+            &save_dir,
+            Path::new(&all.images[2].path),
+            &[" bug ".to_string(), "ui".to_string(), "bug".to_string()],
+        )
+        .unwrap();
+        let tagged = run(ImageQuery {
+            tags: vec!["BUG".to_string(), "ui".to_string()],
+            ..ImageQuery::default()
+        });
+        assert_eq!(tagged.total, 1);
+        assert_eq!(tagged.images[0].tags, ["bug", "ui"]);
+        assert_eq!(
+            run(ImageQuery {
+                text: Some("bug".to_string()),
+                ..ImageQuery::default()
+            })
+            .total,
+            1
+        );
+
+        let recursive = run(ImageQuery {
+            recursive: true,
+            ..ImageQuery::default()
+        });
+        assert_eq!(recursive.total, 4);
+        // Going back to the template's depth doesn't forget nested files
+        assert_eq!(run(ImageQuery::default()).total, 3);
+        assert_eq!(CaptureIndex::open(dir.path()).len(), 4);
+    }
+}
@@ -0,0 +1,354 @@
+//! Retention of auto-saved captures.
+//!
+//! Expired captures are moved to the trash (the freedesktop trash on
+//! Linux), along with their sidecars, never unlinked. Captures tagged
+//! [`STARRED_TAG`] are never expired and don't count against the limits.
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::index::{CaptureIndex, IndexEntry};
+use crate::naming::FilenameTemplate;
+use crate::storage::{self, TIMESTAMP_FORMAT};
+
+/// Tag that protects a capture from retention
+pub const STARRED_TAG: &str = "starred";
+
+/// How long auto-saved captures are kept; limits left unset don't apply
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Trash captures older than this many days
+    pub max_age_days: Option<u32>,
+    /// Trash the oldest captures beyond this many bytes in total
+    pub max_bytes: Option<u64>,
+    /// Keep only this many of the most recent captures
+    pub keep_recent: Option<usize>,
+}
+
+impl RetentionPolicy {
+    /// Whether the policy keeps everything
+    pub fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_bytes.is_none() && self.keep_recent.is_none()
+    }
+}
+
+/// Which limit a capture exceeded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryReason {
+    Age,
+    Size,
+    Count,
+}
+
+/// A capture the policy removes
+#[derive(Clone, Debug, Serialize)]
+pub struct ExpiredCapture {
+    pub path: String,
+    pub bytes: u64,
+    pub reason: ExpiryReason,
+}
+
+/// What a retention run removes, or would remove in a dry run
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RetentionReport {
+    pub expired: Vec<ExpiredCapture>,
+    pub freed_bytes: u64,
+    /// Captures left, starred ones included
+    pub kept: usize,
+    pub starred: usize,
+}
+
+/// Dry run: the captures in `save_dir` that `policy` would trash now
+pub fn plan_retention(
+    save_dir: &str,
+    template: &FilenameTemplate,
+    policy: &RetentionPolicy,
+) -> RetentionReport {
+    let mut index = CaptureIndex::open(Path::new(save_dir));
+    plan(&mut index, template, policy, Local::now())
+}
+
+/// Trash the captures in `save_dir` that `policy` expires, with their sidecars
+pub fn apply_retention(
+    save_dir: &str,
+    template: &FilenameTemplate,
+    policy: &RetentionPolicy,
+) -> Result<RetentionReport> {
+    apply(save_dir, template, policy, Local::now(), |paths| {
+        trash::delete_all(paths).map_err(std::io::Error::other)
+    })
+}
+
+fn plan(
+    index: &mut CaptureIndex,
+    template: &FilenameTemplate,
+    policy: &RetentionPolicy,
+    now: DateTime<Local>,
+) -> RetentionReport {
+    let mut report = RetentionReport::default();
+    let depth = template.depth();
+    let _ = index.reconcile(template, depth);
+
+    // Only pictd's captures; the save directory may be the user's Downloads
+    let mut entries: Vec<(&IndexEntry, NaiveDateTime)> = index
+        .entries()
+        .filter(|entry| entry.depth() <= depth && is_capture(entry, template))
+        .map(|entry| (entry, captured_at(entry)))
+        .collect();
+    entries.sort_by_key(|(entry, time)| (Reverse(*time), Reverse(entry.path.clone())));
+
+    let cutoff = policy
+        .max_age_days
+        .map(|days| now.naive_local() - TimeDelta::days(days.into()));
+    let mut kept = 0;
+    let mut kept_bytes = 0;
+    let mut over_budget = false;
+
+    for (entry, time) in entries {
+        if entry.tags.iter().any(|tag| tag == STARRED_TAG) {
+            report.starred += 1;
+            report.kept += 1;
+            continue;
+        }
+
+        over_budget = over_budget
+            || policy
+                .max_bytes
+                .is_some_and(|max| kept_bytes + entry.bytes > max);
+        let reason = if cutoff.is_some_and(|cutoff| time < cutoff) {
+            Some(ExpiryReason::Age)
+        } else if policy.keep_recent.is_some_and(|n| kept >= n) {
+            Some(ExpiryReason::Count)
+        } else if over_budget {
+            Some(ExpiryReason::Size)
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                report.freed_bytes += entry.bytes;
+                report.expired.push(ExpiredCapture {
+                    path: entry.path.clone(),
+                    bytes: entry.bytes,
+                    reason,
+                });
+            }
+            None => {
+                kept += 1;
+                kept_bytes += entry.bytes;
+                report.kept += 1;
+            }
+        }
+    }
+
+    report
+}
+
+fn apply(
+    save_dir: &str,
+    template: &FilenameTemplate,
+    policy: &RetentionPolicy,
+    now: DateTime<Local>,
+    mut dispose: impl FnMut(&[PathBuf]) -> std::io::Result<()>,
+) -> Result<RetentionReport> {
+    let mut index = CaptureIndex::open(Path::new(save_dir));
+    let report = plan(&mut index, template, policy, now);
+
+    for expired in &report.expired {
+        let path = Path::new(save_dir).join(&expired.path);
+        let mut paths = vec![path.clone()];
+        let sidecar = storage::sidecar_path(&path);
+        if sidecar.exists() {
+            paths.push(sidecar);
+        }
+
+        dispose(&paths).map_err(Error::at(&path))?;
+        index.remove(&expired.path)?;
+    }
+
+    Ok(report)
+}
+
+/// Whether pictd saved `entry`, or it is named by `template` and so was
+/// saved before the index recorded that
+fn is_capture(entry: &IndexEntry, template: &FilenameTemplate) -> bool {
+    entry.captured || template.parse_time(Path::new(&entry.path)).is_some()
+}
+
+/// Capture time from the index, or the file's modification time
+fn captured_at(entry: &IndexEntry) -> NaiveDateTime {
+    NaiveDateTime::parse_from_str(&entry.timestamp, TIMESTAMP_FORMAT).unwrap_or_else(|_| {
+        DateTime::from_timestamp_millis(entry.modified as i64)
+            .unwrap_or_default()
+            .with_timezone(&Local)
+            .naive_local()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::SaveOptions;
+    use crate::source::ClipboardImage;
+    use image::{RgbImage, RgbaImage};
+    use std::fs;
+
+    fn capture(dir: &Path, name: &str, size: u32) {
+        RgbaImage::new(size, size).save(dir.join(name)).unwrap();
+    }
+
+    fn expired(report: &RetentionReport) -> Vec<(&str, ExpiryReason)> {
+        report
+            .expired
+            .iter()
+            .map(|e| (e.path.as_str(), e.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_policies_expire_oldest_and_spare_starred() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let template = FilenameTemplate::default();
+        let now = "2024-03-10T12:00:00"
+            .parse::<NaiveDateTime>()
+            .unwrap()
+            .and_local_timezone(Local)
+            .unwrap();
+
+        capture(dir.path(), "2024-03-10_11-00-00.png", 40);
+        capture(dir.path(), "2024-03-09_11-00-00.png", 40);
+        capture(dir.path(), "2024-03-01_11-00-00.png", 40);
+        capture(dir.path(), "2024-01-01_11-00-00.png", 40);
+        storage::set_tags(
+            &save_dir,
+            &dir.path().join("2024-01-01_11-00-00.png"),
+            &[STARRED_TAG.to_string()],
+        )
+        .unwrap();
+
+        let dry_run = |policy: RetentionPolicy| {
+            let mut index = CaptureIndex::open(dir.path());
+            plan(&mut index, &template, &policy, now)
+        };
+
+        assert!(dry_run(RetentionPolicy::default()).expired.is_empty());
+
+        let by_age = dry_run(RetentionPolicy {
+            max_age_days: Some(7),
+            ..RetentionPolicy::default()
+        });
+        assert_eq!(
+            expired(&by_age),
+            [("2024-03-01_11-00-00.png", ExpiryReason::Age)]
+        );
+        assert_eq!((by_age.kept, by_age.starred), (3, 1));
+
+        let by_count = dry_run(RetentionPolicy {
+            keep_recent: Some(1),
+            ..RetentionPolicy::default()
+        });
+        assert_eq!(
+            expired(&by_count),
+            [
+                ("2024-03-09_11-00-00.png", ExpiryReason::Count),
+                ("2024-03-01_11-00-00.png", ExpiryReason::Count),
+            ]
+        );
+
+        let size = fs::metadata(dir.path().join("2024-03-10_11-00-00.png"))
+            .unwrap()
+            .len();
+        let by_size = dry_run(RetentionPolicy {
+            max_bytes: Some(size * 2 + 1),
+            ..RetentionPolicy::default()
+        });
+        assert_eq!(
+            expired(&by_size),
+            [("2024-03-01_11-00-00.png", ExpiryReason::Size)]
+        );
+        assert_eq!(by_size.freed_bytes, size);
+
+        // Disposal takes the sidecar along
+        storage::set_tags(
+            &save_dir,
+            &dir.path().join("2024-03-09_11-00-00.png"),
+            &["old".to_string()],
+        )
+        .unwrap();
+        let mut disposed = Vec::new();
+        let report = apply(
+            &save_dir,
+            &template,
+            &RetentionPolicy {
+                keep_recent: Some(1),
+                ..RetentionPolicy::default()
+            },
+            now,
+            |paths| {
+                for path in paths {
+                    fs::remove_file(path)?;
+                    disposed.push(path.file_name().unwrap().to_string_lossy().to_string());
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(report.expired.len(), 2);
+        assert_eq!(report.kept, 2);
+        assert_eq!(
+            disposed,
+            [
+                "2024-03-09_11-00-00.png",
+                ".2024-03-09_11-00-00.png.pictd.json",
+                "2024-03-01_11-00-00.png",
+            ]
+        );
+        assert_eq!(CaptureIndex::open(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn test_other_files_in_the_save_dir_are_never_expired() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let template: FilenameTemplate = "{app}-{seq}.{ext}".parse().unwrap();
+        let options = SaveOptions {
+            filename: template.clone(),
+            ..SaveOptions::default()
+        };
+
+        RgbImage::new(40, 40)
+            .save(dir.path().join("holiday.jpg"))
+            .unwrap();
+        let saved = storage::save_capture(
+            &ClipboardImage::decoded(RgbaImage::new(40, 40)),
+            None,
+            &save_dir,
+            &options,
+        )
+        .unwrap();
+
+        let report = apply(
+            &save_dir,
+            &template,
+            &RetentionPolicy {
+                max_age_days: Some(0),
+                keep_recent: Some(0),
+                max_bytes: Some(0),
+            },
+            Local::now() + TimeDelta::days(1),
+            |paths| paths.iter().try_for_each(fs::remove_file),
+        )
+        .unwrap();
+        assert_eq!(expired(&report).len(), 1);
+        assert_eq!(report.expired[0].path, saved.filename);
+        assert_eq!(report.kept, 0);
+        assert!(dir.path().join("holiday.jpg").exists());
+    }
+}
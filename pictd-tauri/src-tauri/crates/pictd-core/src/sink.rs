@@ -0,0 +1,478 @@
+//! Remote copies of saved captures.
+//!
+//! A [`Sink`] stores a capture under a key, its path relative to the save
+//! directory, and returns the URL it can be shared with. There is an
+//! S3-compatible sink, addressed path-style so MinIO and other self-hosted
+//! stores work, and a WebDAV sink. See [`crate::upload`] for the queue that
+//! feeds them.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::atomic::write_atomic;
+use crate::error::{Error, Result};
+
+/// How long one request may take, connecting included
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Escaped in key segments: everything but RFC 3986 unreserved characters
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Somewhere captures are copied to
+pub trait Sink: Send {
+    /// Store `bytes` under `key`, a relative path with `/` separators,
+    /// returning the URL they can be fetched from
+    fn upload(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<String>;
+}
+
+/// A sink as configured, named so queued uploads can refer to it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SinkConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: SinkTarget,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkTarget {
+    S3(S3Config),
+    WebDav(WebDavConfig),
+}
+
+impl SinkConfig {
+    pub fn open(&self) -> Box<dyn Sink> {
+        match &self.target {
+            SinkTarget::S3(config) => Box::new(S3Sink::new(config.clone())),
+            SinkTarget::WebDav(config) => Box::new(WebDavSink::new(config.clone())),
+        }
+    }
+}
+
+/// Load the sinks configured in the JSON array at `path`; none if it is missing
+pub fn load_sinks(path: &Path) -> Result<Vec<SinkConfig>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).map_err(Error::json(path)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(Error::io(path, e)),
+    }
+}
+
+/// Write `sinks` to `path`, as read by [`load_sinks`]
+pub fn save_sinks(path: &Path, sinks: &[SinkConfig]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::at(parent))?;
+    }
+    let json = serde_json::to_string_pretty(sinks).map_err(Error::json(path))?;
+    write_atomic(path, json.as_bytes())
+}
+
+/// `pictd/sinks.json` under the platform's config directory
+pub fn default_sinks_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("pictd")
+        .join("sinks.json")
+}
+
+/// An S3 bucket, or a bucket of a store speaking the S3 API
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct S3Config {
+    /// Base URL of the service, such as `https://s3.eu-west-1.amazonaws.com`
+    /// or `http://localhost:9000` for MinIO. Buckets are addressed
+    /// path-style, as `endpoint/bucket/key`.
+    pub endpoint: String,
+    /// Signing region; MinIO accepts the default
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to keys, such as `captures/`
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Base of the returned URLs, such as a CDN in front of the bucket;
+    /// `endpoint/bucket` when unset
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// A WebDAV collection, such as a Nextcloud folder
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebDavConfig {
+    /// URL of the collection captures go into; missing subcollections are created
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Base of the returned URLs, such as a public share of the
+    /// collection; `url` when unset
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+/// Uploads with a `PUT` signed with AWS Signature Version 4
+pub struct S3Sink {
+    config: S3Config,
+    agent: ureq::Agent,
+}
+
+impl S3Sink {
+    pub fn new(config: S3Config) -> Self {
+        Self {
+            config,
+            agent: agent(),
+        }
+    }
+}
+
+impl Sink for S3Sink {
+    fn upload(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<String> {
+        let key = format!("{}{}", self.config.prefix, key);
+        let bucket_url = join_url(&self.config.endpoint, &escape_key(&self.config.bucket));
+        let url = url::Url::parse(&join_url(&bucket_url, &escape_key(&key)))
+            .map_err(|e| Error::upload(format!("{}: {}", self.config.endpoint, e)))?;
+
+        let payload_hash = hex(&Sha256::digest(bytes));
+        let request = SignedRequest {
+            method: "PUT",
+            host: &host_header(&url),
+            path: url.path(),
+            payload_hash: &payload_hash,
+        };
+        let headers = request.sign(&self.config, Utc::now());
+
+        let mut put = self
+            .agent
+            .put(url.as_str())
+            .set("Content-Type", content_type);
+        for (name, value) in &headers {
+            put = put.set(name, value);
+        }
+        put.send_bytes(bytes).map_err(upload_error)?;
+
+        let base = self.config.public_url.as_deref().unwrap_or(&bucket_url);
+        Ok(join_url(base, &escape_key(&key)))
+    }
+}
+
+/// What is signed of a request, see [`SignedRequest::sign`]
+struct SignedRequest<'a> {
+    method: &'a str,
+    /// `Host` header, with the port unless it is the scheme's default
+    host: &'a str,
+    /// Escaped path; there is never a query
+    path: &'a str,
+    /// Hex SHA-256 of the body
+    payload_hash: &'a str,
+}
+
+impl SignedRequest<'_> {
+    /// The `Authorization`, `x-amz-date` and `x-amz-content-sha256` headers
+    fn sign(&self, config: &S3Config, time: DateTime<Utc>) -> Vec<(&'static str, String)> {
+        let amz_date = time.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = time.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+
+        let canonical_request = format!(
+            "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+            self.method,
+            self.path,
+            self.host,
+            self.payload_hash,
+            amz_date,
+            signed_headers,
+            self.payload_hash
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let key = signing_key(&config.secret_key, &date, &config.region, "s3");
+        let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+
+        vec![
+            (
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    config.access_key, scope, signed_headers, signature
+                ),
+            ),
+            ("x-amz-date", amz_date),
+            ("x-amz-content-sha256", self.payload_hash.to_string()),
+        ]
+    }
+}
+
+/// The Signature Version 4 key for one day, region and service
+fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    let key = hmac(&key, region.as_bytes());
+    let key = hmac(&key, service.as_bytes());
+    hmac(&key, b"aws4_request")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Uploads with `PUT`, creating missing subcollections with `MKCOL`
+pub struct WebDavSink {
+    config: WebDavConfig,
+    agent: ureq::Agent,
+}
+
+impl WebDavSink {
+    pub fn new(config: WebDavConfig) -> Self {
+        Self {
+            config,
+            agent: agent(),
+        }
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.config.username {
+            Some(username) => {
+                let password = self.config.password.as_deref().unwrap_or_default();
+                let credentials = STANDARD.encode(format!("{}:{}", username, password));
+                request.set("Authorization", &format!("Basic {}", credentials))
+            }
+            None => request,
+        }
+    }
+
+    /// Create the collections `key` is in, outermost first
+    fn create_collections(&self, key: &str) -> Result<()> {
+        let segments: Vec<&str> = key.split('/').collect();
+        for depth in 1..segments.len() {
+            let url = join_url(&self.config.url, &escape_key(&segments[..depth].join("/")));
+            match self.request("MKCOL", &format!("{}/", url)).call() {
+                // 405: it exists already
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(upload_error(e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for WebDavSink {
+    fn upload(&self, key: &str, bytes: &[u8], content_type: &str) -> Result<String> {
+        let url = join_url(&self.config.url, &escape_key(key));
+        let put = || self.request("PUT", &url).set("Content-Type", content_type);
+
+        match put().send_bytes(bytes) {
+            Ok(_) => {}
+            // The parent collection is missing; servers disagree on which
+            Err(ureq::Error::Status(404 | 409, _)) => {
+                self.create_collections(key)?;
+                put().send_bytes(bytes).map_err(upload_error)?;
+            }
+            Err(e) => return Err(upload_error(e)),
+        }
+
+        let base = self
+            .config
+            .public_url
+            .as_deref()
+            .unwrap_or(&self.config.url);
+        Ok(join_url(base, &escape_key(key)))
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(TIMEOUT).build()
+}
+
+/// `key` with each `/`-separated segment percent-encoded
+fn escape_key(key: &str) -> String {
+    key.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn join_url(base: &str, path: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), path)
+}
+
+/// The `Host` header ureq sends for `url`
+fn host_header(url: &url::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Describe a failed request, with the S3 error code when there is one
+fn upload_error(error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(status, response) => {
+            let url = response.get_url().to_string();
+            let body = response.into_string().unwrap_or_default();
+            match xml_element(&body, "Code") {
+                Some(code) => Error::upload(format!("{}: {} {}", url, status, code)),
+                None => Error::upload(format!("{}: HTTP {}", url, status)),
+            }
+        }
+        ureq::Error::Transport(transport) => Error::upload(transport),
+    }
+}
+
+/// Text of the first `<name>` element of an XML body
+fn xml_element<'a>(body: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find("</")? + start;
+    Some(&body[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    /// Answer one request per status, returning the base URL and the
+    /// request lines and headers received
+    fn serve(statuses: Vec<u16>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            statuses
+                .into_iter()
+                .map(|status| {
+                    let (stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(&stream);
+                    let mut head = String::new();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        head.push_str(&line);
+                    }
+                    reader.read_exact(&mut vec![0; length]).unwrap();
+                    write!(
+                        &stream,
+                        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .unwrap();
+                    head
+                })
+                .collect()
+        });
+        (base, handle)
+    }
+
+    #[test]
+    fn test_signing_key() {
+        // From the AWS Signature Version 4 documentation
+        let key = signing_key(
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            "20120215",
+            "us-east-1",
+            "iam",
+        );
+        assert_eq!(
+            hex(&key),
+            "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+        );
+    }
+
+    #[test]
+    fn test_s3_puts_path_style() {
+        let (endpoint, server) = serve(vec![200]);
+        let sink = S3Sink::new(S3Config {
+            endpoint: endpoint.clone(),
+            region: default_region(),
+            bucket: "shots".to_string(),
+            prefix: "team/".to_string(),
+            access_key: "minio".to_string(),
+            secret_key: "minio123".to_string(),
+            public_url: None,
+        });
+
+        let url = sink
+            .upload("2026/10/a b.png", b"png bytes", "image/png")
+            .unwrap();
+        assert_eq!(url, format!("{}/shots/team/2026/10/a%20b.png", endpoint));
+
+        let request = &server.join().unwrap()[0];
+        assert!(request.starts_with("PUT /shots/team/2026/10/a%20b.png HTTP/1.1"));
+        assert!(request.contains("Authorization: AWS4-HMAC-SHA256 Credential=minio/"));
+        assert!(request.contains("/us-east-1/s3/aws4_request"));
+        assert!(request.contains(&format!(
+            "x-amz-content-sha256: {}",
+            hex(&Sha256::digest(b"png bytes"))
+        )));
+    }
+
+    #[test]
+    fn test_webdav_creates_missing_collections() {
+        // PUT fails, MKCOL 2026/ exists already, MKCOL 2026/10/ succeeds, PUT again
+        let (base, server) = serve(vec![409, 405, 201, 201]);
+        let sink = WebDavSink::new(WebDavConfig {
+            url: format!("{}/dav/pictd/", base),
+            username: Some("me".to_string()),
+            password: Some("secret".to_string()),
+            public_url: Some("https://share.example.com/s/abc".to_string()),
+        });
+
+        let url = sink
+            .upload("2026/10/shot.png", b"png", "image/png")
+            .unwrap();
+        assert_eq!(url, "https://share.example.com/s/abc/2026/10/shot.png");
+
+        let requests = server.join().unwrap();
+        let lines: Vec<&str> = requests.iter().map(|r| r.lines().next().unwrap()).collect();
+        assert_eq!(
+            lines,
+            [
+                "PUT /dav/pictd/2026/10/shot.png HTTP/1.1",
+                "MKCOL /dav/pictd/2026/ HTTP/1.1",
+                "MKCOL /dav/pictd/2026/10/ HTTP/1.1",
+                "PUT /dav/pictd/2026/10/shot.png HTTP/1.1",
+            ]
+        );
+        assert!(requests[0].contains(&format!(
+            "Authorization: Basic {}",
+            STANDARD.encode("me:secret")
+        )));
+    }
+}
@@ -0,0 +1,551 @@
+use arboard::Clipboard;
+use image::{ImageBuffer, RgbaImage};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::command::{self, CommandPreset, CommandSource};
+use crate::error::{Error, Result};
+use crate::files;
+use crate::format::ImageFormat;
+use crate::watch::{self, ChangeNotifier, ChannelNotifier};
+
+/// Image bytes exactly as the clipboard owner offered them
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodedImage {
+    pub format: ImageFormat,
+    pub bytes: Vec<u8>,
+}
+
+/// An image read from the clipboard
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClipboardImage {
+    /// Decoded pixels, used for duplicate detection and thumbnails
+    pub image: RgbaImage,
+    /// The original encoding, if the source could hand it over
+    pub encoded: Option<EncodedImage>,
+    /// Read from an image file, such as a copied photo, rather than
+    /// captured from the screen
+    pub from_file: bool,
+}
+
+impl ClipboardImage {
+    /// An image only available as pixels, stored as PNG
+    pub fn decoded(image: RgbaImage) -> Self {
+        Self {
+            image,
+            encoded: None,
+            from_file: false,
+        }
+    }
+
+    /// Decode `bytes` and keep them as the original encoding
+    pub fn from_encoded(format: ImageFormat, bytes: Vec<u8>) -> Result<Self> {
+        Ok(Self {
+            image: format.decode(&bytes)?,
+            encoded: Some(EncodedImage { format, bytes }),
+            from_file: false,
+        })
+    }
+
+    /// Drop the original encoding unless its format is one of `accepted`
+    fn accepting(mut self, accepted: &[ImageFormat]) -> Self {
+        if self
+            .encoded
+            .as_ref()
+            .is_some_and(|e| !accepted.contains(&e.format))
+        {
+            self.encoded = None;
+        }
+        self
+    }
+}
+
+/// What a [`ClipboardSource::read`] should hand over
+#[derive(Clone, Debug)]
+pub struct ReadOptions {
+    /// Formats kept with their original bytes; anything else is pixels only
+    pub formats: Vec<ImageFormat>,
+    /// Images with more pixels than this are reported as
+    /// [`ReadOutcome::TooLarge`]. Encoded images are checked against their
+    /// header, so they are never decoded.
+    pub max_pixels: Option<u64>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            formats: ImageFormat::ALL.to_vec(),
+            max_pixels: None,
+        }
+    }
+}
+
+impl ReadOptions {
+    pub(crate) fn too_large(&self, width: u32, height: u32) -> bool {
+        self.max_pixels
+            .is_some_and(|max| u64::from(width) * u64::from(height) > max)
+    }
+
+    /// Decode an offered image unless its header says it is too large
+    pub fn decode(&self, encoded: EncodedImage) -> Result<ReadOutcome> {
+        let (width, height) = encoded.format.dimensions(&encoded.bytes)?;
+        if self.too_large(width, height) {
+            return Ok(ReadOutcome::TooLarge { width, height });
+        }
+
+        let image = encoded.format.decode(&encoded.bytes)?;
+        Ok(ReadOutcome::Image(
+            ClipboardImage {
+                image,
+                encoded: Some(encoded),
+                from_file: false,
+            }
+            .accepting(&self.formats),
+        ))
+    }
+
+    /// Apply the options to an image that is already decoded
+    pub fn admit(&self, image: ClipboardImage) -> ReadOutcome {
+        let (width, height) = image.image.dimensions();
+        if self.too_large(width, height) {
+            return ReadOutcome::TooLarge { width, height };
+        }
+
+        ReadOutcome::Image(image.accepting(&self.formats))
+    }
+}
+
+/// What a [`ClipboardSource::read`] found
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReadOutcome {
+    Image(ClipboardImage),
+    /// An image over [`ReadOptions::max_pixels`]
+    TooLarge {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl ReadOutcome {
+    pub fn image(self) -> Option<ClipboardImage> {
+        match self {
+            Self::Image(image) => Some(image),
+            Self::TooLarge { .. } => None,
+        }
+    }
+}
+
+/// Something clipboard images can be read from
+pub trait ClipboardSource: Send {
+    /// Read the image currently on offer, `Ok(None)` if there is no image
+    fn get_image(&mut self) -> Result<Option<RgbaImage>>;
+
+    /// Image formats on offer, in the order the owner listed them.
+    ///
+    /// Sources that only hand over decoded pixels offer none.
+    fn formats(&mut self) -> Result<Vec<ImageFormat>> {
+        Ok(Vec::new())
+    }
+
+    /// Read the content in `format` without re-encoding it
+    fn get_encoded(&mut self, _format: ImageFormat) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// Read the image, keeping the original bytes of the first offered
+    /// format that is in `options.formats`.
+    ///
+    /// Owners list their native format first, so that is the one kept.
+    /// Falls back to the decoded pixels when nothing acceptable is offered.
+    fn read(&mut self, options: &ReadOptions) -> Result<Option<ReadOutcome>> {
+        for format in self.formats()? {
+            if !options.formats.contains(&format) {
+                continue;
+            }
+
+            // An offer that doesn't decode is skipped in favour of the next
+            if let Some(bytes) = self.get_encoded(format)? {
+                if let Ok(outcome) = options.decode(EncodedImage { format, bytes }) {
+                    return Ok(Some(outcome));
+                }
+            }
+        }
+
+        Ok(self
+            .get_image()?
+            .map(|image| options.admit(ClipboardImage::decoded(image))))
+    }
+
+    /// Files copied to the clipboard, e.g. in a file manager; empty if the
+    /// content isn't a file list
+    fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+
+    /// Open a notifier that fires when the content changes.
+    ///
+    /// Sources that return `None`, or fail to open one, are polled instead.
+    fn change_notifier(&mut self) -> Result<Option<Box<dyn ChangeNotifier>>> {
+        Ok(None)
+    }
+}
+
+impl<S: ClipboardSource + ?Sized> ClipboardSource for Box<S> {
+    fn get_image(&mut self) -> Result<Option<RgbaImage>> {
+        (**self).get_image()
+    }
+
+    fn formats(&mut self) -> Result<Vec<ImageFormat>> {
+        (**self).formats()
+    }
+
+    fn get_encoded(&mut self, format: ImageFormat) -> Result<Option<Vec<u8>>> {
+        (**self).get_encoded(format)
+    }
+
+    fn read(&mut self, options: &ReadOptions) -> Result<Option<ReadOutcome>> {
+        (**self).read(options)
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        (**self).get_files()
+    }
+
+    fn change_notifier(&mut self) -> Result<Option<Box<dyn ChangeNotifier>>> {
+        (**self).change_notifier()
+    }
+}
+
+/// The system clipboard, accessed through arboard.
+///
+/// arboard only hands over decoded pixels, so on X11 and Wayland the
+/// original encoding is read through `xclip` or `wl-paste` when installed.
+pub struct ArboardSource {
+    clipboard: Clipboard,
+    typed: Option<CommandSource>,
+}
+
+impl ArboardSource {
+    pub fn new() -> Result<Self> {
+        let clipboard = Clipboard::new().map_err(Error::clipboard)?;
+
+        let typed = CommandPreset::detect()
+            .filter(|preset| *preset != CommandPreset::WslPowershell && preset.is_installed())
+            .map(CommandPreset::source);
+
+        Ok(Self { clipboard, typed })
+    }
+}
+
+impl ClipboardSource for ArboardSource {
+    fn get_image(&mut self) -> Result<Option<RgbaImage>> {
+        match self.clipboard.get_image() {
+            Ok(img_data) => Ok(ImageBuffer::from_raw(
+                img_data.width as u32,
+                img_data.height as u32,
+                img_data.bytes.into_owned(),
+            )),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => Err(Error::clipboard(e)),
+        }
+    }
+
+    fn formats(&mut self) -> Result<Vec<ImageFormat>> {
+        // A failing helper only costs the original encoding
+        Ok(match &mut self.typed {
+            Some(typed) => typed.formats().unwrap_or_default(),
+            None => Vec::new(),
+        })
+    }
+
+    fn get_encoded(&mut self, format: ImageFormat) -> Result<Option<Vec<u8>>> {
+        Ok(match &mut self.typed {
+            Some(typed) => typed.get_encoded(format).unwrap_or_default(),
+            None => None,
+        })
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        match self.clipboard.get().file_list() {
+            Ok(files) => Ok(files),
+            Err(arboard::Error::ContentNotAvailable) => Ok(Vec::new()),
+            Err(e) => Err(Error::clipboard(e)),
+        }
+    }
+
+    fn change_notifier(&mut self) -> Result<Option<Box<dyn ChangeNotifier>>> {
+        watch::system_notifier()
+    }
+}
+
+/// The clipboard of the current session.
+///
+/// Under WSL arboard only sees the Linux side, so the Windows clipboard is
+/// read through PowerShell instead.
+pub fn system_source() -> Result<Box<dyn ClipboardSource>> {
+    if command::is_wsl() {
+        return Ok(Box::new(CommandPreset::WslPowershell.source()));
+    }
+
+    Ok(Box::new(ArboardSource::new()?))
+}
+
+/// In-memory clipboard that yields a scripted sequence of images.
+///
+/// Each image read takes the next queued entry (if any) and keeps
+/// returning it until another entry is queued, the same way a real
+/// clipboard holds its content; `get_files` looks at the entry the last
+/// read took. Clones share the queue, so a test can keep one handle and
+/// hand the other to a monitor thread.
+#[derive(Clone, Default)]
+pub struct MemorySource {
+    queue: Arc<Mutex<VecDeque<Option<Content>>>>,
+    watchers: Arc<Mutex<Vec<Sender<()>>>>,
+    current: Option<Content>,
+}
+
+#[derive(Clone)]
+enum Content {
+    Image(ClipboardImage),
+    Files(Vec<PathBuf>),
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue an image to be copied to the clipboard
+    pub fn push(&self, image: RgbaImage) {
+        self.push_image(ClipboardImage::decoded(image));
+    }
+
+    /// Queue an image offered in its original encoding
+    pub fn push_encoded(&self, format: ImageFormat, bytes: Vec<u8>) -> Result<()> {
+        self.push_image(ClipboardImage::from_encoded(format, bytes)?);
+        Ok(())
+    }
+
+    /// Queue a file list, as copied in a file manager
+    pub fn push_files(&self, files: Vec<PathBuf>) {
+        self.push_content(Content::Files(files));
+    }
+
+    fn push_image(&self, image: ClipboardImage) {
+        self.push_content(Content::Image(image));
+    }
+
+    fn push_content(&self, content: Content) {
+        self.queue.lock().unwrap().push_back(Some(content));
+        self.notify();
+    }
+
+    /// Queue an empty clipboard
+    pub fn clear(&self) {
+        self.queue.lock().unwrap().push_back(None);
+        self.notify();
+    }
+
+    fn notify(&self) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(()).is_ok());
+    }
+
+    /// Number of entries not yet read
+    pub fn pending(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+}
+
+impl ClipboardSource for MemorySource {
+    fn get_image(&mut self) -> Result<Option<RgbaImage>> {
+        let outcome = self.read(&ReadOptions::default())?;
+        Ok(outcome
+            .and_then(ReadOutcome::image)
+            .map(|image| image.image))
+    }
+
+    fn read(&mut self, options: &ReadOptions) -> Result<Option<ReadOutcome>> {
+        let remaining = {
+            let mut queue = self.queue.lock().unwrap();
+            if let Some(next) = queue.pop_front() {
+                self.current = next;
+            }
+            queue.len()
+        };
+
+        // Keep event-driven readers coming back until the queue drains
+        if remaining > 0 {
+            self.notify();
+        }
+
+        Ok(match &self.current {
+            Some(Content::Image(image)) => Some(options.admit(image.clone())),
+            _ => None,
+        })
+    }
+
+    fn get_files(&mut self) -> Result<Vec<PathBuf>> {
+        Ok(match &self.current {
+            Some(Content::Files(files)) => files.clone(),
+            _ => Vec::new(),
+        })
+    }
+
+    fn change_notifier(&mut self) -> Result<Option<Box<dyn ChangeNotifier>>> {
+        let (tx, notifier) = ChannelNotifier::new();
+        self.watchers.lock().unwrap().push(tx);
+        Ok(Some(Box::new(notifier)))
+    }
+}
+
+/// Treats the newest image file in a directory as the clipboard content.
+///
+/// Dropping a file into the directory is the equivalent of copying it.
+pub struct DropDirSource {
+    dir: PathBuf,
+    last_seen: Option<(PathBuf, SystemTime)>,
+    /// Read with the options of the first read after the file changed
+    current: Option<ReadOutcome>,
+}
+
+impl DropDirSource {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            last_seen: None,
+            current: None,
+        }
+    }
+
+    fn newest_image(&self) -> Option<(PathBuf, SystemTime)> {
+        let entries = fs::read_dir(&self.dir).ok()?;
+
+        entries
+            .flatten()
+            .filter(|entry| files::is_image_file(&entry.path()))
+            .filter_map(|entry| {
+                let modified = entry.metadata().ok()?.modified().ok()?;
+                Some((entry.path(), modified))
+            })
+            .max_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)))
+    }
+}
+
+impl ClipboardSource for DropDirSource {
+    fn get_image(&mut self) -> Result<Option<RgbaImage>> {
+        let outcome = self.read(&ReadOptions::default())?;
+        Ok(outcome
+            .and_then(ReadOutcome::image)
+            .map(|image| image.image))
+    }
+
+    fn read(&mut self, options: &ReadOptions) -> Result<Option<ReadOutcome>> {
+        let newest = self.newest_image();
+
+        if newest != self.last_seen {
+            self.current = match &newest {
+                Some((path, _)) => Some(files::read_image_file(path, &ReadOptions::default())?),
+                None => None,
+            };
+            self.last_seen = newest;
+        }
+
+        Ok(self.current.clone().map(|outcome| match outcome {
+            ReadOutcome::Image(image) => options.admit(image),
+            too_large => too_large,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn test_memory_source_holds_last_image() {
+        let handle = MemorySource::new();
+        let mut source = handle.clone();
+
+        assert_eq!(source.get_image().unwrap(), None);
+
+        handle.push(RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255])));
+        handle.push(RgbaImage::from_pixel(3, 3, Rgba([4, 5, 6, 255])));
+
+        assert_eq!(source.get_image().unwrap().unwrap().width(), 2);
+        assert_eq!(source.get_image().unwrap().unwrap().width(), 3);
+        assert_eq!(source.get_image().unwrap().unwrap().width(), 3);
+
+        handle.clear();
+        assert_eq!(source.get_image().unwrap(), None);
+    }
+
+    #[test]
+    fn test_drop_dir_source_picks_up_new_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = DropDirSource::new(dir.path());
+
+        assert_eq!(source.get_image().unwrap(), None);
+
+        RgbaImage::from_pixel(4, 5, Rgba([9, 9, 9, 255]))
+            .save(dir.path().join("drop.png"))
+            .unwrap();
+        fs::write(dir.path().join("notes.txt"), "not an image").unwrap();
+
+        let img = source.get_image().unwrap().unwrap();
+        assert_eq!(img.dimensions(), (4, 5));
+    }
+
+    #[test]
+    fn test_drop_dir_source_keeps_original_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut source = DropDirSource::new(dir.path());
+
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba([200, 10, 10, 255])))
+            .to_rgb8()
+            .write_to(&mut jpeg, image::ImageFormat::Jpeg)
+            .unwrap();
+        let jpeg = jpeg.into_inner();
+        fs::write(dir.path().join("photo.jpg"), &jpeg).unwrap();
+
+        let read = source.read(&ReadOptions::default()).unwrap();
+        let read = read.and_then(ReadOutcome::image).unwrap();
+        assert_eq!(read.image.dimensions(), (6, 4));
+        assert!(read.from_file);
+        assert_eq!(
+            read.encoded,
+            Some(EncodedImage {
+                format: ImageFormat::Jpeg,
+                bytes: jpeg
+            })
+        );
+
+        // Not accepted: only the pixels are handed over
+        let png_only = ReadOptions {
+            formats: vec![ImageFormat::Png],
+            ..ReadOptions::default()
+        };
+        let read = source.read(&png_only).unwrap();
+        assert_eq!(read.and_then(ReadOutcome::image).unwrap().encoded, None);
+
+        let small = ReadOptions {
+            max_pixels: Some(10),
+            ..ReadOptions::default()
+        };
+        assert_eq!(
+            source.read(&small).unwrap(),
+            Some(ReadOutcome::TooLarge {
+                width: 6,
+                height: 4
+            })
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::atomic::write_atomic;
+use crate::digest::ImageDigest;
+use crate::error::{Error, Result};
+
+/// What the clipboard monitor has already seen, kept between runs so a
+/// restart doesn't save the current clipboard image a second time
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MonitorState {
+    /// Digest of the last clipboard image read, saved or not
+    pub last_digest: Option<ImageDigest>,
+    /// Digests of the most recent saves, newest first
+    #[serde(default)]
+    pub recent_digests: VecDeque<ImageDigest>,
+    /// Image files of the last file list read from the clipboard
+    #[serde(default)]
+    pub last_files: Vec<PathBuf>,
+}
+
+impl MonitorState {
+    /// Load the state at `path`, starting fresh if it is missing
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(Error::json(path)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// Write the state to `path`, replacing any previous file in one step
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::at(parent))?;
+        }
+
+        let json = serde_json::to_string_pretty(self).map_err(Error::json(path))?;
+        write_atomic(path, json.as_bytes())
+    }
+}
+
+/// `pictd` directory under the platform's state directory
+/// (`~/.local/state` on Linux, the local app data directory elsewhere)
+pub fn state_dir() -> PathBuf {
+    dirs::state_dir()
+        .or_else(dirs::data_local_dir)
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("pictd")
+}
+
+/// Default location of the clipboard monitor's state
+pub fn default_monitor_state_path() -> PathBuf {
+    state_dir().join("monitor-state.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_state_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested/state.json");
+
+        assert_eq!(MonitorState::load(&path).unwrap(), MonitorState::default());
+
+        let digest = ImageDigest::of(&RgbaImage::from_pixel(1, 1, Rgba([1, 2, 3, 4])));
+        let state = MonitorState {
+            last_digest: Some(digest),
+            recent_digests: VecDeque::from([digest]),
+            last_files: vec![PathBuf::from("/tmp/copied.png")],
+        };
+        state.save(&path).unwrap();
+
+        assert_eq!(MonitorState::load(&path).unwrap(), state);
+
+        fs::write(&path, "{ not json").unwrap();
+        assert_eq!(MonitorState::load(&path).unwrap_err().kind(), "json");
+    }
+}
@@ -1,11 +1,34 @@
 use base64::{engine::general_purpose::STANDARD, Engine};
 use chrono::Local;
-use image::RgbaImage;
-use serde::Serialize;
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::fs;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
+use crate::atomic::write_atomic;
+use crate::digest::ImageDigest;
+use crate::encode::{self, SaveOptions};
+use crate::error::{Error, Result};
+use crate::format::{self, ImageFormat};
+use crate::index::{self, CaptureIndex};
+use crate::metadata::{self, CaptureMetadata};
+use crate::naming::{FilenameTemplate, NameContext};
+use crate::optimize::{self, OptimizeReport};
+use crate::perceptual::PerceptualHash;
+use crate::query::{query_saved_images, ImageQuery};
+use crate::source::ClipboardImage;
+use crate::thumbnail;
+use crate::window::SourceWindow;
+
+/// Quality of WebP thumbnails
+const THUMBNAIL_WEBP_QUALITY: u8 = 80;
+
+/// How `ImageInfo::timestamp` is written
+pub(crate) const TIMESTAMP_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
 #[derive(Clone, Serialize, Debug)]
 pub struct ImageInfo {
     pub path: String,
@@ -13,57 +36,237 @@ pub struct ImageInfo {
     pub timestamp: String,
     pub width: u32,
     pub height: u32,
-    pub thumbnail: String, // base64 encoded
+    /// Encoding of the stored file
+    pub format: ImageFormat,
+    pub digest: ImageDigest,
+    /// Base64 PNG; not included in listings, see [`load_thumbnail`]
+    pub thumbnail: Option<String>,
+    /// `pictd://thumb/<id>`, served from a [`ThumbnailCache`](crate::ThumbnailCache)
+    pub thumbnail_url: Option<String>,
+    /// Window focused when the image was captured, if known
+    pub source: Option<SourceWindow>,
+    /// File sizes before and after PNG optimization, when it ran
+    pub optimization: Option<OptimizeReport>,
+    /// Labels set with [`set_tags`]
+    pub tags: Vec<String>,
+    /// Where a remote sink serves a copy, see [`crate::upload`]
+    pub public_url: Option<String>,
+}
+
+impl ImageInfo {
+    /// Whether the filename, source window or a tag contains `query`, ignoring case
+    pub fn matches(&self, query: &str) -> bool {
+        let query_lower = query.to_lowercase();
+        self.filename.to_lowercase().contains(&query_lower)
+            || self.source.as_ref().is_some_and(|s| s.matches(query))
+            || self
+                .tags
+                .iter()
+                .any(|tag| tag.to_lowercase().contains(&query_lower))
+    }
 }
 
 pub fn get_downloads_dir() -> PathBuf {
     dirs::download_dir().unwrap_or_else(|| PathBuf::from("."))
 }
 
-/// Save image with auto-generated timestamp filename
-pub fn save_image(image: &RgbaImage, save_dir: &str) -> Result<ImageInfo, String> {
-    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
-    let mut filename = format!("{}.png", timestamp);
-    let save_path = PathBuf::from(save_dir);
+/// Save image as PNG with the default timestamp filename
+pub fn save_image(image: &RgbaImage, save_dir: &str) -> Result<ImageInfo> {
+    save_capture(
+        &ClipboardImage::decoded(image.clone()),
+        None,
+        save_dir,
+        &SaveOptions::default(),
+    )
+}
 
-    // Ensure directory exists
-    fs::create_dir_all(&save_path).map_err(|e| e.to_string())?;
+/// Save a clipboard image under a name from `options.filename`. Its
+/// original encoding is kept when `options` allow, otherwise it is encoded
+/// as `options.format` (PNG by default). The window it came from is
+/// recorded next to it.
+pub fn save_capture(
+    capture: &ClipboardImage,
+    source: Option<&SourceWindow>,
+    save_dir: &str,
+    options: &SaveOptions,
+) -> Result<ImageInfo> {
+    let (format, bytes) = match &capture.encoded {
+        Some(encoded) if options.keeps(encoded.format) => {
+            (encoded.format, Cow::Borrowed(encoded.bytes.as_slice()))
+        }
+        _ => {
+            let format = options.format.unwrap_or(ImageFormat::Png);
+            let bytes = encode::encode(&capture.image, format, options)?;
+            (format, Cow::Owned(bytes))
+        }
+    };
+    // Copied files, such as photos, have a resolution of their own
+    let screen_pixels = !capture.from_file;
+    let (bytes, optimization) = optimize(bytes, format, &capture.image, options)?;
 
-    let mut filepath = save_path.join(&filename);
+    let now = Local::now();
+    let bytes = embed_metadata(bytes, format, options, screen_pixels, || {
+        CaptureMetadata::capture(now, source)
+    })?;
+    let timestamp = now.format(TIMESTAMP_FORMAT).to_string();
+    let digest = ImageDigest::of(&capture.image);
+    let hex = digest.to_hex();
+    let context = NameContext {
+        time: now,
+        app: source.and_then(|s| s.app.as_deref()),
+        title: source.and_then(|s| s.title.as_deref()),
+        width: capture.image.width(),
+        height: capture.image.height(),
+        digest: &hex,
+        ext: format.extension(),
+    };
 
-    // Handle duplicate timestamps
-    let mut counter = 1;
-    while filepath.exists() {
-        filename = format!("{}_{}.png", timestamp, counter);
-        filepath = save_path.join(&filename);
-        counter += 1;
-    }
+    // Generate thumbnail
+    let thumbnail = inline_thumbnail(&capture.image)?;
 
-    // Save the image
-    image.save(&filepath).map_err(|e| e.to_string())?;
+    // The name is claimed only once the bytes are on disk, so concurrent
+    // saves never share one and watchers never see a partial file
+    let filepath = options
+        .filename
+        .write(Path::new(save_dir), &context, &bytes)?;
+    let filename = filepath
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
 
-    // Generate thumbnail
-    let thumbnail = generate_thumbnail(image)?;
+    if let Some(source) = source {
+        // A capture is either saved with its source or not at all
+        if let Err(e) = write_source(&filepath, source) {
+            let _ = fs::remove_file(&filepath);
+            return Err(e);
+        }
+    }
 
-    Ok(ImageInfo {
+    let info = ImageInfo {
         path: filepath.to_string_lossy().to_string(),
         filename,
         timestamp,
-        width: image.width(),
-        height: image.height(),
-        thumbnail,
-    })
+        width: capture.image.width(),
+        height: capture.image.height(),
+        format,
+        thumbnail: Some(thumbnail),
+        thumbnail_url: thumbnail_url(&filepath, &digest),
+        digest,
+        source: source.cloned(),
+        optimization,
+        tags: Vec::new(),
+        public_url: None,
+    };
+
+    // A failed record is repaired by the next reconcile
+    let phash = PerceptualHash::of(&capture.image);
+    let _ = index::record_saved(Path::new(save_dir), &info, phash);
+
+    Ok(info)
+}
+
+/// Run the PNG optimization stage of `options` over encoded bytes
+fn optimize<'a>(
+    bytes: Cow<'a, [u8]>,
+    format: ImageFormat,
+    image: &RgbaImage,
+    options: &SaveOptions,
+) -> Result<(Cow<'a, [u8]>, Option<OptimizeReport>)> {
+    match &options.optimize {
+        Some(optimize_options) if format == ImageFormat::Png => {
+            // Quantizing re-encodes the pixels, so it needs them as encoded
+            let image = match options.background {
+                Some(background) => Cow::Owned(encode::flatten(image, background)),
+                None => Cow::Borrowed(image),
+            };
+            let (optimized, report) = optimize::optimize_png(&bytes, &image, optimize_options)?;
+            Ok((Cow::Owned(optimized), Some(report)))
+        }
+        _ => Ok((bytes, None)),
+    }
+}
+
+/// Add the metadata `options` ask for to encoded bytes; `capture` supplies
+/// the capture's own fields. The scale factor only sets the resolution of
+/// `screen_pixels`, images captured from the screen rather than read from
+/// a file.
+fn embed_metadata<'a>(
+    bytes: Cow<'a, [u8]>,
+    format: ImageFormat,
+    options: &SaveOptions,
+    screen_pixels: bool,
+    capture: impl FnOnce() -> CaptureMetadata,
+) -> Result<Cow<'a, [u8]>> {
+    let mut fields = if options.embed_metadata {
+        capture()
+    } else {
+        CaptureMetadata::default()
+    };
+    if screen_pixels {
+        fields.dpi = options.scale_factor.map(metadata::dpi_for_scale);
+    }
+    if fields.is_empty() {
+        return Ok(bytes);
+    }
+    Ok(Cow::Owned(metadata::embed(&bytes, format, &fields)?))
 }
 
 /// Save image to a specific target path (for markdown mode)
-pub fn save_image_to_path(image: &RgbaImage, target_path: &Path) -> Result<ImageInfo, String> {
+pub fn save_image_to_path(image: &RgbaImage, target_path: &Path) -> Result<ImageInfo> {
+    save_image_to_path_with(image, target_path, &SaveOptions::default())
+}
+
+/// Save image to a specific target path, encoded for its extension
+/// (`.jpg`, `.webp`, `.avif`, ...) with `options`
+pub fn save_image_to_path_with(
+    image: &RgbaImage,
+    target_path: &Path,
+    options: &SaveOptions,
+) -> Result<ImageInfo> {
+    save_image_to_path_with_metadata(image, target_path, options, &CaptureMetadata::default())
+}
+
+/// Like [`save_image_to_path_with`], embedding `fields` such as the alt text
+/// and markdown file along with the capture time when
+/// `options.embed_metadata` is set
+pub fn save_image_to_path_with_metadata(
+    image: &RgbaImage,
+    target_path: &Path,
+    options: &SaveOptions,
+    fields: &CaptureMetadata,
+) -> Result<ImageInfo> {
     // Ensure parent directory exists
     if let Some(parent) = target_path.parent() {
-        fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+        fs::create_dir_all(parent).map_err(Error::at(parent))?;
     }
 
-    // Save the image
-    image.save(target_path).map_err(|e| e.to_string())?;
+    // Encode the image; other extensions `image` knows, such as BMP, are left to it
+    let mut optimization = None;
+    let bytes = match ImageFormat::from_path(target_path) {
+        Some(format) => {
+            let bytes = Cow::Owned(encode::encode(image, format, options)?);
+            let (bytes, report) = optimize(bytes, format, image, options)?;
+            optimization = report;
+            let bytes = embed_metadata(bytes, format, options, true, || CaptureMetadata {
+                alt_text: fields.alt_text.clone(),
+                document: fields.document.clone(),
+                ..CaptureMetadata::capture(Local::now(), None)
+            })?;
+            bytes.into_owned()
+        }
+        None => {
+            let format =
+                image::ImageFormat::from_path(target_path).map_err(Error::from_encoding)?;
+            let mut bytes = Cursor::new(Vec::new());
+            image
+                .write_to(&mut bytes, format)
+                .map_err(Error::from_encoding)?;
+            bytes.into_inner()
+        }
+    };
+    // Replaced in one step, so a live-reloading preview never sees half an image
+    write_atomic(target_path, &bytes)?;
 
     let filename = target_path
         .file_name()
@@ -71,10 +274,11 @@ pub fn save_image_to_path(image: &RgbaImage, target_path: &Path) -> Result<Image
         .to_string_lossy()
         .to_string();
 
-    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+    let timestamp = Local::now().format(TIMESTAMP_FORMAT).to_string();
 
     // Generate thumbnail
-    let thumbnail = generate_thumbnail(image)?;
+    let thumbnail = inline_thumbnail(image)?;
+    let digest = ImageDigest::of(image);
 
     Ok(ImageInfo {
         path: target_path.to_string_lossy().to_string(),
@@ -82,54 +286,546 @@ pub fn save_image_to_path(image: &RgbaImage, target_path: &Path) -> Result<Image
         timestamp,
         width: image.width(),
         height: image.height(),
-        thumbnail,
+        format: ImageFormat::from_path(target_path).unwrap_or(ImageFormat::Png),
+        thumbnail: Some(thumbnail),
+        thumbnail_url: thumbnail_url(target_path, &digest),
+        digest,
+        source: None,
+        optimization,
+        tags: Vec::new(),
+        public_url: None,
     })
 }
 
-fn generate_thumbnail(image: &RgbaImage) -> Result<String, String> {
-    let thumb = image::imageops::resize(image, 150, 150, image::imageops::FilterType::Triangle);
+/// Hidden file next to a saved image holding its source window, tags and public URL
+pub(crate) fn sidecar_path(image_path: &Path) -> PathBuf {
+    let filename = image_path.file_name().unwrap_or_default().to_string_lossy();
+    image_path.with_file_name(format!(".{}.pictd.json", filename))
+}
+
+/// What the sidecar of a saved image records
+#[derive(Default, Serialize, Deserialize)]
+struct Sidecar {
+    #[serde(flatten)]
+    source: SourceWindow,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+fn read_sidecar(image_path: &Path) -> Sidecar {
+    fs::read_to_string(sidecar_path(image_path))
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_sidecar(image_path: &Path, sidecar: &Sidecar) -> Result<()> {
+    let path = sidecar_path(image_path);
+    if sidecar.source == SourceWindow::default() && sidecar.tags.is_empty() && sidecar.url.is_none()
+    {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io(path, e)),
+            _ => Ok(()),
+        };
+    }
+    let json = serde_json::to_string(sidecar).map_err(Error::json(&path))?;
+    write_atomic(&path, json.as_bytes())
+}
 
-    let mut buf = Cursor::new(Vec::new());
-    thumb
-        .write_to(&mut buf, image::ImageFormat::Png)
-        .map_err(|e| e.to_string())?;
+/// Record the window a saved image came from
+pub fn write_source(image_path: &Path, source: &SourceWindow) -> Result<()> {
+    let mut sidecar = read_sidecar(image_path);
+    sidecar.source = source.clone();
+    write_sidecar(image_path, &sidecar)
+}
+
+/// The window a saved image came from, if it was recorded
+pub fn read_source(image_path: &Path) -> Option<SourceWindow> {
+    let source = read_sidecar(image_path).source;
+    (source != SourceWindow::default()).then_some(source)
+}
+
+/// Tags of a saved image
+pub fn read_tags(image_path: &Path) -> Vec<String> {
+    read_sidecar(image_path).tags
+}
+
+/// Replace the tags of a saved image in `save_dir`, returning them trimmed,
+/// sorted and without duplicates. The index is updated too.
+pub fn set_tags(save_dir: &str, image_path: &Path, tags: &[String]) -> Result<Vec<String>> {
+    let mut tags: Vec<String> = tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+
+    let mut sidecar = read_sidecar(image_path);
+    sidecar.tags = tags.clone();
+    write_sidecar(image_path, &sidecar)?;
+
+    // The image file itself is unchanged, so reconciling wouldn't notice
+    let mut index = CaptureIndex::open(Path::new(save_dir));
+    index.update(image_path, |entry| entry.tags = tags.clone())?;
+    Ok(tags)
+}
 
-    Ok(STANDARD.encode(buf.into_inner()))
+/// URL of a saved image's copy on a remote sink
+pub fn read_public_url(image_path: &Path) -> Option<String> {
+    read_sidecar(image_path).url
 }
 
+/// Record where a remote sink serves a copy of a saved image in `save_dir`.
+/// The index is updated too.
+pub fn set_public_url(save_dir: &Path, image_path: &Path, url: &str) -> Result<()> {
+    let mut sidecar = read_sidecar(image_path);
+    sidecar.url = Some(url.to_string());
+    write_sidecar(image_path, &sidecar)?;
+
+    let mut index = CaptureIndex::open(save_dir);
+    index.update(image_path, |entry| entry.public_url = Some(url.to_string()))
+}
+
+/// Base64 PNG thumbnail of a saved image
+pub fn load_thumbnail(path: &Path) -> Result<String> {
+    inline_thumbnail(&format::open_image(path)?)
+}
+
+fn inline_thumbnail(image: &RgbaImage) -> Result<String> {
+    Ok(STANDARD.encode(generate_thumbnail(image, &ThumbnailOptions::default())?))
+}
+
+/// Resampling filter for thumbnails, fastest to sharpest
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl From<ThumbnailFilter> for FilterType {
+    fn from(filter: ThumbnailFilter) -> Self {
+        match filter {
+            ThumbnailFilter::Nearest => FilterType::Nearest,
+            ThumbnailFilter::Triangle => FilterType::Triangle,
+            ThumbnailFilter::CatmullRom => FilterType::CatmullRom,
+            ThumbnailFilter::Gaussian => FilterType::Gaussian,
+            ThumbnailFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How thumbnails are made
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThumbnailOptions {
+    /// Bounding box; images are scaled down to fit, keeping their aspect ratio
+    pub width: u32,
+    pub height: u32,
+    pub filter: ThumbnailFilter,
+    /// PNG or WebP (lossy)
+    pub format: ImageFormat,
+    /// Pad to the full bounding box with this color, centering the image
+    pub letterbox: Option<[u8; 4]>,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        Self {
+            width: 150,
+            height: 150,
+            filter: ThumbnailFilter::Triangle,
+            format: ImageFormat::Png,
+            letterbox: None,
+        }
+    }
+}
+
+/// `image` scaled down to fit `options`' bounding box. Smaller images keep
+/// their size; with `letterbox`, the result always fills the box.
+pub fn thumbnail(image: &RgbaImage, options: &ThumbnailOptions) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let (box_width, box_height) = (options.width.max(1), options.height.max(1));
+
+    let scale = f64::min(
+        box_width as f64 / width.max(1) as f64,
+        box_height as f64 / height.max(1) as f64,
+    )
+    .min(1.0);
+    let fitted_width = ((width as f64 * scale).round() as u32).clamp(1, box_width);
+    let fitted_height = ((height as f64 * scale).round() as u32).clamp(1, box_height);
+    let fitted = if (fitted_width, fitted_height) == (width, height) {
+        image.clone()
+    } else {
+        imageops::resize(image, fitted_width, fitted_height, options.filter.into())
+    };
+
+    match options.letterbox {
+        Some(color) => {
+            let mut boxed = RgbaImage::from_pixel(box_width, box_height, Rgba(color));
+            let x = (box_width - fitted.width()) / 2;
+            let y = (box_height - fitted.height()) / 2;
+            imageops::overlay(&mut boxed, &fitted, x as i64, y as i64);
+            boxed
+        }
+        None => fitted,
+    }
+}
+
+/// A thumbnail of `image` encoded as `options.format`
+pub fn generate_thumbnail(image: &RgbaImage, options: &ThumbnailOptions) -> Result<Vec<u8>> {
+    let encoding = match options.format {
+        ImageFormat::Png => SaveOptions::default(),
+        ImageFormat::Webp => SaveOptions {
+            lossless: false,
+            quality: THUMBNAIL_WEBP_QUALITY,
+            ..SaveOptions::default()
+        },
+        other => {
+            return Err(Error::UnsupportedFormat(format!(
+                "{} thumbnails",
+                other.extension()
+            )))
+        }
+    };
+    encode::encode(&thumbnail(image, options), options.format, &encoding)
+}
+
+/// Thumbnail URL of the saved file at `path`
+fn thumbnail_url(path: &Path, digest: &ImageDigest) -> Option<String> {
+    let modified = index::modified_millis(&fs::metadata(path).ok()?);
+    Some(thumbnail::thumbnail_url(&thumbnail::thumbnail_id(
+        digest, modified,
+    )))
+}
+
+/// Timestamp of a file named by the default template
+pub(crate) fn timestamp_from_stem(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Build the `ImageInfo` of an already saved image, timestamped by its filename
+pub(crate) fn describe_image(
+    image: &RgbaImage,
+    path: &Path,
+    format: ImageFormat,
+) -> Result<ImageInfo> {
+    let filename = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let timestamp = timestamp_from_stem(path);
+    let digest = ImageDigest::of(image);
+
+    Ok(ImageInfo {
+        path: path.to_string_lossy().to_string(),
+        filename,
+        timestamp,
+        width: image.width(),
+        height: image.height(),
+        format,
+        thumbnail: Some(inline_thumbnail(image)?),
+        thumbnail_url: thumbnail_url(path, &digest),
+        digest,
+        source: read_source(path),
+        optimization: None,
+        tags: read_tags(path),
+        public_url: read_public_url(path),
+    })
+}
+
+/// Saved images named by the default template, newest first
 pub fn list_saved_images(save_dir: &str) -> Vec<ImageInfo> {
-    let save_path = PathBuf::from(save_dir);
-    let mut images = Vec::new();
-
-    if let Ok(entries) = fs::read_dir(&save_path) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.extension().map_or(false, |e| e == "png") {
-                if let Ok(img) = image::open(&path) {
-                    let rgba = img.to_rgba8();
-                    if let Ok(thumbnail) = generate_thumbnail(&rgba) {
-                        let filename = path
-                            .file_name()
-                            .unwrap_or_default()
-                            .to_string_lossy()
-                            .to_string();
-                        let timestamp = filename.trim_end_matches(".png").to_string();
-
-                        images.push(ImageInfo {
-                            path: path.to_string_lossy().to_string(),
-                            filename,
-                            timestamp,
-                            width: rgba.width(),
-                            height: rgba.height(),
-                            thumbnail,
-                        });
-                    }
-                }
+    list_saved_images_with(save_dir, &FilenameTemplate::default())
+}
+
+/// Saved images named by `template`, newest first, see [`query_saved_images`]
+pub fn list_saved_images_with(save_dir: &str, template: &FilenameTemplate) -> Vec<ImageInfo> {
+    query_saved_images(save_dir, template, &ImageQuery::default()).images
+}
+
+/// Image files in `dir` and `depth` levels of subdirectories, skipping hidden entries
+pub(crate) fn image_files(dir: &Path, depth: usize) -> Vec<(PathBuf, ImageFormat)> {
+    let mut paths = Vec::new();
+    collect_images(dir, depth, &mut paths);
+    paths
+}
+
+fn collect_images(dir: &Path, depth: usize, paths: &mut Vec<(PathBuf, ImageFormat)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        // Symlinked directories are not followed, so loops can't recurse forever
+        if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+            if depth > 0 {
+                collect_images(&path, depth - 1, paths);
             }
+        } else if let Some(format) = ImageFormat::from_path(&path) {
+            paths.push((path, format));
         }
     }
+}
+
+/// Saved images named by `template` whose filename, app, window title or
+/// tag contains `query`
+pub fn search_saved_images(
+    save_dir: &str,
+    template: &FilenameTemplate,
+    query: &str,
+) -> Vec<ImageInfo> {
+    let query = ImageQuery {
+        text: Some(query.to_string()),
+        ..ImageQuery::default()
+    };
+    query_saved_images(save_dir, template, &query).images
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::optimize::{OptimizeOptions, QuantizeOptions};
+    use image::Rgba;
+
+    #[test]
+    fn test_capture_keeps_original_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="12" height="8"/>"#;
+
+        let capture = ClipboardImage::from_encoded(ImageFormat::Svg, svg.to_vec()).unwrap();
+        let info = save_capture(&capture, None, &save_dir, &SaveOptions::default()).unwrap();
+
+        assert_eq!(info.format, ImageFormat::Svg);
+        assert!(info.filename.ends_with(".svg"));
+        assert_eq!((info.width, info.height), (12, 8));
+        assert_eq!(fs::read(&info.path).unwrap(), svg);
+
+        // The display's resolution is stamped on screenshots, but not on
+        // copied files
+        let png = encode::encode(
+            &RgbaImage::new(40, 40),
+            ImageFormat::Png,
+            &SaveOptions::default(),
+        )
+        .unwrap();
+        let capture = ClipboardImage::from_encoded(ImageFormat::Png, png.clone()).unwrap();
+        let options = SaveOptions {
+            scale_factor: Some(2.0),
+            ..SaveOptions::default()
+        };
+        let info = save_capture(&capture, None, &save_dir, &options).unwrap();
+        assert_eq!(
+            metadata::read_metadata(Path::new(&info.path)).unwrap().dpi,
+            Some(192)
+        );
+        fs::remove_file(&info.path).unwrap();
+
+        let copied = ClipboardImage {
+            from_file: true,
+            ..capture
+        };
+        let info = save_capture(&copied, None, &save_dir, &options).unwrap();
+        assert_eq!(fs::read(&info.path).unwrap(), png);
+        fs::remove_file(&info.path).unwrap();
+
+        // Pixels alone are stored as PNG
+        let info = save_image(
+            &RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 255])),
+            &save_dir,
+        )
+        .unwrap();
+        assert_eq!(info.format, ImageFormat::Png);
+
+        let listed = list_saved_images(&save_dir);
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().any(|i| i.format == ImageFormat::Svg));
+    }
+
+    #[test]
+    fn test_output_format_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let translucent = RgbaImage::from_pixel(6, 4, Rgba([0, 0, 0, 0]));
+
+        // A placeholder's extension picks the encoder, flattening for JPEG
+        let info = save_image_to_path(&translucent, &dir.path().join("foo.jpg")).unwrap();
+        assert_eq!(info.format, ImageFormat::Jpeg);
+        let saved = format::open_image(Path::new(&info.path)).unwrap();
+        assert_eq!(saved.dimensions(), (6, 4));
+        assert!(saved.get_pixel(0, 0).0[0] > 245);
+
+        // A configured format re-encodes captures, even original bytes
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="12" height="8"/>"#;
+        let capture = ClipboardImage::from_encoded(ImageFormat::Svg, svg.to_vec()).unwrap();
+        let options = SaveOptions {
+            format: Some(ImageFormat::Webp),
+            ..SaveOptions::default()
+        };
+        let info = save_capture(&capture, None, &save_dir, &options).unwrap();
+        assert_eq!(info.format, ImageFormat::Webp);
+        assert!(info.filename.ends_with(".webp"));
+        assert_eq!(
+            format::open_image(Path::new(&info.path))
+                .unwrap()
+                .dimensions(),
+            (12, 8)
+        );
+    }
+
+    #[test]
+    fn test_optimized_save_reports_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = SaveOptions {
+            optimize: Some(OptimizeOptions::default()),
+            ..SaveOptions::default()
+        };
+        let image = RgbaImage::from_pixel(300, 200, Rgba([250, 250, 250, 255]));
+
+        let info = save_image_to_path_with(&image, &dir.path().join("flat.png"), &options).unwrap();
+        let report = info.optimization.unwrap();
+        assert_eq!(report.bytes_after, fs::metadata(&info.path).unwrap().len());
+        assert!(report.bytes_after < report.bytes_before);
+
+        // Other formats are left alone
+        let info = save_image_to_path_with(&image, &dir.path().join("flat.jpg"), &options).unwrap();
+        assert!(info.optimization.is_none());
+
+        // Quantizing keeps the background transparency was flattened onto
+        let gradient = RgbaImage::from_fn(64, 64, |x, y| {
+            let alpha = if x < 32 { 0 } else { 255 };
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x * y) % 256) as u8, alpha])
+        });
+        let options = SaveOptions {
+            background: Some([255, 0, 0]),
+            optimize: Some(OptimizeOptions {
+                quantize: Some(QuantizeOptions {
+                    max_colors: 256,
+                    min_psnr: 0.0,
+                }),
+                ..OptimizeOptions::default()
+            }),
+            ..SaveOptions::default()
+        };
+        let info =
+            save_image_to_path_with(&gradient, &dir.path().join("flat2.png"), &options).unwrap();
+        assert!(info.optimization.unwrap().quantized);
+        let saved = format::open_image(Path::new(&info.path)).unwrap();
+        assert!(saved.pixels().all(|pixel| pixel.0[3] == 255));
+        let corner = saved.get_pixel(0, 0).0;
+        assert!(corner[0] > 200 && corner[1] < 50 && corner[2] < 50);
+    }
+
+    #[test]
+    fn test_source_window_is_searchable() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let source = SourceWindow {
+            app: Some("Gimp-2.10".to_string()),
+            title: Some("logo.xcf".to_string()),
+        };
+
+        let capture = ClipboardImage::decoded(RgbaImage::new(4, 4));
+        let info =
+            save_capture(&capture, Some(&source), &save_dir, &SaveOptions::default()).unwrap();
+        assert_eq!(info.source.as_ref(), Some(&source));
+        save_image(&RgbaImage::new(4, 4), &save_dir).unwrap();
+
+        // The sidecar is not listed as an image but restores the source
+        let listed = list_saved_images(&save_dir);
+        assert_eq!(listed.len(), 2);
+
+        let found = search_saved_images(&save_dir, &FilenameTemplate::default(), "gimp");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, info.path);
+        assert_eq!(found[0].source.as_ref(), Some(&source));
+        assert_eq!(
+            search_saved_images(&save_dir, &FilenameTemplate::default(), "LOGO").len(),
+            1
+        );
+    }
 
-    // Sort by filename (timestamp) descending
-    images.sort_by(|a, b| b.filename.cmp(&a.filename));
-    images
+    #[test]
+    fn test_filename_template_subdirectories() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().to_string_lossy().to_string();
+        let options = SaveOptions {
+            filename: "{year}/{month}/{date:%Y%m%d}-{time}-{app}-{w}x{h}-{seq:03}.{ext}"
+                .parse()
+                .unwrap(),
+            ..SaveOptions::default()
+        };
+        let source = SourceWindow {
+            app: Some("../evil/app".to_string()),
+            title: None,
+        };
+
+        let capture = ClipboardImage::decoded(RgbaImage::new(4, 3));
+        let first = save_capture(&capture, Some(&source), &save_dir, &options).unwrap();
+        let second = save_capture(&capture, Some(&source), &save_dir, &options).unwrap();
+
+        let relative = Path::new(&first.path).strip_prefix(dir.path()).unwrap();
+        assert_eq!(relative.components().count(), 3);
+        assert!(first.filename.ends_with("-_evil_app-4x3-001.png"));
+        assert_ne!(first.path, second.path);
+        assert!(second.filename.contains("-_evil_app-4x3-00"));
+
+        // Timestamps are parsed back from the names in the subdirectories
+        let listed = list_saved_images_with(&save_dir, &options.filename);
+        assert_eq!(listed.len(), 2);
+        assert!(listed.iter().any(|i| i.timestamp == first.timestamp));
+        assert!(listed.iter().all(|i| i.source.as_ref() == Some(&source)));
+        assert!(list_saved_images(&save_dir).is_empty());
+    }
+
+    #[test]
+    fn test_thumbnails_keep_aspect_ratio() {
+        let wide = RgbaImage::from_pixel(1600, 400, Rgba([200, 10, 10, 255]));
+
+        let options = ThumbnailOptions::default();
+        assert_eq!(thumbnail(&wide, &options).dimensions(), (150, 38));
+        // Small images aren't scaled up
+        let icon = RgbaImage::new(16, 16);
+        assert_eq!(thumbnail(&icon, &options).dimensions(), (16, 16));
+
+        let letterboxed = ThumbnailOptions {
+            width: 100,
+            height: 100,
+            letterbox: Some([0, 0, 0, 255]),
+            ..ThumbnailOptions::default()
+        };
+        let boxed = thumbnail(&wide, &letterboxed);
+        assert_eq!(boxed.dimensions(), (100, 100));
+        assert_eq!(boxed.get_pixel(50, 0).0, [0, 0, 0, 255]);
+        assert_eq!(boxed.get_pixel(50, 50).0, [200, 10, 10, 255]);
+
+        let webp = ThumbnailOptions {
+            format: ImageFormat::Webp,
+            filter: ThumbnailFilter::Lanczos3,
+            ..ThumbnailOptions::default()
+        };
+        let bytes = generate_thumbnail(&wide, &webp).unwrap();
+        assert_eq!(
+            ImageFormat::Webp.decode(&bytes).unwrap().dimensions(),
+            (150, 38)
+        );
+        let gif = ThumbnailOptions {
+            format: ImageFormat::Gif,
+            ..ThumbnailOptions::default()
+        };
+        assert!(generate_thumbnail(&wide, &gif).is_err());
+    }
 }
@@ -0,0 +1,237 @@
+//! Thumbnails cached on disk.
+//!
+//! Keyed by image digest and file modification time, so an edited file
+//! gets a fresh thumbnail while identical images share one. Frontends
+//! fetch them by id from `pictd://thumb/<id>` URLs, see [`ImageInfo::thumbnail_url`].
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::digest::ImageDigest;
+use crate::error::{Error, Result};
+use crate::format;
+use crate::storage::{self, ImageInfo, ThumbnailOptions};
+
+/// URI scheme thumbnails are served under
+pub const THUMBNAIL_SCHEME: &str = "pictd";
+
+const URL_PREFIX: &str = "pictd://thumb/";
+
+/// Id of the thumbnail of an image with `digest`, last modified at
+/// `modified` (milliseconds since the Unix epoch)
+pub fn thumbnail_id(digest: &ImageDigest, modified: u64) -> String {
+    format!("{}-{}", digest.to_hex(), modified)
+}
+
+/// `pictd://thumb/<id>`
+pub fn thumbnail_url(id: &str) -> String {
+    format!("{}{}", URL_PREFIX, id)
+}
+
+/// The id in a request path: `/<id>` for `pictd://thumb/<id>`, or
+/// `/thumb/<id>` where custom schemes are served over http (Windows)
+pub fn id_from_request_path(path: &str) -> Option<&str> {
+    let id = path.rsplit('/').next()?;
+    let (digest, modified) = id.split_once('-')?;
+    let valid = digest.len() == 32
+        && digest.bytes().all(|b| b.is_ascii_hexdigit())
+        && !modified.is_empty()
+        && modified.bytes().all(|b| b.is_ascii_digit());
+    valid.then_some(id)
+}
+
+/// Default location of the thumbnail cache
+pub fn default_thumbnail_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("pictd")
+        .join("thumbnails")
+}
+
+/// Thumbnails on disk, and where to make missing ones from
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    options: ThumbnailOptions,
+    /// Tells apart thumbnails made with different options
+    variant: String,
+    sources: Mutex<HashMap<String, PathBuf>>,
+    temp_counter: AtomicUsize,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self::with_options(dir, ThumbnailOptions::default())
+    }
+
+    pub fn with_options(dir: impl Into<PathBuf>, options: ThumbnailOptions) -> Self {
+        let json = serde_json::to_string(&options).unwrap_or_default();
+        Self {
+            dir: dir.into(),
+            variant: format!("{:08x}", xxh3_64(json.as_bytes()) as u32),
+            options,
+            sources: Mutex::new(HashMap::new()),
+            temp_counter: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn options(&self) -> &ThumbnailOptions {
+        &self.options
+    }
+
+    /// Content type of the cached thumbnails
+    pub fn mime_type(&self) -> &'static str {
+        self.options.format.mime_type()
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}.{}.{}",
+            id,
+            self.variant,
+            self.options.format.extension()
+        ))
+    }
+
+    /// Remember where `info`'s thumbnail comes from, and cache its inline
+    /// thumbnail if it was made with the same options
+    pub fn register(&self, info: &ImageInfo) {
+        let Some(id) = info
+            .thumbnail_url
+            .as_deref()
+            .and_then(|url| url.strip_prefix(URL_PREFIX))
+        else {
+            return;
+        };
+        self.sources
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), PathBuf::from(&info.path));
+
+        if let Some(inline) = &info.thumbnail {
+            if self.options == ThumbnailOptions::default() && !self.path(id).exists() {
+                if let Ok(png) = STANDARD.decode(inline) {
+                    let _ = self.store(id, &png);
+                }
+            }
+        }
+    }
+
+    /// The thumbnail `id`, generated from its registered image if not cached
+    pub fn get(&self, id: &str) -> Result<Vec<u8>> {
+        if let Ok(png) = fs::read(self.path(id)) {
+            return Ok(png);
+        }
+        let source = self
+            .sources
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::io(self.path(id), std::io::ErrorKind::NotFound.into()))?;
+        self.generate(id, &source)
+    }
+
+    /// Generate the missing thumbnails of `images` on all cores
+    pub fn warm(&self, images: &[ImageInfo]) {
+        let missing: Vec<(&str, &Path)> = images
+            .iter()
+            .filter_map(|info| {
+                let id = info.thumbnail_url.as_deref()?.strip_prefix(URL_PREFIX)?;
+                (!self.path(id).exists()).then_some((id, Path::new(&info.path)))
+            })
+            .collect();
+        if missing.is_empty() {
+            return;
+        }
+
+        let workers = thread::available_parallelism()
+            .map_or(2, |n| n.get())
+            .min(missing.len());
+        let queue = Mutex::new(missing.into_iter());
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let Some((id, source)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let _ = self.generate(id, source);
+                });
+            }
+        });
+    }
+
+    fn generate(&self, id: &str, source: &Path) -> Result<Vec<u8>> {
+        let image = format::open_image(source)?;
+        let thumbnail = storage::generate_thumbnail(&image, &self.options)?;
+        self.store(id, &thumbnail)?;
+        Ok(thumbnail)
+    }
+
+    /// Write through a temporary file, so readers never see half a thumbnail
+    fn store(&self, id: &str, thumbnail: &[u8]) -> Result<()> {
+        fs::create_dir_all(&self.dir).map_err(Error::at(&self.dir))?;
+        let n = self.temp_counter.fetch_add(1, Ordering::Relaxed);
+        let temp = self
+            .dir
+            .join(format!(".{}.{}.{}.tmp", id, std::process::id(), n));
+        fs::write(&temp, thumbnail).map_err(Error::at(&temp))?;
+        let path = self.path(id);
+        fs::rename(&temp, &path).map_err(Error::at(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    #[test]
+    fn test_request_paths() {
+        let id = thumbnail_id(&ImageDigest::of(&RgbaImage::new(1, 1)), 1700000000000);
+        let url = thumbnail_url(&id);
+        assert!(url.starts_with("pictd://thumb/"));
+
+        assert_eq!(id_from_request_path(&format!("/{}", id)), Some(id.as_str()));
+        assert_eq!(
+            id_from_request_path(&format!("/thumb/{}", id)),
+            Some(id.as_str())
+        );
+        assert_eq!(id_from_request_path("/../../etc/passwd"), None);
+        assert_eq!(id_from_request_path("/abc-12"), None);
+    }
+
+    #[test]
+    fn test_thumbnails_are_cached_by_digest_and_mtime() {
+        let images = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let save_dir = images.path().to_string_lossy().to_string();
+        let cache = ThumbnailCache::new(cache_dir.path());
+
+        for i in 0..3 {
+            RgbaImage::new(20 + i, 10)
+                .save(images.path().join(format!("{}.png", i)))
+                .unwrap();
+        }
+        let listed = storage::list_saved_images(&save_dir);
+        assert_eq!(listed.len(), 3);
+        for info in &listed {
+            assert!(info.thumbnail.is_none());
+            cache.register(info);
+        }
+
+        cache.warm(&listed);
+        assert_eq!(fs::read_dir(cache_dir.path()).unwrap().count(), 3);
+
+        let id = listed[0].thumbnail_url.as_deref().unwrap();
+        let id = id.strip_prefix(URL_PREFIX).unwrap();
+        let png = cache.get(id).unwrap();
+        assert!(format::ImageFormat::Png.decode(&png).is_ok());
+        assert!(cache.get("0123-4").is_err());
+    }
+}
@@ -0,0 +1,311 @@
+//! The queue of captures waiting to be copied to remote sinks.
+//!
+//! The queue is kept on disk, so captures saved while offline, or just
+//! before pictd quits, are uploaded later. A failed upload is retried with
+//! exponential backoff, up to [`MAX_ATTEMPTS`] times.
+
+use chrono::{DateTime, Local, TimeDelta};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use crate::atomic::write_atomic;
+use crate::error::{Error, Result};
+use crate::format::ImageFormat;
+use crate::sink::{Sink, SinkConfig};
+use crate::state::state_dir;
+use crate::storage;
+
+/// Failed uploads are given up after this many attempts, about half a day
+pub const MAX_ATTEMPTS: u32 = 20;
+
+/// Wait before the first retry, doubled after each failure
+const FIRST_RETRY_SECONDS: i64 = 30;
+
+/// Longest wait between retries
+const MAX_RETRY_SECONDS: i64 = 60 * 60;
+
+/// One capture waiting for one sink
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingUpload {
+    pub path: PathBuf,
+    /// Save directory of `path`; the key is the path relative to it
+    pub save_dir: PathBuf,
+    /// Name of the sink, see [`SinkConfig::name`]
+    pub sink: String,
+    /// Failed attempts so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// Not tried before this time
+    pub retry_at: DateTime<Local>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+/// How a due upload went
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum UploadOutcome {
+    Uploaded {
+        path: String,
+        sink: String,
+        url: String,
+    },
+    /// Retried at `retry_at`, or given up if it is `None`
+    Failed {
+        path: String,
+        sink: String,
+        message: String,
+        retry_at: Option<DateTime<Local>>,
+    },
+    /// Uploaded, but the URL couldn't be stored with the capture
+    UrlNotRecorded { path: String, message: String },
+}
+
+/// Uploads not done yet, oldest first
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UploadQueue {
+    pub pending: Vec<PendingUpload>,
+}
+
+impl UploadQueue {
+    /// Load the queue at `path`, starting empty if it is missing
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(Error::json(path)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::io(path, e)),
+        }
+    }
+
+    /// Write the queue to `path`, replacing any previous file in one step
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::at(parent))?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(Error::json(path))?;
+        write_atomic(path, json.as_bytes())
+    }
+
+    /// Queue the capture at `path` in `save_dir` for each of `sinks`, due now
+    pub fn push(
+        &mut self,
+        save_dir: &Path,
+        path: &Path,
+        sinks: &[SinkConfig],
+        now: DateTime<Local>,
+    ) {
+        for sink in sinks {
+            self.pending.push(PendingUpload {
+                path: path.to_path_buf(),
+                save_dir: save_dir.to_path_buf(),
+                sink: sink.name.clone(),
+                attempts: 0,
+                retry_at: now,
+                last_error: None,
+            });
+        }
+    }
+
+    /// Try the uploads due at `now` on the sink of their name. Uploads for
+    /// sinks not in `sinks` wait until one of that name is configured again,
+    /// and uploads of files deleted since are dropped.
+    ///
+    /// The first sink's URL is recorded as the capture's public URL; any
+    /// other sink's only until the first one has uploaded.
+    pub fn process(
+        &mut self,
+        sinks: &[(String, Box<dyn Sink>)],
+        now: DateTime<Local>,
+    ) -> Vec<UploadOutcome> {
+        let mut outcomes = Vec::new();
+        let mut index = 0;
+
+        while index < self.pending.len() {
+            let upload = &mut self.pending[index];
+            let Some(position) = sinks.iter().position(|(name, _)| *name == upload.sink) else {
+                index += 1;
+                continue;
+            };
+            if upload.retry_at > now {
+                index += 1;
+                continue;
+            }
+            if !upload.path.exists() {
+                self.pending.remove(index);
+                continue;
+            }
+
+            let path = upload.path.to_string_lossy().to_string();
+            match copy_to(&*sinks[position].1, upload) {
+                Ok(url) => {
+                    let upload = self.pending.remove(index);
+                    if position == 0 || storage::read_public_url(&upload.path).is_none() {
+                        if let Err(e) =
+                            storage::set_public_url(&upload.save_dir, &upload.path, &url)
+                        {
+                            outcomes.push(UploadOutcome::UrlNotRecorded {
+                                path: path.clone(),
+                                message: e.to_string(),
+                            });
+                        }
+                    }
+                    outcomes.push(UploadOutcome::Uploaded {
+                        path,
+                        sink: upload.sink,
+                        url,
+                    });
+                }
+                Err(e) => {
+                    upload.attempts += 1;
+                    upload.last_error = Some(e.to_string());
+                    let retry_at = (upload.attempts < MAX_ATTEMPTS)
+                        .then(|| now + retry_delay(upload.attempts));
+                    outcomes.push(UploadOutcome::Failed {
+                        path,
+                        sink: upload.sink.clone(),
+                        message: e.to_string(),
+                        retry_at,
+                    });
+                    match retry_at {
+                        Some(retry_at) => {
+                            upload.retry_at = retry_at;
+                            index += 1;
+                        }
+                        None => {
+                            self.pending.remove(index);
+                        }
+                    }
+                }
+            }
+        }
+
+        outcomes
+    }
+}
+
+/// Upload the file of `upload` to `sink`, keyed by its path in the save directory
+fn copy_to(sink: &dyn Sink, upload: &PendingUpload) -> Result<String> {
+    let relative = upload
+        .path
+        .strip_prefix(&upload.save_dir)
+        .map_err(|_| Error::PathEscape {
+            path: upload.path.clone(),
+            root: upload.save_dir.clone(),
+        })?;
+    let key = relative.to_string_lossy().replace('\\', "/");
+    let bytes = fs::read(&upload.path).map_err(Error::at(&upload.path))?;
+    let content_type = ImageFormat::from_path(&upload.path)
+        .map_or("application/octet-stream", ImageFormat::mime_type);
+    sink.upload(&key, &bytes, content_type)
+}
+
+/// Wait after the `attempts`th failure
+fn retry_delay(attempts: u32) -> TimeDelta {
+    let seconds = FIRST_RETRY_SECONDS.saturating_mul(1 << (attempts - 1).min(16));
+    TimeDelta::seconds(seconds.min(MAX_RETRY_SECONDS))
+}
+
+/// Default location of the upload queue
+pub fn default_upload_queue_path() -> PathBuf {
+    state_dir().join("upload-queue.json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{SinkTarget, WebDavConfig};
+    use image::{Rgba, RgbaImage};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Fails the first `failures` uploads, then serves from `example.com`
+    struct FlakySink {
+        failures: usize,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Sink for FlakySink {
+        fn upload(&self, key: &str, _bytes: &[u8], _content_type: &str) -> Result<String> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(Error::upload("connection refused"));
+            }
+            Ok(format!("https://example.com/{}", key))
+        }
+    }
+
+    fn sink_config(name: &str) -> SinkConfig {
+        SinkConfig {
+            name: name.to_string(),
+            target: SinkTarget::WebDav(WebDavConfig {
+                url: "https://dav.example.com".to_string(),
+                username: None,
+                password: None,
+                public_url: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_failed_uploads_are_retried_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let save_dir = dir.path().join("shots");
+        let capture = save_dir.join("2026/shot.png");
+        fs::create_dir_all(capture.parent().unwrap()).unwrap();
+        RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]))
+            .save(&capture)
+            .unwrap();
+        let queue_path = dir.path().join("state/upload-queue.json");
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let sinks: Vec<(String, Box<dyn Sink>)> = vec![(
+            "team".to_string(),
+            Box::new(FlakySink {
+                failures: 1,
+                calls: calls.clone(),
+            }),
+        )];
+        let now = Local::now();
+
+        let mut queue = UploadQueue::default();
+        queue.push(&save_dir, &capture, &[sink_config("team")], now);
+        let outcomes = queue.process(&sinks, now);
+        assert!(matches!(
+            &outcomes[..],
+            [UploadOutcome::Failed {
+                retry_at: Some(_),
+                ..
+            }]
+        ));
+        queue.save(&queue_path).unwrap();
+
+        // Not due yet after a restart
+        let mut queue = UploadQueue::load(&queue_path).unwrap();
+        assert_eq!(queue.pending[0].attempts, 1);
+        assert!(queue.process(&sinks, now).is_empty());
+
+        let later = now + retry_delay(1);
+        assert_eq!(
+            queue.process(&sinks, later),
+            [UploadOutcome::Uploaded {
+                path: capture.to_string_lossy().to_string(),
+                sink: "team".to_string(),
+                url: "https://example.com/2026/shot.png".to_string(),
+            }]
+        );
+        assert!(queue.pending.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            storage::read_public_url(&capture).as_deref(),
+            Some("https://example.com/2026/shot.png")
+        );
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_to_an_hour() {
+        assert_eq!(retry_delay(1), TimeDelta::seconds(30));
+        assert_eq!(retry_delay(3), TimeDelta::seconds(120));
+        assert_eq!(retry_delay(MAX_ATTEMPTS), TimeDelta::hours(1));
+    }
+}
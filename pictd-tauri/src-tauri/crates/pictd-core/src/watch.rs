@@ -0,0 +1,380 @@
+//! Clipboard change notifications.
+//!
+//! Lets the monitor sleep until the selection owner changes instead of
+//! decoding the clipboard image on every poll. X11 uses XFixes selection
+//! events, Wayland uses the `ext-data-control` protocol (or its
+//! `wlr-data-control` predecessor).
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// Signals when the clipboard content may have changed
+pub trait ChangeNotifier: Send {
+    /// Wait up to `timeout` for a change; `Ok(true)` if one happened
+    fn wait(&mut self, timeout: Duration) -> Result<bool>;
+}
+
+/// Notifier fed by another thread through a channel
+pub struct ChannelNotifier {
+    rx: Receiver<()>,
+}
+
+impl ChannelNotifier {
+    /// Create a notifier and the sender that triggers it
+    pub fn new() -> (Sender<()>, Self) {
+        let (tx, rx) = mpsc::channel();
+        (tx, Self { rx })
+    }
+}
+
+impl ChangeNotifier for ChannelNotifier {
+    fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(()) => {
+                // Collapse a burst of notifications into a single read
+                while self.rx.try_recv().is_ok() {}
+                Ok(true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::clipboard("change notifier disconnected"))
+            }
+        }
+    }
+}
+
+/// Notifier for the system clipboard of the current session, if it supports
+/// one. Under XWayland, a failure of the Wayland notifier is only reported
+/// if the X11 one fails too.
+pub fn system_notifier() -> Result<Option<Box<dyn ChangeNotifier>>> {
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    ))]
+    {
+        let mut first_error = None;
+
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            match wayland::notifier() {
+                Ok(notifier) => return Ok(Some(Box::new(notifier))),
+                Err(e) => first_error = Some(e),
+            }
+        }
+
+        if std::env::var_os("DISPLAY").is_some() {
+            match x11::notifier() {
+                Ok(notifier) => return Ok(Some(Box::new(notifier))),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub mod x11 {
+    use super::ChannelNotifier;
+    use crate::error::{Error, Result};
+    use std::thread;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xfixes::{ConnectionExt as _, SelectionEventMask};
+    use x11rb::protocol::xproto::ConnectionExt as _;
+    use x11rb::protocol::Event;
+
+    /// Watch `CLIPBOARD` ownership changes on the default display
+    pub fn notifier() -> Result<ChannelNotifier> {
+        notifier_for(None)
+    }
+
+    /// Watch `CLIPBOARD` ownership changes on the given display
+    pub fn notifier_for(display: Option<&str>) -> Result<ChannelNotifier> {
+        let (conn, screen_num) = x11rb::connect(display).map_err(Error::clipboard)?;
+
+        conn.xfixes_query_version(5, 0)
+            .map_err(Error::clipboard)?
+            .reply()
+            .map_err(|e| Error::clipboard(format!("XFixes not available: {}", e)))?;
+
+        let root = conn.setup().roots[screen_num].root;
+        let clipboard = conn
+            .intern_atom(false, b"CLIPBOARD")
+            .map_err(Error::clipboard)?
+            .reply()
+            .map_err(Error::clipboard)?
+            .atom;
+
+        conn.xfixes_select_selection_input(
+            root,
+            clipboard,
+            SelectionEventMask::SET_SELECTION_OWNER
+                | SelectionEventMask::SELECTION_WINDOW_DESTROY
+                | SelectionEventMask::SELECTION_CLIENT_CLOSE,
+        )
+        .map_err(Error::clipboard)?;
+        conn.flush().map_err(Error::clipboard)?;
+
+        let (tx, notifier) = ChannelNotifier::new();
+
+        // The thread exits on the first event after the notifier is dropped
+        thread::spawn(move || {
+            while let Ok(event) = conn.wait_for_event() {
+                if let Event::XfixesSelectionNotify(_) = event {
+                    if tx.send(()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(notifier)
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+pub mod wayland {
+    use super::ChannelNotifier;
+    use crate::error::{Error, Result};
+    use std::sync::mpsc::Sender;
+    use std::thread;
+    use wayland_client::globals::{registry_queue_init, GlobalListContents};
+    use wayland_client::protocol::{wl_registry::WlRegistry, wl_seat::WlSeat};
+    use wayland_client::{event_created_child, Connection, Dispatch, QueueHandle};
+    use wayland_protocols::ext::data_control::v1::client::{
+        ext_data_control_device_v1::{self, ExtDataControlDeviceV1},
+        ext_data_control_manager_v1::ExtDataControlManagerV1,
+        ext_data_control_offer_v1::ExtDataControlOfferV1,
+    };
+    use wayland_protocols_wlr::data_control::v1::client::{
+        zwlr_data_control_device_v1::{self, ZwlrDataControlDeviceV1},
+        zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+        zwlr_data_control_offer_v1::ZwlrDataControlOfferV1,
+    };
+
+    struct State {
+        tx: Sender<()>,
+        done: bool,
+    }
+
+    impl State {
+        fn selection_changed(&mut self) {
+            if self.tx.send(()).is_err() {
+                self.done = true;
+            }
+        }
+    }
+
+    /// Watch selection changes through the data-control protocol
+    pub fn notifier() -> Result<ChannelNotifier> {
+        let conn = Connection::connect_to_env().map_err(Error::clipboard)?;
+        let (globals, mut queue) = registry_queue_init::<State>(&conn).map_err(Error::clipboard)?;
+        let qh = queue.handle();
+
+        let seat: WlSeat = globals
+            .bind(&qh, 1..=1, ())
+            .map_err(|e| Error::clipboard(format!("No seat: {}", e)))?;
+
+        if let Ok(manager) = globals.bind::<ExtDataControlManagerV1, _, _>(&qh, 1..=1, ()) {
+            manager.get_data_device(&seat, &qh, ());
+        } else {
+            let manager: ZwlrDataControlManagerV1 = globals
+                .bind(&qh, 1..=2, ())
+                .map_err(|e| Error::clipboard(format!("Compositor lacks data-control: {}", e)))?;
+            manager.get_data_device(&seat, &qh, ());
+        }
+
+        let (tx, notifier) = ChannelNotifier::new();
+        let mut state = State { tx, done: false };
+
+        thread::spawn(move || {
+            while !state.done {
+                if queue.blocking_dispatch(&mut state).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(notifier)
+    }
+
+    impl Dispatch<WlRegistry, GlobalListContents> for State {
+        fn event(
+            _: &mut Self,
+            _: &WlRegistry,
+            _: <WlRegistry as wayland_client::Proxy>::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<WlSeat, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &WlSeat,
+            _: <WlSeat as wayland_client::Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ExtDataControlManagerV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ExtDataControlManagerV1,
+            _: <ExtDataControlManagerV1 as wayland_client::Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ExtDataControlOfferV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ExtDataControlOfferV1,
+            _: <ExtDataControlOfferV1 as wayland_client::Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ExtDataControlDeviceV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            _: &ExtDataControlDeviceV1,
+            event: ext_data_control_device_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            match event {
+                // The content is read through the regular clipboard source,
+                // so offers are released as soon as they are announced
+                ext_data_control_device_v1::Event::Selection { id } => {
+                    if let Some(offer) = id {
+                        offer.destroy();
+                    }
+                    state.selection_changed();
+                }
+                ext_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                    offer.destroy();
+                }
+                ext_data_control_device_v1::Event::Finished => state.done = true,
+                _ => {}
+            }
+        }
+
+        event_created_child!(State, ExtDataControlDeviceV1, [
+            ext_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ExtDataControlOfferV1, ()),
+        ]);
+    }
+
+    impl Dispatch<ZwlrDataControlManagerV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrDataControlManagerV1,
+            _: <ZwlrDataControlManagerV1 as wayland_client::Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrDataControlOfferV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrDataControlOfferV1,
+            _: <ZwlrDataControlOfferV1 as wayland_client::Proxy>::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrDataControlDeviceV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            _: &ZwlrDataControlDeviceV1,
+            event: zwlr_data_control_device_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            match event {
+                zwlr_data_control_device_v1::Event::Selection { id } => {
+                    if let Some(offer) = id {
+                        offer.destroy();
+                    }
+                    state.selection_changed();
+                }
+                zwlr_data_control_device_v1::Event::PrimarySelection { id: Some(offer) } => {
+                    offer.destroy();
+                }
+                zwlr_data_control_device_v1::Event::Finished => state.done = true,
+                _ => {}
+            }
+        }
+
+        event_created_child!(State, ZwlrDataControlDeviceV1, [
+            zwlr_data_control_device_v1::EVT_DATA_OFFER_OPCODE => (ZwlrDataControlOfferV1, ()),
+        ]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_notifier_collapses_bursts() {
+        let (tx, mut notifier) = ChannelNotifier::new();
+
+        assert!(!notifier.wait(Duration::from_millis(10)).unwrap());
+
+        tx.send(()).unwrap();
+        tx.send(()).unwrap();
+        assert!(notifier.wait(Duration::from_millis(10)).unwrap());
+        assert!(!notifier.wait(Duration::from_millis(10)).unwrap());
+
+        drop(tx);
+        assert!(notifier.wait(Duration::from_millis(10)).is_err());
+    }
+
+    /// Needs an X server, e.g. `xvfb-run cargo test -p pictd-core -- --ignored`
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    ))]
+    #[test]
+    #[ignore]
+    fn test_x11_notifier_sees_new_owner() {
+        let mut notifier = x11::notifier().unwrap();
+        let mut clipboard = arboard::Clipboard::new().unwrap();
+
+        clipboard.set_text("pictd").unwrap();
+        assert!(notifier.wait(Duration::from_secs(5)).unwrap());
+    }
+}
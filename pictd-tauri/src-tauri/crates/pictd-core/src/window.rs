@@ -0,0 +1,266 @@
+//! The window the user is working in.
+//!
+//! Read from the EWMH `_NET_ACTIVE_WINDOW` property on X11 and from the
+//! `wlr-foreign-toplevel-management` protocol on wlroots compositors (Sway,
+//! Hyprland, ...). Other Wayland compositors don't expose the focused
+//! window, so only XWayland clients are seen there.
+
+use serde::{Deserialize, Serialize};
+
+/// Application and title of a window
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceWindow {
+    /// `WM_CLASS` class name on X11, app id on Wayland
+    pub app: Option<String>,
+    pub title: Option<String>,
+}
+
+impl SourceWindow {
+    /// Whether the app or title contains `query`, ignoring case
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [&self.app, &self.title]
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+}
+
+/// The focused window, if the session exposes it
+pub fn active_window() -> Option<SourceWindow> {
+    #[cfg(all(
+        unix,
+        not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+    ))]
+    {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            if let Ok(Some(window)) = wayland::active_window() {
+                return Some(window);
+            }
+        }
+        if std::env::var_os("DISPLAY").is_some() {
+            return x11::active_window().ok().flatten();
+        }
+    }
+
+    None
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod x11 {
+    use super::SourceWindow;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt as _, Window};
+    use x11rb::rust_connection::RustConnection;
+
+    pub fn active_window() -> Result<Option<SourceWindow>, String> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(|e| e.to_string())?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let active_atom = intern(&conn, b"_NET_ACTIVE_WINDOW")?;
+        let active = property(&conn, root, active_atom, AtomEnum::WINDOW.into(), 1)?;
+        let Some(window) = active.value32().and_then(|mut ids| ids.next()) else {
+            return Ok(None);
+        };
+        if window == 0 {
+            return Ok(None);
+        }
+
+        // Two NUL-terminated strings: the instance name, then the class name
+        let wm_class = property(
+            &conn,
+            window,
+            AtomEnum::WM_CLASS.into(),
+            AtomEnum::STRING.into(),
+            256,
+        )?;
+        let app = wm_class
+            .value
+            .split(|b| *b == 0)
+            .nth(1)
+            .filter(|class| !class.is_empty())
+            .map(|class| String::from_utf8_lossy(class).into_owned());
+
+        // The UTF-8 EWMH title, or the legacy one for old clients
+        let utf8 = intern(&conn, b"UTF8_STRING")?;
+        let net_wm_name = intern(&conn, b"_NET_WM_NAME")?;
+        let mut title = property(&conn, window, net_wm_name, utf8, 1024)?.value;
+        if title.is_empty() {
+            title = property(
+                &conn,
+                window,
+                AtomEnum::WM_NAME.into(),
+                AtomEnum::ANY.into(),
+                1024,
+            )?
+            .value;
+        }
+        let title = (!title.is_empty()).then(|| String::from_utf8_lossy(&title).into_owned());
+
+        Ok(Some(SourceWindow { app, title }))
+    }
+
+    fn intern(conn: &RustConnection, name: &[u8]) -> Result<Atom, String> {
+        Ok(conn
+            .intern_atom(false, name)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())?
+            .atom)
+    }
+
+    fn property(
+        conn: &RustConnection,
+        window: Window,
+        property: Atom,
+        kind: Atom,
+        long_length: u32,
+    ) -> Result<x11rb::protocol::xproto::GetPropertyReply, String> {
+        conn.get_property(false, window, property, kind, 0, long_length)
+            .map_err(|e| e.to_string())?
+            .reply()
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+mod wayland {
+    use super::SourceWindow;
+    use wayland_client::globals::{registry_queue_init, GlobalListContents};
+    use wayland_client::protocol::wl_registry::WlRegistry;
+    use wayland_client::{event_created_child, Connection, Dispatch, Proxy, QueueHandle};
+    use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+        zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+        zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+    };
+
+    #[derive(Default)]
+    struct Toplevel {
+        window: SourceWindow,
+        activated: bool,
+    }
+
+    #[derive(Default)]
+    struct State {
+        toplevels: Vec<(ZwlrForeignToplevelHandleV1, Toplevel)>,
+    }
+
+    impl State {
+        fn toplevel(&mut self, handle: &ZwlrForeignToplevelHandleV1) -> &mut Toplevel {
+            let index = match self.toplevels.iter().position(|(h, _)| h == handle) {
+                Some(index) => index,
+                None => {
+                    self.toplevels.push((handle.clone(), Toplevel::default()));
+                    self.toplevels.len() - 1
+                }
+            };
+            &mut self.toplevels[index].1
+        }
+    }
+
+    pub fn active_window() -> Result<Option<SourceWindow>, String> {
+        let conn = Connection::connect_to_env().map_err(|e| e.to_string())?;
+        let (globals, mut queue) =
+            registry_queue_init::<State>(&conn).map_err(|e| e.to_string())?;
+        let qh = queue.handle();
+
+        let manager: ZwlrForeignToplevelManagerV1 = globals
+            .bind(&qh, 1..=3, ())
+            .map_err(|e| format!("Compositor lacks foreign-toplevel: {}", e))?;
+
+        // The first roundtrip announces the toplevels, the second their state
+        let mut state = State::default();
+        queue.roundtrip(&mut state).map_err(|e| e.to_string())?;
+        queue.roundtrip(&mut state).map_err(|e| e.to_string())?;
+        manager.stop();
+
+        Ok(state
+            .toplevels
+            .into_iter()
+            .find(|(_, toplevel)| toplevel.activated)
+            .map(|(_, toplevel)| toplevel.window))
+    }
+
+    impl Dispatch<WlRegistry, GlobalListContents> for State {
+        fn event(
+            _: &mut Self,
+            _: &WlRegistry,
+            _: <WlRegistry as Proxy>::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for State {
+        fn event(
+            _: &mut Self,
+            _: &ZwlrForeignToplevelManagerV1,
+            _: zwlr_foreign_toplevel_manager_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+
+        event_created_child!(State, ZwlrForeignToplevelManagerV1, [
+            zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+        ]);
+    }
+
+    impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for State {
+        fn event(
+            state: &mut Self,
+            handle: &ZwlrForeignToplevelHandleV1,
+            event: zwlr_foreign_toplevel_handle_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            match event {
+                zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
+                    state.toplevel(handle).window.title = Some(title);
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                    state.toplevel(handle).window.app = Some(app_id);
+                }
+                // An array of native-endian u32 state values
+                zwlr_foreign_toplevel_handle_v1::Event::State { state: values } => {
+                    let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+                    state.toplevel(handle).activated = values
+                        .chunks_exact(4)
+                        .any(|v| u32::from_ne_bytes([v[0], v[1], v[2], v[3]]) == activated);
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                    state.toplevels.retain(|(h, _)| h != handle);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_matches_app_or_title() {
+        let window = SourceWindow {
+            app: Some("firefox".to_string()),
+            title: Some("Rust Docs — Mozilla Firefox".to_string()),
+        };
+
+        assert!(window.matches("Firefox"));
+        assert!(window.matches("rust docs"));
+        assert!(!window.matches("gimp"));
+        assert!(!SourceWindow::default().matches("firefox"));
+    }
+}
@@ -1,23 +1,44 @@
 use anyhow::anyhow;
 use image::RgbaImage;
-use pictd_core::{get_unfilled_placeholders, save_image_to_path, ImagePlaceholder};
+use pictd_core::files::{image_files, import_file_to_path};
+use pictd_core::storage::thumbnail;
+use pictd_core::{
+    get_unfilled_placeholders, save_image_to_path_with_metadata, CaptureMetadata, ClipboardSource,
+    Error, HookConfig, HookFailure, HookInput, HookRunner, ImageDigest, ImageInfo,
+    ImagePlaceholder, SaveOptions, ThumbnailOptions,
+};
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+/// Largest preview kept, in pixels; the pane scales it down further
+const PREVIEW_SIZE: u32 = 256;
 
 pub struct App {
     pub markdown_path: PathBuf,
     pub placeholders: Vec<ImagePlaceholder>,
     pub selected_index: usize,
+    pub source: Box<dyn ClipboardSource>,
     pub clipboard_image: Option<RgbaImage>,
     pub clipboard_dimensions: Option<(u32, u32)>,
-    pub last_image_hash: Option<u64>,
+    /// The clipboard image scaled down for the preview pane
+    pub preview: Option<RgbaImage>,
+    /// Image files copied in a file manager, waiting to fill placeholders
+    pub clipboard_files: Vec<PathBuf>,
+    pub last_digest: Option<ImageDigest>,
+    pub last_files: Vec<PathBuf>,
+    /// Encoding and optimization of saved images
+    pub save_options: SaveOptions,
+    /// Post-save hooks, and the failures they report back
+    hooks: Option<(HookRunner, Receiver<HookFailure>)>,
     pub status_message: String,
     pub should_quit: bool,
 }
 
 impl App {
-    pub fn new(markdown_path: &Path) -> anyhow::Result<Self> {
+    pub fn new(markdown_path: &Path, source: Box<dyn ClipboardSource>) -> anyhow::Result<Self> {
         let placeholders = get_unfilled_placeholders(markdown_path)
-            .map_err(|e| anyhow!("Failed to parse markdown: {}", e))?;
+            .map_err(|e| anyhow!("Failed to read markdown: {}", describe_error(&e)))?;
 
         if placeholders.is_empty() {
             anyhow::bail!("No unfilled image placeholders found in the markdown file");
@@ -27,14 +48,54 @@ impl App {
             markdown_path: markdown_path.to_path_buf(),
             placeholders,
             selected_index: 0,
+            source,
             clipboard_image: None,
             clipboard_dimensions: None,
-            last_image_hash: None,
+            preview: None,
+            clipboard_files: Vec::new(),
+            last_digest: None,
+            last_files: Vec::new(),
+            save_options: SaveOptions::default(),
+            hooks: None,
             status_message: "Waiting for clipboard image...".to_string(),
             should_quit: false,
         })
     }
 
+    /// Run `config`'s hooks after each save
+    pub fn set_hooks(&mut self, config: &HookConfig) {
+        let (tx, failures) = mpsc::channel();
+        self.hooks = HookRunner::start(config, move |failure| {
+            let _ = tx.send(failure);
+        })
+        .map(|runner| (runner, failures));
+    }
+
+    /// Queue the hooks for a save that filled the placeholder at `index`
+    fn run_hooks(&self, info: &ImageInfo, index: usize) {
+        if let Some((runner, _)) = &self.hooks {
+            let markdown = self.markdown_path.to_string_lossy();
+            runner.run(HookInput::for_placeholder(
+                info,
+                &markdown,
+                &self.placeholders[index],
+            ));
+        }
+    }
+
+    /// Treat the image currently on the clipboard as stale so it isn't offered
+    pub fn ignore_current_clipboard(&mut self) {
+        if let Some(img) = pictd_core::clipboard::get_clipboard_image(self.source.as_mut()) {
+            self.last_digest = Some(ImageDigest::of(&img));
+        } else {
+            self.last_files = self.copied_image_files();
+        }
+    }
+
+    fn copied_image_files(&mut self) -> Vec<PathBuf> {
+        image_files(&self.source.get_files().unwrap_or_default())
+    }
+
     pub fn select_next(&mut self) {
         if !self.placeholders.is_empty() {
             self.selected_index = (self.selected_index + 1) % self.placeholders.len();
@@ -52,12 +113,26 @@ impl App {
     }
 
     pub fn check_clipboard(&mut self) {
-        if let Some(img) = pictd_core::clipboard::get_clipboard_image() {
-            let hash = pictd_core::clipboard::simple_hash(&img);
+        if let Some(failure) = self.hooks.as_ref().and_then(|(_, f)| f.try_iter().last()) {
+            self.status_message = format!("{} ({})", failure.message, failure.path);
+        }
+
+        if let Some(img) = pictd_core::clipboard::get_clipboard_image(self.source.as_mut()) {
+            let digest = ImageDigest::of(&img);
 
-            if self.last_image_hash != Some(hash) {
-                self.last_image_hash = Some(hash);
+            if self.last_digest != Some(digest) {
+                self.last_digest = Some(digest);
+                self.last_files.clear();
+                self.clipboard_files.clear();
                 self.clipboard_dimensions = Some((img.width(), img.height()));
+                self.preview = Some(thumbnail(
+                    &img,
+                    &ThumbnailOptions {
+                        width: PREVIEW_SIZE,
+                        height: PREVIEW_SIZE,
+                        ..ThumbnailOptions::default()
+                    },
+                ));
                 self.clipboard_image = Some(img);
                 self.status_message = format!(
                     "Image ready: {}x{} - Press Enter to save",
@@ -65,10 +140,31 @@ impl App {
                     self.clipboard_dimensions.unwrap().1
                 );
             }
+            return;
+        }
+
+        // Files copied in a file manager instead of pixels
+        let copied = self.copied_image_files();
+        if !copied.is_empty() && copied != self.last_files {
+            self.last_files = copied.clone();
+            self.last_digest = None;
+            self.clipboard_image = None;
+            self.clipboard_dimensions = None;
+            self.preview = None;
+            self.status_message = format!(
+                "{} image file(s) ready - Press Enter to fill placeholders from the selected one",
+                copied.len()
+            );
+            self.clipboard_files = copied;
         }
     }
 
     pub fn save_to_selected(&mut self) -> anyhow::Result<()> {
+        if !self.clipboard_files.is_empty() {
+            self.save_files_from_selected();
+            return Ok(());
+        }
+
         let Some(image) = self.clipboard_image.take() else {
             self.status_message = "No image in clipboard!".to_string();
             return Ok(());
@@ -82,30 +178,44 @@ impl App {
         let placeholder = &self.placeholders[self.selected_index];
         let target_path = &placeholder.absolute_path;
 
-        match save_image_to_path(&image, target_path) {
+        let fields = CaptureMetadata {
+            alt_text: Some(placeholder.alt_text.clone()).filter(|alt| !alt.is_empty()),
+            document: Some(self.markdown_path.to_string_lossy().to_string()),
+            ..CaptureMetadata::default()
+        };
+
+        match save_image_to_path_with_metadata(&image, target_path, &self.save_options, &fields) {
             Ok(info) => {
-                self.status_message = format!("Saved: {}", info.filename);
+                self.run_hooks(&info, self.selected_index);
+                self.status_message = match info.optimization {
+                    Some(report) => format!(
+                        "Saved: {} ({} KB -> {} KB)",
+                        info.filename,
+                        report.bytes_before.div_ceil(1024),
+                        report.bytes_after.div_ceil(1024)
+                    ),
+                    None => format!("Saved: {}", info.filename),
+                };
 
                 // Remove the saved placeholder
                 self.placeholders.remove(self.selected_index);
 
                 // Adjust selection index if needed
-                if !self.placeholders.is_empty() {
-                    if self.selected_index >= self.placeholders.len() {
-                        self.selected_index = self.placeholders.len() - 1;
-                    }
+                if !self.placeholders.is_empty() && self.selected_index >= self.placeholders.len() {
+                    self.selected_index = self.placeholders.len() - 1;
                 }
 
                 // Clear clipboard state so user needs new image
                 self.clipboard_dimensions = None;
+                self.preview = None;
 
                 // Check if all done
-                if self.placeholders.is_empty() {
+                if self.all_done() {
                     self.status_message = "All placeholders filled! Press q to quit.".to_string();
                 }
             }
             Err(e) => {
-                self.status_message = format!("Error saving: {}", e);
+                self.status_message = format!("Error saving: {}", describe_error(&e));
                 // Put the image back
                 self.clipboard_image = Some(image);
             }
@@ -114,6 +224,49 @@ impl App {
         Ok(())
     }
 
+    /// Fill the selected placeholder and the ones after it with the copied files, in order
+    fn save_files_from_selected(&mut self) {
+        let mut saved = 0;
+
+        while !self.clipboard_files.is_empty() && self.selected_index < self.placeholders.len() {
+            let target_path = &self.placeholders[self.selected_index].absolute_path;
+
+            match import_file_to_path(&self.clipboard_files[0], target_path) {
+                Ok(info) => {
+                    self.run_hooks(&info, self.selected_index);
+                    saved += 1;
+                    self.clipboard_files.remove(0);
+                    // The next placeholder moves up into the selected slot
+                    self.placeholders.remove(self.selected_index);
+                }
+                Err(e) => {
+                    self.status_message = format!("Error saving: {}", describe_error(&e));
+                    break;
+                }
+            }
+        }
+
+        if !self.placeholders.is_empty() && self.selected_index >= self.placeholders.len() {
+            self.selected_index = self.placeholders.len() - 1;
+        }
+
+        if saved == 0 {
+            return;
+        }
+
+        self.status_message = if self.all_done() {
+            "All placeholders filled! Press q to quit.".to_string()
+        } else if self.clipboard_files.is_empty() {
+            format!("Saved {} file(s)", saved)
+        } else {
+            format!(
+                "Saved {} file(s), {} left - select a placeholder and press Enter",
+                saved,
+                self.clipboard_files.len()
+            )
+        };
+    }
+
     pub fn remaining_count(&self) -> usize {
         self.placeholders.len()
     }
@@ -122,3 +275,153 @@ impl App {
         self.placeholders.is_empty()
     }
 }
+
+/// What went wrong and what to do about it, in words for the status line
+pub fn describe_error(error: &Error) -> String {
+    match error {
+        Error::Io { path, source } => match source.kind() {
+            ErrorKind::NotFound => format!("{} doesn't exist", path.display()),
+            ErrorKind::PermissionDenied => {
+                format!("no permission to write {}", path.display())
+            }
+            ErrorKind::StorageFull => format!("disk full writing {}", path.display()),
+            _ => format!("{}: {}", path.display(), source),
+        },
+        Error::Decode(message) => format!("not a readable image ({})", message),
+        Error::Encode(message) => format!("couldn't encode the image ({})", message),
+        Error::UnsupportedFormat(format) => format!(
+            "can't write {} images; use .png, .jpg, .webp, .gif or .avif",
+            format
+        ),
+        Error::PathEscape { path, root } => format!(
+            "{} is outside {}, not writing there",
+            path.display(),
+            root.display()
+        ),
+        Error::ClipboardUnavailable(message) => format!(
+            "clipboard unavailable ({}); try --source or --drop-dir",
+            message
+        ),
+        Error::Upload(message) => format!("upload failed ({})", message),
+        Error::Hook(message) => format!("hook failed ({})", message),
+        Error::Json { path, source } => format!("{} is corrupt ({})", path.display(), source),
+        Error::InvalidInput(message) => message.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+    use pictd_core::MemorySource;
+    use std::fs;
+
+    #[test]
+    fn test_fill_placeholder_from_clipboard() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = dir.path().join("guide.md");
+        fs::write(&markdown, "![One](img/one.png)\n![Two](img/two.png)\n").unwrap();
+
+        let clipboard = MemorySource::new();
+        let mut app = App::new(&markdown, Box::new(clipboard.clone())).unwrap();
+        assert_eq!(app.remaining_count(), 2);
+
+        clipboard.push(RgbaImage::from_pixel(5, 4, Rgba([10, 20, 30, 255])));
+        app.check_clipboard();
+        assert_eq!(app.clipboard_dimensions, Some((5, 4)));
+        assert_eq!(app.preview.as_ref().map(|p| p.dimensions()), Some((5, 4)));
+
+        app.save_to_selected().unwrap();
+        assert!(dir.path().join("img/one.png").exists());
+        assert_eq!(app.remaining_count(), 1);
+        assert_eq!(app.placeholders[0].relative_path, "img/two.png");
+        assert!(app.preview.is_none());
+    }
+
+    #[test]
+    fn test_copied_files_fill_consecutive_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = dir.path().join("guide.md");
+        fs::write(
+            &markdown,
+            "![One](img/one.png)\n![Two](img/two.png)\n![Three](img/three.png)\n",
+        )
+        .unwrap();
+
+        let copied: Vec<PathBuf> = ["a.png", "b.png"]
+            .iter()
+            .map(|name| dir.path().join(name))
+            .collect();
+        for path in &copied {
+            RgbaImage::from_pixel(2, 2, Rgba([1, 2, 3, 255]))
+                .save(path)
+                .unwrap();
+        }
+
+        let clipboard = MemorySource::new();
+        let mut app = App::new(&markdown, Box::new(clipboard.clone())).unwrap();
+        app.select_next();
+
+        clipboard.push_files(copied);
+        app.check_clipboard();
+        assert_eq!(app.clipboard_files.len(), 2);
+
+        app.save_to_selected().unwrap();
+        assert!(dir.path().join("img/two.png").exists());
+        assert!(dir.path().join("img/three.png").exists());
+        assert_eq!(app.remaining_count(), 1);
+        assert_eq!(app.placeholders[0].relative_path, "img/one.png");
+        assert!(app.clipboard_files.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hooks_get_the_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = dir.path().join("guide.md");
+        fs::write(&markdown, "![Login](img/login.png)\n").unwrap();
+        let out = dir.path().join("hook.txt");
+
+        let clipboard = MemorySource::new();
+        let mut app = App::new(&markdown, Box::new(clipboard.clone())).unwrap();
+        app.set_hooks(&HookConfig {
+            hooks: vec![pictd_core::Hook::command(format!(
+                "echo \"$PICTD_PLACEHOLDER $PICTD_ALT_TEXT\" > '{}'; exit 1",
+                out.display()
+            ))],
+            ..HookConfig::default()
+        });
+        clipboard.push(RgbaImage::new(3, 3));
+        app.check_clipboard();
+        app.save_to_selected().unwrap();
+
+        // The hook runs in the background; its failure shows once it is done
+        for _ in 0..500 {
+            app.check_clipboard();
+            if app.status_message.starts_with("Hook failed") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(app.status_message.contains("exit status: 1"));
+        assert_eq!(fs::read_to_string(&out).unwrap(), "img/login.png Login\n");
+    }
+
+    #[test]
+    fn test_save_errors_are_explained() {
+        let dir = tempfile::tempdir().unwrap();
+        let markdown = dir.path().join("guide.md");
+        fs::write(&markdown, "![Diagram](img/diagram.xyz)\n").unwrap();
+
+        let clipboard = MemorySource::new();
+        let mut app = App::new(&markdown, Box::new(clipboard.clone())).unwrap();
+        clipboard.push(RgbaImage::new(3, 3));
+        app.check_clipboard();
+        app.save_to_selected().unwrap();
+
+        assert!(app.status_message.starts_with("Error saving: can't write"));
+        assert!(app.status_message.contains("use .png"));
+        assert_eq!(app.remaining_count(), 1);
+        assert!(app.clipboard_image.is_some());
+    }
+}
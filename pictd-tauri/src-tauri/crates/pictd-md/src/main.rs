@@ -2,7 +2,11 @@ mod app;
 mod tui;
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use pictd_core::{
+    system_source, ArboardSource, ClipboardSource, CommandPreset, DropDirSource, Hook, HookConfig,
+    OptimizeOptions, QuantizeOptions, SaveOptions,
+};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -12,6 +16,68 @@ struct Args {
     /// Path to the markdown file
     #[arg(value_name = "MARKDOWN_FILE")]
     markdown: PathBuf,
+
+    /// Where clipboard images are read from
+    #[arg(long, value_enum, default_value_t = Source::Auto)]
+    source: Source,
+
+    /// Read images from the newest file in this directory instead of the clipboard
+    #[arg(long, value_name = "DIR", conflicts_with = "source")]
+    drop_dir: Option<PathBuf>,
+
+    /// Don't offer the image that is already on the clipboard at startup
+    #[arg(long)]
+    ignore_initial: bool,
+
+    /// Recompress saved PNGs losslessly and strip unneeded chunks
+    #[arg(long)]
+    optimize: bool,
+
+    /// Also reduce PNGs to a 256-color palette unless quality would drop
+    /// below this PSNR (dB); implies --optimize
+    #[arg(long, value_name = "MIN_PSNR", num_args = 0..=1, default_missing_value = "40")]
+    quantize: Option<f64>,
+
+    /// Embed the capture time, placeholder alt text and markdown file in
+    /// saved images
+    #[arg(long)]
+    embed_metadata: bool,
+
+    /// Display scale the screenshots were taken at, written as their DPI so
+    /// they show at logical size
+    #[arg(long, value_name = "FACTOR")]
+    scale: Option<f32>,
+
+    /// Shell command to run after each save, with the image and placeholder
+    /// in PICTD_* environment variables and as JSON on stdin; repeatable
+    #[arg(long = "hook", value_name = "COMMAND")]
+    hooks: Vec<String>,
+
+    /// URL to POST the JSON description of each save to; repeatable
+    #[arg(long = "webhook", value_name = "URL")]
+    webhooks: Vec<String>,
+
+    /// Seconds before a hook command is killed or a webhook abandoned
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    hook_timeout: u64,
+
+    /// How many hooks may run at once
+    #[arg(long, value_name = "N", default_value_t = 4)]
+    hook_jobs: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Source {
+    /// Windows clipboard under WSL, system clipboard otherwise
+    Auto,
+    /// System clipboard
+    System,
+    /// wl-paste (Wayland)
+    WlPaste,
+    /// xclip (X11)
+    Xclip,
+    /// powershell.exe (Windows clipboard from WSL)
+    Wsl,
 }
 
 fn main() -> Result<()> {
@@ -22,6 +88,51 @@ fn main() -> Result<()> {
         anyhow::bail!("Markdown file not found: {}", args.markdown.display());
     }
 
+    let explain = |e: pictd_core::Error| anyhow::anyhow!(app::describe_error(&e));
+    let source: Box<dyn ClipboardSource> = match (args.drop_dir, args.source) {
+        (Some(dir), _) => Box::new(DropDirSource::new(dir)),
+        (None, Source::Auto) => system_source().map_err(explain)?,
+        (None, Source::System) => Box::new(ArboardSource::new().map_err(explain)?),
+        (None, Source::WlPaste) => Box::new(CommandPreset::WlPaste.source()),
+        (None, Source::Xclip) => Box::new(CommandPreset::Xclip.source()),
+        (None, Source::Wsl) => Box::new(CommandPreset::WslPowershell.source()),
+    };
+
+    let quantize = args.quantize.map(|min_psnr| QuantizeOptions {
+        min_psnr,
+        ..QuantizeOptions::default()
+    });
+    let save_options = SaveOptions {
+        optimize: (args.optimize || quantize.is_some()).then(|| OptimizeOptions {
+            quantize,
+            ..OptimizeOptions::default()
+        }),
+        embed_metadata: args.embed_metadata,
+        scale_factor: args.scale,
+        ..SaveOptions::default()
+    };
+
+    let hooks = args
+        .hooks
+        .into_iter()
+        .map(Hook::command)
+        .chain(args.webhooks.into_iter().map(Hook::webhook))
+        .map(|hook| Hook {
+            timeout_secs: args.hook_timeout,
+            ..hook
+        })
+        .collect();
+    let hooks = HookConfig {
+        hooks,
+        max_concurrent: args.hook_jobs,
+    };
+
     // Run the TUI application
-    tui::run(&args.markdown)
+    tui::run(
+        &args.markdown,
+        source,
+        args.ignore_initial,
+        save_options,
+        hooks,
+    )
 }
@@ -5,6 +5,10 @@ use crossterm::{
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
+use image::RgbaImage;
+use pictd_core::encode::flatten;
+use pictd_core::storage::thumbnail;
+use pictd_core::{ClipboardSource, FileStatus, HookConfig, SaveOptions, ThumbnailOptions};
 use ratatui::{
     backend::CrosstermBackend,
     layout::{Constraint, Direction, Layout, Rect},
@@ -17,7 +21,13 @@ use std::io;
 use std::path::Path;
 use std::time::Duration;
 
-pub fn run(markdown_path: &Path) -> Result<()> {
+pub fn run(
+    markdown_path: &Path,
+    source: Box<dyn ClipboardSource>,
+    ignore_initial: bool,
+    save_options: SaveOptions,
+    hooks: HookConfig,
+) -> Result<()> {
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -26,7 +36,7 @@ pub fn run(markdown_path: &Path) -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // Create app state
-    let mut app = match App::new(markdown_path) {
+    let mut app = match App::new(markdown_path, source) {
         Ok(app) => app,
         Err(e) => {
             // Restore terminal before returning error
@@ -41,6 +51,12 @@ pub fn run(markdown_path: &Path) -> Result<()> {
         }
     };
 
+    app.save_options = save_options;
+    app.set_hooks(&hooks);
+    if ignore_initial {
+        app.ignore_current_clipboard();
+    }
+
     // Main loop
     let result = run_app(&mut terminal, &mut app);
 
@@ -119,8 +135,18 @@ fn ui(f: &mut Frame, app: &App) {
         .block(Block::default().borders(Borders::ALL));
     f.render_widget(header, chunks[0]);
 
-    // Placeholder list
-    render_placeholder_list(f, app, chunks[1]);
+    // Placeholder list, with the clipboard image beside it
+    match &app.preview {
+        Some(preview) => {
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(30), Constraint::Percentage(40)])
+                .split(chunks[1]);
+            render_placeholder_list(f, app, columns[0]);
+            render_preview(f, preview, columns[1]);
+        }
+        None => render_placeholder_list(f, app, chunks[1]),
+    }
 
     // Clipboard status
     let clipboard_status = if app.clipboard_image.is_some() {
@@ -134,6 +160,16 @@ fn ui(f: &mut Frame, app: &App) {
                     .add_modifier(Modifier::BOLD),
             ),
         ])
+    } else if !app.clipboard_files.is_empty() {
+        Line::from(vec![
+            Span::raw(" Clipboard: "),
+            Span::styled(
+                format!("{} FILE(S) READY", app.clipboard_files.len()),
+                Style::default()
+                    .fg(Color::Green)
+                    .add_modifier(Modifier::BOLD),
+            ),
+        ])
     } else {
         Line::from(vec![
             Span::raw(" Clipboard: "),
@@ -178,14 +214,24 @@ fn render_placeholder_list(f: &mut Frame, app: &App, area: Rect) {
                 Style::default()
             };
 
-            let line = Line::from(vec![
+            let mut spans = vec![
                 Span::styled(prefix, style),
                 Span::styled(&p.relative_path, style),
                 Span::styled(
                     format!("  (line {})", p.line_number),
                     Style::default().fg(Color::DarkGray),
                 ),
-            ]);
+            ];
+            // A broken file is there, but gets replaced
+            let broken = match p.status {
+                FileStatus::Empty => Some("  empty"),
+                FileStatus::Corrupt => Some("  corrupt"),
+                _ => None,
+            };
+            if let Some(broken) = broken {
+                spans.push(Span::styled(broken, Style::default().fg(Color::Red)));
+            }
+            let line = Line::from(spans);
 
             ListItem::new(line)
         })
@@ -200,3 +246,45 @@ fn render_placeholder_list(f: &mut Frame, app: &App, area: Rect) {
 
     f.render_widget(list, area);
 }
+
+/// Draw `preview` with half blocks, two pixel rows per terminal row
+fn render_preview(f: &mut Frame, preview: &RgbaImage, area: Rect) {
+    let block = Block::default().borders(Borders::ALL).title(" Preview ");
+    let inner = block.inner(area);
+
+    // Terminals can't show transparency
+    let fitted = flatten(
+        &thumbnail(
+            preview,
+            &ThumbnailOptions {
+                width: inner.width.into(),
+                height: u32::from(inner.height) * 2,
+                ..ThumbnailOptions::default()
+            },
+        ),
+        [0, 0, 0],
+    );
+    let rgb = |x, y| {
+        let [r, g, b, _] = fitted.get_pixel(x, y).0;
+        Color::Rgb(r, g, b)
+    };
+
+    let lines: Vec<Line> = (0..fitted.height())
+        .step_by(2)
+        .map(|y| {
+            let spans: Vec<Span> = (0..fitted.width())
+                .map(|x| {
+                    let bottom = if y + 1 < fitted.height() {
+                        rgb(x, y + 1)
+                    } else {
+                        Color::Reset
+                    };
+                    Span::styled("▀", Style::default().fg(rgb(x, y)).bg(bottom))
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect();
+
+    f.render_widget(Paragraph::new(lines).block(block), area);
+}
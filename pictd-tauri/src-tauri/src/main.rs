@@ -1,35 +1,166 @@
 // Prevents additional console window on Windows in release
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use pictd_core::{get_downloads_dir, list_saved_images, ClipboardMonitor, ImageInfo};
+use pictd_core::hook::default_hooks_path;
+use pictd_core::sink::{default_sinks_path, load_sinks, save_sinks};
+use pictd_core::thumbnail::{self, default_thumbnail_dir, THUMBNAIL_SCHEME};
+use pictd_core::upload::default_upload_queue_path;
+use pictd_core::{
+    find_similar, format::open_image as decode_image, get_downloads_dir, plan_retention,
+    query_saved_images, read_metadata, set_tags, state::default_monitor_state_path, system_source,
+    CaptureFilter, CaptureMetadata, ClipboardMonitor, Error, FilenameTemplate, HookConfig,
+    ImageFormat, ImageInfo, ImagePage, ImageQuery, MonitorConfig, MonitorEvent, OptimizeOptions,
+    QuantizeOptions, RetentionPolicy, RetentionReport, SaveOptions, SimilarImage, SinkConfig,
+    ThumbnailCache,
+};
 use serde::Serialize;
-use std::sync::Mutex;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use tauri::{
+    http,
     image::Image,
-    menu::{Menu, MenuItem},
+    menu::{CheckMenuItem, Menu, MenuItem},
     tray::{MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent},
-    AppHandle, Emitter, Manager, State,
+    AppHandle, Emitter, Manager, State, UriSchemeResponder,
 };
 
 struct AppState {
     monitor: ClipboardMonitor,
     save_dir: Mutex<String>,
+    thumbnails: Arc<ThumbnailCache>,
 }
 
 #[derive(Clone, Serialize)]
 struct Settings {
     save_dir: String,
     is_monitoring: bool,
+    save_options: SaveOptions,
+    retention: RetentionPolicy,
+    sinks: Vec<SinkConfig>,
+    hooks: HookConfig,
+}
+
+/// Start the monitor on the system clipboard
+fn start_monitor(state: &AppState) -> Result<(), Error> {
+    let source = system_source()?;
+    let save_dir = state.save_dir.lock().unwrap().clone();
+    state.monitor.start(source, save_dir);
+    Ok(())
+}
+
+/// Change the monitor's configuration, restarting it if it is running
+fn reconfigure(state: &AppState, update: impl FnOnce(&mut MonitorConfig)) -> Result<(), Error> {
+    state.monitor.configure(update);
+    if state.monitor.is_running() {
+        state.monitor.stop();
+        start_monitor(state)?;
+    }
+    Ok(())
+}
+
+/// PNG optimization of new captures: lossless, plus palette reduction if `quantize`
+fn set_optimization(state: &AppState, optimize: bool, quantize: bool) -> Result<(), Error> {
+    reconfigure(state, |config| {
+        config.save.optimize = (optimize || quantize).then(|| OptimizeOptions {
+            quantize: quantize.then(QuantizeOptions::default),
+            ..OptimizeOptions::default()
+        });
+    })
+}
+
+/// Hand `info`'s thumbnail to the cache, leaving only its URL to send
+fn send_thumbnail_by_url(thumbnails: &ThumbnailCache, info: &mut ImageInfo) {
+    thumbnails.register(info);
+    info.thumbnail = None;
+}
+
+/// Forward monitor events to the frontend: every event as `monitor-event`,
+/// and each capture also as `image-saved`
+fn forward_monitor_events(app_handle: AppHandle, state: &AppState) {
+    let events = state.monitor.subscribe();
+    let thumbnails = state.thumbnails.clone();
+    thread::spawn(move || {
+        for mut event in events {
+            if let MonitorEvent::Error { message } = &event {
+                eprintln!("{}", message);
+            }
+            if let MonitorEvent::Saved { info } = &mut event {
+                send_thumbnail_by_url(&thumbnails, info);
+                let _ = app_handle.emit("image-saved", &*info);
+            }
+            let _ = app_handle.emit("monitor-event", &event);
+        }
+    });
 }
 
 #[tauri::command]
 fn get_settings(state: State<AppState>) -> Settings {
+    let config = state.monitor.config();
     Settings {
         save_dir: state.save_dir.lock().unwrap().clone(),
         is_monitoring: state.monitor.is_running(),
+        save_options: config.save,
+        retention: config.retention,
+        sinks: config.sinks,
+        hooks: config.hooks,
     }
 }
 
+/// Output format (`None` keeps the clipboard's) and quality of new captures
+#[tauri::command]
+fn set_output_format(
+    format: Option<ImageFormat>,
+    quality: u8,
+    state: State<AppState>,
+) -> Result<(), Error> {
+    if let Some(format) = format.filter(|format| !format.encodes_pixels()) {
+        return Err(Error::invalid(format!(
+            "Captures can't be saved as {}",
+            format.mime_type()
+        )));
+    }
+    reconfigure(&state, |config| {
+        config.save.format = format;
+        config.save.quality = quality;
+    })
+}
+
+/// Naming of new captures, see `pictd_core::naming`
+#[tauri::command]
+fn set_filename_template(template: String, state: State<AppState>) -> Result<(), Error> {
+    let template: FilenameTemplate = template.parse()?;
+    reconfigure(&state, |config| config.save.filename = template)
+}
+
+/// Which captures the monitor trashes, checked hourly while it runs
+#[tauri::command]
+fn set_retention(policy: RetentionPolicy, state: State<AppState>) -> Result<(), Error> {
+    reconfigure(&state, |config| config.retention = policy)
+}
+
+/// What `policy` would trash now, without trashing anything
+#[tauri::command]
+fn preview_retention(policy: RetentionPolicy, state: State<AppState>) -> RetentionReport {
+    let save_dir = state.save_dir.lock().unwrap().clone();
+    let template = state.monitor.config().save.filename;
+    plan_retention(&save_dir, &template, &policy)
+}
+
+/// Remote sinks new captures are copied to, saved for the next start
+#[tauri::command]
+fn set_sinks(sinks: Vec<SinkConfig>, state: State<AppState>) -> Result<(), Error> {
+    save_sinks(&default_sinks_path(), &sinks)?;
+    reconfigure(&state, |config| config.sinks = sinks)
+}
+
+/// Commands and webhooks run after each capture, saved for the next start
+#[tauri::command]
+fn set_hooks(hooks: HookConfig, state: State<AppState>) -> Result<(), Error> {
+    hooks.save(&default_hooks_path())?;
+    reconfigure(&state, |config| config.hooks = hooks)
+}
+
 #[tauri::command]
 fn set_save_directory(path: String, state: State<AppState>) -> Result<(), String> {
     let mut save_dir = state.save_dir.lock().map_err(|e| e.to_string())?;
@@ -38,13 +169,8 @@ fn set_save_directory(path: String, state: State<AppState>) -> Result<(), String
 }
 
 #[tauri::command]
-fn start_monitoring(app_handle: AppHandle, state: State<AppState>) -> Result<(), String> {
-    let save_dir = state.save_dir.lock().map_err(|e| e.to_string())?.clone();
-    let handle = app_handle.clone();
-    state.monitor.start(save_dir, move |info| {
-        let _ = handle.emit("image-saved", &info);
-    });
-    Ok(())
+fn start_monitoring(state: State<AppState>) -> Result<(), Error> {
+    start_monitor(&state)
 }
 
 #[tauri::command]
@@ -54,30 +180,153 @@ fn stop_monitoring(state: State<AppState>) -> Result<(), String> {
 }
 
 #[tauri::command]
-fn get_saved_images(state: State<AppState>) -> Vec<ImageInfo> {
+fn query_images(query: ImageQuery, state: State<AppState>) -> ImagePage {
+    let save_dir = state.save_dir.lock().unwrap().clone();
+    let template = state.monitor.config().save.filename;
+    let page = query_saved_images(&save_dir, &template, &query);
+
+    // Fill the thumbnail cache for this page in the background; the
+    // protocol handler makes any the window asks for first
+    for info in &page.images {
+        state.thumbnails.register(info);
+    }
+    let thumbnails = state.thumbnails.clone();
+    let warm = page.images.clone();
+    thread::spawn(move || thumbnails.warm(&warm));
+
+    page
+}
+
+#[tauri::command]
+fn set_image_tags(
+    path: String,
+    tags: Vec<String>,
+    state: State<AppState>,
+) -> Result<Vec<String>, Error> {
+    let save_dir = state.save_dir.lock().unwrap().clone();
+    set_tags(&save_dir, Path::new(&path), &tags)
+}
+
+/// Serve `pictd://thumb/<id>` from the thumbnail cache, off the main thread
+fn serve_thumbnail(app_handle: &AppHandle, path: &str, responder: UriSchemeResponder) {
+    let thumbnails = app_handle.state::<AppState>().thumbnails.clone();
+    let id = thumbnail::id_from_request_path(path).map(str::to_string);
+    thread::spawn(move || {
+        let response = match id.map(|id| thumbnails.get(&id)) {
+            Some(Ok(thumbnail)) => http::Response::builder()
+                .header(http::header::CONTENT_TYPE, thumbnails.mime_type())
+                .body(thumbnail),
+            _ => http::Response::builder()
+                .status(http::StatusCode::NOT_FOUND)
+                .body(Vec::new()),
+        };
+        responder.respond(response.unwrap());
+    });
+}
+
+/// Capture info embedded in a saved image
+#[tauri::command]
+fn get_image_metadata(path: String) -> Result<CaptureMetadata, Error> {
+    read_metadata(Path::new(&path))
+}
+
+#[tauri::command]
+fn find_similar_images(path: String, state: State<AppState>) -> Result<Vec<SimilarImage>, Error> {
+    let image = decode_image(Path::new(&path))?;
     let save_dir = state.save_dir.lock().unwrap().clone();
-    list_saved_images(&save_dir)
+    let template = state.monitor.config().save.filename;
+
+    Ok(find_similar(&image, &save_dir, &template)
+        .into_iter()
+        .filter(|similar| similar.info.path != path)
+        .map(|mut similar| {
+            send_thumbnail_by_url(&state.thumbnails, &mut similar.info);
+            similar
+        })
+        .collect())
 }
 
+/// Put a saved image back on the clipboard; the monitor won't save it again
 #[tauri::command]
-fn open_image(path: String) -> Result<(), String> {
-    open::that(&path).map_err(|e| e.to_string())
+fn copy_image(path: String, state: State<AppState>) -> Result<(), Error> {
+    state.monitor.copy_image(Path::new(&path))
+}
+
+#[tauri::command]
+fn open_image(path: String) -> Result<(), Error> {
+    open::that(&path).map_err(|e| Error::io(path, e))
 }
 
 fn main() {
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
         .manage(AppState {
-            monitor: ClipboardMonitor::new(),
+            monitor: ClipboardMonitor::with_config(MonitorConfig {
+                state_path: Some(default_monitor_state_path()),
+                sinks: load_sinks(&default_sinks_path()).unwrap_or_else(|e| {
+                    eprintln!("Ignoring sinks: {}", e);
+                    Vec::new()
+                }),
+                upload_queue_path: Some(default_upload_queue_path()),
+                hooks: HookConfig::load(&default_hooks_path()).unwrap_or_else(|e| {
+                    eprintln!("Ignoring hooks: {}", e);
+                    HookConfig::default()
+                }),
+                // Skip icons and anything too big to decode comfortably
+                filter: CaptureFilter {
+                    min_width: 32,
+                    min_height: 32,
+                    max_pixels: Some(50_000_000),
+                    ..CaptureFilter::default()
+                },
+                // Lossless, so on unless turned off from the tray
+                save: SaveOptions {
+                    optimize: Some(OptimizeOptions::default()),
+                    ..SaveOptions::default()
+                },
+                ..MonitorConfig::default()
+            }),
             save_dir: Mutex::new(get_downloads_dir().to_string_lossy().to_string()),
+            thumbnails: Arc::new(ThumbnailCache::new(default_thumbnail_dir())),
+        })
+        .register_asynchronous_uri_scheme_protocol(THUMBNAIL_SCHEME, |ctx, request, responder| {
+            serve_thumbnail(ctx.app_handle(), request.uri().path(), responder)
         })
         .setup(|app| {
             // Create tray menu
             let show_i = MenuItem::with_id(app, "show", "Show Window", true, None::<&str>)?;
             let pause_i = MenuItem::with_id(app, "pause", "Pause Monitoring", true, None::<&str>)?;
+            let optimize_i =
+                CheckMenuItem::with_id(app, "optimize", "Optimize PNGs", true, true, None::<&str>)?;
+            let quantize_i = CheckMenuItem::with_id(
+                app,
+                "quantize",
+                "Reduce PNG Colors (Lossy)",
+                true,
+                false,
+                None::<&str>,
+            )?;
+            let metadata_i = CheckMenuItem::with_id(
+                app,
+                "metadata",
+                "Embed Capture Info",
+                true,
+                false,
+                None::<&str>,
+            )?;
             let quit_i = MenuItem::with_id(app, "quit", "Quit", true, None::<&str>)?;
 
-            let menu = Menu::with_items(app, &[&show_i, &pause_i, &quit_i])?;
+            let menu = Menu::with_items(
+                app,
+                &[
+                    &show_i,
+                    &pause_i,
+                    &optimize_i,
+                    &quantize_i,
+                    &metadata_i,
+                    &quit_i,
+                ],
+            )?;
 
             // Load tray icon
             let icon = Image::from_bytes(include_bytes!("../icons/icon.png"))
@@ -87,7 +336,7 @@ fn main() {
                 .icon(icon)
                 .menu(&menu)
                 .tooltip("pictd - Clipboard Image Saver")
-                .on_menu_event(|app, event| match event.id.as_ref() {
+                .on_menu_event(move |app, event| match event.id.as_ref() {
                     "show" => {
                         if let Some(window) = app.get_webview_window("main") {
                             let _ = window.show();
@@ -98,12 +347,25 @@ fn main() {
                         let state = app.state::<AppState>();
                         if state.monitor.is_running() {
                             state.monitor.stop();
-                        } else {
-                            let save_dir = state.save_dir.lock().unwrap().clone();
-                            let handle = app.clone();
-                            state.monitor.start(save_dir, move |info| {
-                                let _ = handle.emit("image-saved", &info);
-                            });
+                        } else if let Err(e) = start_monitor(&state) {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    "optimize" | "quantize" => {
+                        let optimize = optimize_i.is_checked().unwrap_or(false);
+                        let quantize = quantize_i.is_checked().unwrap_or(false);
+                        if let Err(e) =
+                            set_optimization(&app.state::<AppState>(), optimize, quantize)
+                        {
+                            eprintln!("{}", e);
+                        }
+                    }
+                    "metadata" => {
+                        let embed = metadata_i.is_checked().unwrap_or(false);
+                        if let Err(e) = reconfigure(&app.state::<AppState>(), |config| {
+                            config.save.embed_metadata = embed
+                        }) {
+                            eprintln!("{}", e);
                         }
                     }
                     "quit" => {
@@ -132,21 +394,37 @@ fn main() {
                 .build(app)?;
 
             // Auto-start monitoring
-            let app_handle = app.handle().clone();
             let state = app.state::<AppState>();
-            let save_dir = state.save_dir.lock().unwrap().clone();
-            state.monitor.start(save_dir, move |info| {
-                let _ = app_handle.emit("image-saved", &info);
-            });
+            // Captures are tagged with the display scale so they open at logical size
+            if let Ok(Some(monitor)) = app.primary_monitor() {
+                let scale = monitor.scale_factor() as f32;
+                state
+                    .monitor
+                    .configure(|config| config.save.scale_factor = Some(scale));
+            }
+            forward_monitor_events(app.handle().clone(), &state);
+            if let Err(e) = start_monitor(&state) {
+                eprintln!("{}", e);
+            }
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             get_settings,
             set_save_directory,
+            set_output_format,
+            set_filename_template,
+            set_retention,
+            preview_retention,
+            set_sinks,
+            set_hooks,
             start_monitoring,
             stop_monitoring,
-            get_saved_images,
+            query_images,
+            set_image_tags,
+            get_image_metadata,
+            find_similar_images,
+            copy_image,
             open_image
         ])
         .run(tauri::generate_context!())
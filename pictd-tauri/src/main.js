@@ -2,11 +2,28 @@ const { invoke } = window.__TAURI__.core;
 const { listen } = window.__TAURI__.event;
 
 let isMonitoring = true;
+let showingSimilar = false;
+
+// Images are fetched a page at a time as the grid scrolls
+const PAGE_SIZE = 60;
+let loadedCount = 0;
+let totalCount = 0;
+let loading = null;
 
 async function init() {
   // Load settings
   const settings = await invoke('get_settings');
   document.getElementById('save-dir').value = settings.save_dir;
+  document.getElementById('output-format').value = settings.save_options.format || '';
+  document.getElementById('quality').value = settings.save_options.quality;
+  document.getElementById('output-format').addEventListener('change', setOutputFormat);
+  document.getElementById('quality').addEventListener('change', setOutputFormat);
+  document.getElementById('filename-template').value = settings.save_options.filename;
+  document.getElementById('filename-template').addEventListener('change', setFilenameTemplate);
+  showRetention(settings.retention);
+  ['retention-days', 'retention-gb', 'retention-count'].forEach(id =>
+    document.getElementById(id).addEventListener('change', setRetention));
+  document.getElementById('retention-preview-btn').addEventListener('click', previewRetention);
   isMonitoring = settings.is_monitoring;
   updateStatusUI();
 
@@ -15,39 +32,183 @@ async function init() {
 
   // Listen for new images
   await listen('image-saved', (event) => {
+    if (showingSimilar || !isDefaultQuery()) return;
     addImageCard(event.payload, true);
+    loadedCount++;
+    totalCount++;
     hideEmptyMessage();
   });
 
+  // Keep the status in sync when the tray pauses or resumes monitoring
+  await listen('monitor-event', (event) => {
+    const { type } = event.payload;
+    if (type === 'started' || type === 'stopped') {
+      isMonitoring = type === 'started';
+      updateStatusUI();
+    }
+    // Expired captures were trashed
+    if (type === 'pruned' && !showingSimilar) showAll();
+    // The first sink to upload a capture provides its URL
+    if (type === 'uploaded') {
+      const card = [...document.querySelectorAll('#image-grid .image-card')]
+        .find(card => card.dataset.path === event.payload.path);
+      if (card && !card.dataset.url) showPublicUrl(card, event.payload.url);
+    }
+    if (type === 'upload_failed' || type === 'hook_failed') console.error(event.payload);
+  });
+
   // Toggle button
   document.getElementById('toggle-btn').addEventListener('click', toggleMonitoring);
+  document.getElementById('show-all-btn').addEventListener('click', showAll);
+
+  let searchTimer;
+  document.getElementById('search').addEventListener('input', () => {
+    clearTimeout(searchTimer);
+    searchTimer = setTimeout(showAll, 200);
+  });
+  document.getElementById('sort').addEventListener('change', showAll);
+
+  // Fetch the next page when the end of the grid scrolls into view
+  new IntersectionObserver((entries) => {
+    if (entries.some(entry => entry.isIntersecting)) loadMoreImages();
+  }, { rootMargin: '400px' }).observe(document.getElementById('grid-end'));
+}
+
+// The search box takes words, `#tag`s and `app:name`
+function currentQuery() {
+  const query = { sort: document.getElementById('sort').value, tags: [] };
+  const words = [];
+  for (const word of document.getElementById('search').value.trim().split(/\s+/)) {
+    if (word.startsWith('#') && word.length > 1) {
+      query.tags.push(word.slice(1));
+    } else if (word.startsWith('app:') && word.length > 4) {
+      query.app = word.slice(4);
+    } else if (word) {
+      words.push(word);
+    }
+  }
+  if (words.length > 0) query.text = words.join(' ');
+  return query;
+}
+
+// New captures belong at the top only of an unfiltered, newest first grid
+function isDefaultQuery() {
+  const query = currentQuery();
+  return query.sort === 'newest_first' && !query.text && !query.app && query.tags.length === 0;
 }
 
 async function loadImages() {
-  const images = await invoke('get_saved_images');
-  const grid = document.getElementById('image-grid');
+  loadedCount = 0;
+  totalCount = Infinity;
+  loading = null;
+  await loadMoreImages();
+}
 
-  if (images.length > 0) {
-    hideEmptyMessage();
-    images.forEach(img => addImageCard(img, false));
+async function loadMoreImages() {
+  if (showingSimilar || loadedCount >= totalCount) return;
+  if (loading) return loading;
+
+  const request = loading = (async () => {
+    const page = await invoke('query_images', {
+      query: { ...currentQuery(), offset: loadedCount, limit: PAGE_SIZE }
+    });
+    // A newer search replaced this one
+    if (request !== loading) return;
+
+    totalCount = page.total;
+    loadedCount += page.images.length;
+    if (page.images.length > 0) hideEmptyMessage();
+    page.images.forEach(img => addImageCard(img, false));
+  })();
+  try {
+    await request;
+  } finally {
+    if (loading === request) loading = null;
   }
 }
 
+// Custom schemes are served from http://<scheme>.localhost on Windows
+const thumbnailBase = navigator.userAgent.includes('Windows') ? 'http://pictd.localhost/' : 'pictd://';
+
+function thumbnailSrc(imageInfo) {
+  if (imageInfo.thumbnail) return `data:image/png;base64,${imageInfo.thumbnail}`;
+  return imageInfo.thumbnail_url.replace('pictd://', thumbnailBase);
+}
+
 function addImageCard(imageInfo, isNew) {
   const grid = document.getElementById('image-grid');
 
   const card = document.createElement('div');
   card.className = 'image-card' + (isNew ? ' new' : '');
+  card.dataset.path = imageInfo.path;
   card.onclick = () => invoke('open_image', { path: imageInfo.path });
 
+  const distance = imageInfo.distance !== undefined
+    ? ` &middot; distance ${imageInfo.distance}`
+    : '';
+
+  const source = imageInfo.source
+    ? [imageInfo.source.app, imageInfo.source.title].filter(Boolean).join(' — ')
+    : '';
+
   card.innerHTML = `
-    <img src="data:image/png;base64,${imageInfo.thumbnail}" alt="${imageInfo.filename}">
+    <img loading="lazy" alt="${imageInfo.filename}">
     <div class="info">
       <div class="filename">${imageInfo.filename}</div>
-      <div class="dimensions">${imageInfo.width} x ${imageInfo.height}</div>
+      <div class="dimensions">${imageInfo.width} x ${imageInfo.height} &middot; ${imageInfo.format.toUpperCase()}${distance}</div>
+      <div class="source"></div>
+      <div class="tags" title="Click to edit tags"></div>
+      <button class="link-btn" title="Copy public URL" hidden>&#x1F517;</button>
+      <button class="star-btn" title="Star to keep it from being trashed">&#x2606;</button>
+      <button class="copy-btn" title="Copy to clipboard">&#x2398;</button>
+      <button class="similar-btn" title="Find similar images">&asymp;</button>
     </div>
   `;
 
+  card.querySelector('img').src = thumbnailSrc(imageInfo);
+
+  // Window titles are arbitrary text
+  card.querySelector('.source').textContent = source;
+  card.querySelector('.source').title = source;
+
+  showTags(card, imageInfo);
+  card.querySelector('.star-btn').onclick = (event) => {
+    event.stopPropagation();
+    toggleStar(card, imageInfo);
+  };
+  card.querySelector('.tags').onclick = (event) => {
+    event.stopPropagation();
+    editTags(card, imageInfo);
+  };
+
+  card.querySelector('.copy-btn').onclick = async (event) => {
+    event.stopPropagation();
+    try {
+      await invoke('copy_image', { path: imageInfo.path });
+      flashCard(card, 'copied');
+    } catch (e) {
+      console.error(e);
+      flashCard(card, 'copy-failed');
+    }
+  };
+
+  showPublicUrl(card, imageInfo.public_url);
+  card.querySelector('.link-btn').onclick = async (event) => {
+    event.stopPropagation();
+    try {
+      await navigator.clipboard.writeText(card.dataset.url);
+      flashCard(card, 'copied');
+    } catch (e) {
+      console.error(e);
+      flashCard(card, 'copy-failed');
+    }
+  };
+
+  card.querySelector('.similar-btn').onclick = (event) => {
+    event.stopPropagation();
+    showSimilar(imageInfo);
+  };
+
   if (isNew) {
     grid.insertBefore(card, grid.firstChild);
   } else {
@@ -55,11 +216,170 @@ function addImageCard(imageInfo, isNew) {
   }
 }
 
+// Where a sink serves the capture; the button only shows once there is one
+function showPublicUrl(card, url) {
+  card.dataset.url = url || '';
+  card.querySelector('.link-btn').hidden = !url;
+}
+
+// Starred captures are exempt from retention
+const STARRED = 'starred';
+
+function showTags(card, imageInfo) {
+  const starred = imageInfo.tags.includes(STARRED);
+  card.querySelector('.star-btn').innerHTML = starred ? '&#x2605;' : '&#x2606;';
+  card.querySelector('.star-btn').classList.toggle('starred', starred);
+
+  const tags = card.querySelector('.tags');
+  tags.textContent = imageInfo.tags.length > 0
+    ? imageInfo.tags.map(tag => `#${tag}`).join(' ')
+    : '+ tag';
+  tags.classList.toggle('empty', imageInfo.tags.length === 0);
+}
+
+async function toggleStar(card, imageInfo) {
+  const tags = imageInfo.tags.includes(STARRED)
+    ? imageInfo.tags.filter(tag => tag !== STARRED)
+    : [...imageInfo.tags, STARRED];
+  try {
+    imageInfo.tags = await invoke('set_image_tags', { path: imageInfo.path, tags });
+    showTags(card, imageInfo);
+  } catch (e) {
+    console.error(e);
+    flashCard(card, 'copy-failed');
+  }
+}
+
+function editTags(card, imageInfo) {
+  const tags = card.querySelector('.tags');
+  const input = document.createElement('input');
+  input.type = 'text';
+  input.className = 'tag-input';
+  input.value = imageInfo.tags.join(' ');
+  input.placeholder = 'space separated tags';
+  input.onclick = (event) => event.stopPropagation();
+
+  let done = false;
+  const finish = async (save) => {
+    if (done) return;
+    done = true;
+    if (save) {
+      try {
+        imageInfo.tags = await invoke('set_image_tags', {
+          path: imageInfo.path,
+          tags: input.value.split(/[\s,]+/).map(tag => tag.replace(/^#/, '')).filter(Boolean)
+        });
+      } catch (e) {
+        console.error(e);
+        flashCard(card, 'copy-failed');
+      }
+    }
+    input.replaceWith(tags);
+    showTags(card, imageInfo);
+  };
+  input.onkeydown = (event) => {
+    if (event.key === 'Enter') finish(true);
+    if (event.key === 'Escape') finish(false);
+  };
+  input.onblur = () => finish(true);
+
+  tags.replaceWith(input);
+  input.focus();
+}
+
+function flashCard(card, className) {
+  card.classList.add(className);
+  setTimeout(() => card.classList.remove(className), 1000);
+}
+
+function clearCards() {
+  document.querySelectorAll('#image-grid .image-card').forEach(card => card.remove());
+}
+
+async function showSimilar(imageInfo) {
+  const similar = await invoke('find_similar_images', { path: imageInfo.path });
+
+  showingSimilar = true;
+  loading = null;
+  clearCards();
+  similar.forEach(img => addImageCard(img, false));
+
+  document.getElementById('grid-title').textContent = `Similar to ${imageInfo.filename}`;
+  document.getElementById('show-all-btn').hidden = false;
+}
+
+async function showAll() {
+  showingSimilar = false;
+  clearCards();
+  await loadImages();
+
+  document.getElementById('grid-title').textContent = 'Saved Images';
+  document.getElementById('show-all-btn').hidden = true;
+}
+
 function hideEmptyMessage() {
   const msg = document.getElementById('empty-message');
   if (msg) msg.style.display = 'none';
 }
 
+async function setOutputFormat() {
+  const format = document.getElementById('output-format').value || null;
+  const quality = Math.min(100, Math.max(1, parseInt(document.getElementById('quality').value, 10) || 90));
+  document.getElementById('quality').value = quality;
+  await invoke('set_output_format', { format, quality });
+}
+
+async function setFilenameTemplate() {
+  const input = document.getElementById('filename-template');
+  try {
+    await invoke('set_filename_template', { template: input.value });
+    input.classList.remove('invalid');
+    input.title = '';
+    await showAll();
+  } catch (error) {
+    input.classList.add('invalid');
+    input.title = error.message;
+  }
+}
+
+function showRetention(policy) {
+  document.getElementById('retention-days').value = policy.max_age_days ?? '';
+  document.getElementById('retention-gb').value = policy.max_bytes != null ? policy.max_bytes / 1e9 : '';
+  document.getElementById('retention-count').value = policy.keep_recent ?? '';
+}
+
+// Empty fields leave that limit off
+function retentionPolicy() {
+  const number = (id) => {
+    const value = parseFloat(document.getElementById(id).value);
+    return Number.isFinite(value) && value > 0 ? value : null;
+  };
+  const days = number('retention-days');
+  const gb = number('retention-gb');
+  const count = number('retention-count');
+  return {
+    max_age_days: days && Math.round(days),
+    max_bytes: gb && Math.round(gb * 1e9),
+    keep_recent: count && Math.round(count),
+  };
+}
+
+async function setRetention() {
+  document.getElementById('retention-preview').hidden = true;
+  await invoke('set_retention', { policy: retentionPolicy() });
+}
+
+async function previewRetention() {
+  const report = await invoke('preview_retention', { policy: retentionPolicy() });
+  const preview = document.getElementById('retention-preview');
+  const megabytes = (report.freed_bytes / 1e6).toFixed(1);
+  preview.textContent = report.expired.length === 0
+    ? `Nothing to trash; ${report.kept} images kept.`
+    : `${report.expired.length} images (${megabytes} MB) would go to the trash; ${report.kept} kept, ${report.starred} of them starred.`;
+  preview.title = report.expired.map(expired => `${expired.path} (${expired.reason})`).join('\n');
+  preview.hidden = false;
+}
+
 async function toggleMonitoring() {
   if (isMonitoring) {
     await invoke('stop_monitoring');
@@ -1,3 +0,0 @@
-#!/bin/bash
-source "$(dirname "$0")/venv/bin/activate"
-python "$@"